@@ -45,6 +45,9 @@
 
           vulkan-loader
           wayland
+
+          # Only needed by the cpal feature
+          alsa-lib
         ];
 
         nativeBuildInputs = with pkgs; [
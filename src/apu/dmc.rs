@@ -0,0 +1,255 @@
+use super::tables;
+use crate::nes::Region;
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+const SAMPLE_BASE: u16 = 0xC000;
+
+/// The delta modulation channel, $4010-$4013: 1-bit delta encoded samples
+/// fetched from memory by DMA, each bit moving a 7-bit level up or down by 2
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Dmc {
+    rates: &'static [u16; 16],
+    irq_enabled: bool,
+    looping: bool,
+    /// Timer period in CPU cycles
+    period: u16,
+    timer: u16,
+    level: u8,
+
+    sample_addr: u16,
+    sample_length: u16,
+    current_addr: u16,
+    bytes_remaining: u16,
+    /// The byte fetched by the last DMA, waiting for the output unit
+    buffer: Option<u8>,
+
+    shift: u8,
+    bits_remaining: u8,
+    silence: bool,
+    pub(super) interrupt: bool,
+}
+
+impl Dmc {
+    pub fn new(region: Region) -> Self {
+        let rates = tables::dmc_rates(region);
+        Self {
+            rates,
+            irq_enabled: false,
+            looping: false,
+            period: rates[0],
+            timer: 0,
+            level: 0,
+            sample_addr: SAMPLE_BASE,
+            sample_length: 1,
+            current_addr: SAMPLE_BASE,
+            bytes_remaining: 0,
+            buffer: None,
+            shift: 0,
+            bits_remaining: 8,
+            silence: true,
+            interrupt: false,
+        }
+    }
+
+    /// Writes one of the four registers, `register` being the address & 3
+    pub fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => {
+                self.irq_enabled = data & 0b1000_0000 != 0;
+                self.looping = data & 0b0100_0000 != 0;
+                self.period = self.rates[(data & 0b1111) as usize];
+                if !self.irq_enabled {
+                    self.interrupt = false;
+                }
+            }
+            1 => self.level = data & 0b0111_1111,
+            2 => self.sample_addr = SAMPLE_BASE + data as u16 * 64,
+            3 => self.sample_length = data as u16 * 16 + 1,
+            _ => unreachable!("the DMC has four registers"),
+        }
+    }
+
+    /// The enable bit of $4015: stops the sample, or starts it over if it
+    /// had finished
+    pub fn set_enabled(&mut self, enabled: bool) {
+        if !enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+
+    fn restart(&mut self) {
+        self.current_addr = self.sample_addr;
+        self.bytes_remaining = self.sample_length;
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.bytes_remaining > 0
+    }
+
+    /// Address the memory reader wants to fetch, when the buffer is empty and
+    /// the sample has bytes left
+    pub fn dma_request(&self) -> Option<u16> {
+        (self.buffer.is_none() && self.bytes_remaining > 0).then_some(self.current_addr)
+    }
+
+    /// Hands the byte fetched by the DMA to the memory reader
+    pub fn fill_buffer(&mut self, data: u8) {
+        self.buffer = Some(data);
+        // Addresses wrap from $FFFF around to $8000
+        self.current_addr = self.current_addr.checked_add(1).unwrap_or(0x8000);
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.looping {
+                self.restart();
+            } else if self.irq_enabled {
+                self.interrupt = true;
+            }
+        }
+    }
+
+    /// Clocks the timer, once every CPU cycle
+    pub fn clock(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.period - 1;
+
+        if !self.silence {
+            if self.shift & 1 != 0 {
+                if self.level <= 125 {
+                    self.level += 2;
+                }
+            } else if self.level >= 2 {
+                self.level -= 2;
+            }
+        }
+        self.shift >>= 1;
+
+        self.bits_remaining -= 1;
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.buffer.take() {
+                Some(data) => {
+                    self.silence = false;
+                    self.shift = data;
+                }
+                None => self.silence = true,
+            }
+        }
+    }
+
+    /// The current level, 0-127
+    pub fn output(&self) -> u8 {
+        self.level
+    }
+}
+
+impl Snapshot for Dmc {
+    fn save(&self, state: &mut StateWriter) {
+        state.bool(self.irq_enabled);
+        state.bool(self.looping);
+        state.u16(self.period);
+        state.u16(self.timer);
+        state.u8(self.level);
+        state.u16(self.sample_addr);
+        state.u16(self.sample_length);
+        state.u16(self.current_addr);
+        state.u16(self.bytes_remaining);
+        state.bool(self.buffer.is_some());
+        state.u8(self.buffer.unwrap_or(0));
+        state.u8(self.shift);
+        state.u8(self.bits_remaining);
+        state.bool(self.silence);
+        state.bool(self.interrupt);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.irq_enabled = state.bool()?;
+        self.looping = state.bool()?;
+        self.period = state.u16()?.max(1);
+        self.timer = state.u16()?;
+        self.level = state.u8()? & 0b0111_1111;
+        self.sample_addr = state.u16()?;
+        self.sample_length = state.u16()?;
+        self.current_addr = state.u16()?;
+        self.bytes_remaining = state.u16()?;
+        let buffered = state.bool()?;
+        self.buffer = Some(state.u8()?).filter(|_| buffered);
+        self.shift = state.u8()?;
+        self.bits_remaining = state.u8()?.clamp(1, 8);
+        self.silence = state.bool()?;
+        self.interrupt = state.bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Runs the channel, serving its DMA requests from `memory` at $C000
+    fn run(dmc: &mut Dmc, memory: &[u8], cycles: usize) -> Vec<u8> {
+        (0..cycles)
+            .map(|_| {
+                if let Some(addr) = dmc.dma_request() {
+                    dmc.fill_buffer(memory[(addr - SAMPLE_BASE) as usize]);
+                }
+                dmc.clock();
+                dmc.output()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_sample_playback() {
+        let mut dmc = Dmc::new(Region::NTSC);
+        // Fastest rate, 54 cycles per bit
+        dmc.write(0, 0x0F);
+        dmc.write(1, 64);
+        dmc.write(3, 0);
+        dmc.set_enabled(true);
+        assert!(dmc.is_active());
+
+        let levels = run(&mut dmc, &[0b0000_1111], 54 * 16);
+        // The first byte only starts once the empty shift register runs out
+        assert_eq!(levels[54 * 8 - 1], 64);
+        assert_eq!(levels[54 * 12 - 1], 72);
+        assert_eq!(levels[54 * 16 - 1], 64);
+        assert!(!dmc.is_active());
+    }
+
+    #[test]
+    fn test_irq_and_looping() {
+        let mut dmc = Dmc::new(Region::NTSC);
+        dmc.write(0, 0x80);
+        dmc.write(3, 0);
+        dmc.set_enabled(true);
+        run(&mut dmc, &[0], 1);
+        assert!(dmc.interrupt);
+        dmc.write(0, 0x00);
+        assert!(!dmc.interrupt);
+
+        dmc.write(0, 0xC0);
+        dmc.set_enabled(true);
+        run(&mut dmc, &[0], 428 * 8 * 3);
+        assert!(dmc.is_active());
+        assert!(!dmc.interrupt);
+    }
+
+    #[test]
+    fn test_address_wraps_to_8000() {
+        let mut dmc = Dmc::new(Region::NTSC);
+        dmc.write(2, 0xFF);
+        dmc.write(3, 4);
+        dmc.set_enabled(true);
+        assert_eq!(dmc.dma_request(), Some(0xFFC0));
+        for _ in 0..0x40 {
+            dmc.fill_buffer(0);
+            dmc.buffer = None;
+        }
+        assert_eq!(dmc.dma_request(), Some(0x8000));
+    }
+}
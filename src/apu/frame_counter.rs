@@ -0,0 +1,285 @@
+use super::tables;
+use crate::nes::Region;
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+/// What the frame counter clocks on a step
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameClock {
+    /// Envelopes and the triangle's linear counter
+    Quarter,
+    /// Length counters and sweeps, along with everything a quarter clocks
+    Half,
+}
+
+/// Which sequence $4017 bit 7 selects
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Four steps ending with the frame IRQ, the power-on mode
+    #[default]
+    FourStep,
+    /// Five steps and no IRQ
+    FiveStep,
+}
+
+/// The frame counter, $4017: a divider stepping through a sequence of
+/// quarter and half frame clocks, about 240 times a second
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameCounter {
+    four_step: &'static [u32; 4],
+    five_step: &'static [u32; 5],
+    mode: Mode,
+    irq_inhibit: bool,
+    /// CPU cycles since the sequence started
+    cycle: u32,
+    /// A written mode waiting for its reset, with the cycles left until then
+    pending: Option<(Mode, u8)>,
+    pub(super) interrupt: bool,
+}
+
+impl FrameCounter {
+    pub fn new(region: Region) -> Self {
+        Self {
+            four_step: tables::four_step_sequence(region),
+            five_step: tables::five_step_sequence(region),
+            mode: Mode::default(),
+            irq_inhibit: false,
+            cycle: 0,
+            pending: None,
+            interrupt: false,
+        }
+    }
+
+    /// Writes $4017. The inhibit flag applies right away, the new mode and
+    /// the sequence reset 3 or 4 cycles later depending on whether the write
+    /// landed on an APU cycle, `odd_cycle` being the parity of the CPU cycle
+    pub fn write(&mut self, data: u8, odd_cycle: bool) {
+        self.irq_inhibit = data & 0b0100_0000 != 0;
+        if self.irq_inhibit {
+            self.interrupt = false;
+        }
+        let mode = if data & 0b1000_0000 != 0 {
+            Mode::FiveStep
+        } else {
+            Mode::FourStep
+        };
+        self.pending = Some((mode, if odd_cycle { 4 } else { 3 }));
+    }
+
+    /// What the reset line does: keeps the mode and inhibit flag, clears the
+    /// IRQ and restarts the sequence right away. With the 7 cycles of the CPU
+    /// reset that follow, it is as if $4017 was written 10 or 11 cycles
+    /// before the first instruction
+    pub fn reset(&mut self) {
+        if let Some((mode, _)) = self.pending.take() {
+            self.mode = mode;
+        }
+        self.cycle = 0;
+        self.interrupt = false;
+    }
+
+    /// The value last written to $4017
+    pub fn last_write(&self) -> u8 {
+        let mode = self.pending.map_or(self.mode, |(mode, _)| mode);
+        ((mode == Mode::FiveStep) as u8) << 7 | (self.irq_inhibit as u8) << 6
+    }
+
+    /// Runs one CPU cycle, returning the units to clock on it
+    pub fn clock(&mut self) -> Option<FrameClock> {
+        if let Some((mode, delay)) = self.pending {
+            if delay > 1 {
+                self.pending = Some((mode, delay - 1));
+            } else {
+                self.pending = None;
+                self.mode = mode;
+                self.cycle = 0;
+                // Switching to five steps clocks everything immediately
+                return (mode == Mode::FiveStep).then_some(FrameClock::Half);
+            }
+        }
+
+        self.cycle += 1;
+        match self.mode {
+            Mode::FourStep => self.four_step_clock(),
+            Mode::FiveStep => self.five_step_clock(),
+        }
+    }
+
+    fn four_step_clock(&mut self) -> Option<FrameClock> {
+        let [first, second, third, last] = *self.four_step;
+        // The flag is raised on three cycles in a row around the last step
+        if (last - 1..=last + 1).contains(&self.cycle) && !self.irq_inhibit {
+            self.interrupt = true;
+        }
+        match self.cycle {
+            cycle if cycle == first || cycle == third => Some(FrameClock::Quarter),
+            cycle if cycle == second || cycle == last => Some(FrameClock::Half),
+            cycle if cycle == last + 1 => {
+                self.cycle = 0;
+                None
+            }
+            _ => None,
+        }
+    }
+
+    fn five_step_clock(&mut self) -> Option<FrameClock> {
+        let [first, second, third, _, last] = *self.five_step;
+        match self.cycle {
+            cycle if cycle == first || cycle == third => Some(FrameClock::Quarter),
+            cycle if cycle == second || cycle == last => Some(FrameClock::Half),
+            cycle if cycle == last + 1 => {
+                self.cycle = 0;
+                None
+            }
+            _ => None,
+        }
+    }
+
+    /// Reading $4015 acknowledges the frame IRQ
+    pub fn acknowledge(&mut self) {
+        self.interrupt = false;
+    }
+}
+
+impl Snapshot for FrameCounter {
+    fn save(&self, state: &mut StateWriter) {
+        state.bool(self.mode == Mode::FiveStep);
+        state.bool(self.irq_inhibit);
+        state.u32(self.cycle);
+        state.bool(self.pending.is_some());
+        let (mode, delay) = self.pending.unwrap_or_default();
+        state.bool(mode == Mode::FiveStep);
+        state.u8(delay);
+        state.bool(self.interrupt);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        let mode = |five_step| {
+            if five_step {
+                Mode::FiveStep
+            } else {
+                Mode::FourStep
+            }
+        };
+        self.mode = mode(state.bool()?);
+        self.irq_inhibit = state.bool()?;
+        self.cycle = state.u32()?;
+        let pending = state.bool()?;
+        let pending_mode = mode(state.bool()?);
+        let delay = state.u8()?.max(1);
+        self.pending = pending.then_some((pending_mode, delay));
+        self.interrupt = state.bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Runs `cycles` CPU cycles, returning the ones something was clocked on
+    fn run(counter: &mut FrameCounter, cycles: u32) -> Vec<(u32, FrameClock)> {
+        (1..=cycles)
+            .filter_map(|cycle| counter.clock().map(|clock| (cycle, clock)))
+            .collect()
+    }
+
+    #[test]
+    fn test_four_step_sequence_and_irq() {
+        let mut counter = FrameCounter::new(Region::NTSC);
+        let clocks = run(&mut counter, 29830 * 2);
+        assert_eq!(
+            &clocks[..4],
+            [
+                (7457, FrameClock::Quarter),
+                (14913, FrameClock::Half),
+                (22371, FrameClock::Quarter),
+                (29829, FrameClock::Half),
+            ]
+        );
+        // The sequence repeats every 29830 cycles
+        assert_eq!(clocks[4], (29830 + 7457, FrameClock::Quarter));
+        assert!(counter.interrupt);
+
+        counter.acknowledge();
+        assert!(!counter.interrupt);
+    }
+
+    #[test]
+    fn test_five_step_sequence_clocks_on_write() {
+        let mut counter = FrameCounter::new(Region::NTSC);
+        counter.write(0b1000_0000, false);
+        let clocks = run(&mut counter, 3 + 37282);
+        assert_eq!(
+            clocks,
+            [
+                (3, FrameClock::Half),
+                (3 + 7457, FrameClock::Quarter),
+                (3 + 14913, FrameClock::Half),
+                (3 + 22371, FrameClock::Quarter),
+                (3 + 37281, FrameClock::Half),
+            ]
+        );
+        assert!(!counter.interrupt);
+    }
+
+    #[test]
+    fn test_write_delay_jitter() {
+        let mut even = FrameCounter::new(Region::NTSC);
+        even.write(0, false);
+        let mut odd = FrameCounter::new(Region::NTSC);
+        odd.write(0, true);
+        assert_eq!(
+            run(&mut even, 3 + 7457).last(),
+            Some(&(3 + 7457, FrameClock::Quarter))
+        );
+        assert_eq!(
+            run(&mut odd, 4 + 7457).last(),
+            Some(&(4 + 7457, FrameClock::Quarter))
+        );
+    }
+
+    #[test]
+    fn test_irq_flag_set_three_cycles_in_a_row() {
+        // Acknowledging on the first two cycles does not stick
+        for ack in [29828, 29829] {
+            let mut counter = FrameCounter::new(Region::NTSC);
+            run(&mut counter, ack);
+            assert!(counter.interrupt);
+            counter.acknowledge();
+            run(&mut counter, 1);
+            assert!(counter.interrupt, "acknowledged on {ack}");
+        }
+        let mut counter = FrameCounter::new(Region::NTSC);
+        run(&mut counter, 29827);
+        assert!(!counter.interrupt);
+        run(&mut counter, 3);
+        counter.acknowledge();
+        run(&mut counter, 1);
+        assert!(!counter.interrupt);
+    }
+
+    #[test]
+    fn test_reset_restarts_in_the_written_mode() {
+        let mut counter = FrameCounter::new(Region::NTSC);
+        run(&mut counter, 29830);
+        counter.write(0b1000_0000, false);
+        counter.reset();
+        assert!(!counter.interrupt);
+        assert_eq!(counter.last_write(), 0b1000_0000);
+        assert_eq!(run(&mut counter, 7457)[0], (7457, FrameClock::Quarter));
+        assert_eq!(counter.mode, Mode::FiveStep);
+    }
+
+    #[test]
+    fn test_inhibit_clears_and_blocks_irq() {
+        let mut counter = FrameCounter::new(Region::NTSC);
+        run(&mut counter, 29830);
+        assert!(counter.interrupt);
+
+        counter.write(0b0100_0000, false);
+        assert!(!counter.interrupt);
+        run(&mut counter, 29830 * 2);
+        assert!(!counter.interrupt);
+        assert_eq!(counter.last_write(), 0b0100_0000);
+    }
+}
@@ -0,0 +1,171 @@
+//! The resistor network the channels are summed through, which is not linear:
+//! louder channels add less, and channels sharing a pin compress each other
+
+use super::Channel;
+
+/// Pulse output for the sum of both pulse levels, 0-30
+const PULSE_ENTRIES: usize = 31;
+/// Triangle, noise and DMC output for `3 * triangle + 2 * noise + dmc`
+const TND_ENTRIES: usize = 3 * 15 + 2 * 15 + 127 + 1;
+
+/// How loud one channel is in the mix
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChannelSettings {
+    pub gain: f32,
+    pub muted: bool,
+    /// When any channel is soloed, only soloed channels are heard
+    pub solo: bool,
+}
+
+impl Default for ChannelSettings {
+    fn default() -> Self {
+        Self {
+            gain: 1.0,
+            muted: false,
+            solo: false,
+        }
+    }
+}
+
+/// Lookup tables of the nonlinear mix, built once per APU, and the
+/// per-channel settings applied on top
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mixer {
+    pulse: [f32; PULSE_ENTRIES],
+    tnd: [f32; TND_ENTRIES],
+    settings: [ChannelSettings; Channel::ALL.len()],
+}
+
+impl Mixer {
+    pub fn new() -> Self {
+        let mut pulse = [0.0; PULSE_ENTRIES];
+        for (n, out) in pulse.iter_mut().enumerate().skip(1) {
+            *out = 95.52 / (8128.0 / n as f32 + 100.0);
+        }
+        let mut tnd = [0.0; TND_ENTRIES];
+        for (n, out) in tnd.iter_mut().enumerate().skip(1) {
+            *out = 163.67 / (24329.0 / n as f32 + 100.0);
+        }
+        Self {
+            pulse,
+            tnd,
+            settings: Default::default(),
+        }
+    }
+
+    pub fn settings(&self, channel: Channel) -> ChannelSettings {
+        self.settings[channel as usize]
+    }
+
+    pub fn set_gain(&mut self, channel: Channel, gain: f32) {
+        self.settings[channel as usize].gain = gain;
+    }
+
+    pub fn set_muted(&mut self, channel: Channel, muted: bool) {
+        self.settings[channel as usize].muted = muted;
+    }
+
+    pub fn set_solo(&mut self, channel: Channel, solo: bool) {
+        self.settings[channel as usize].solo = solo;
+    }
+
+    /// Back to every channel at full volume
+    pub fn reset_settings(&mut self) {
+        self.settings = Default::default();
+    }
+
+    /// Gain a channel actually gets once mutes and solos are applied
+    fn effective_gain(&self, channel: Channel) -> f32 {
+        let any_solo = self.settings.iter().any(|settings| settings.solo);
+        let settings = self.settings(channel);
+        if settings.muted || (any_solo && !settings.solo) {
+            0.0
+        } else {
+            settings.gain
+        }
+    }
+
+    /// Mixes the channel levels, ordered as [`Channel::ALL`], to 0.0-1.0 at
+    /// unit gains. Each channel gets its share of the group it is mixed in,
+    /// scaled by its gain, so a muted channel still compresses the others
+    /// as it does on hardware
+    pub fn mix(&self, levels: [u8; 5]) -> f32 {
+        let [pulse_1, pulse_2, triangle, noise, dmc] = levels.map(f32::from);
+        let [
+            pulse_1_gain,
+            pulse_2_gain,
+            triangle_gain,
+            noise_gain,
+            dmc_gain,
+        ] = Channel::ALL.map(|channel| self.effective_gain(channel));
+
+        let pulse_sum = pulse_1 + pulse_2;
+        let pulse = if pulse_sum == 0.0 {
+            0.0
+        } else {
+            self.pulse[pulse_sum as usize] * (pulse_1 * pulse_1_gain + pulse_2 * pulse_2_gain)
+                / pulse_sum
+        };
+
+        let tnd_sum = 3.0 * triangle + 2.0 * noise + dmc;
+        let tnd = if tnd_sum == 0.0 {
+            0.0
+        } else {
+            self.tnd[tnd_sum as usize]
+                * (3.0 * triangle * triangle_gain + 2.0 * noise * noise_gain + dmc * dmc_gain)
+                / tnd_sum
+        };
+        pulse + tnd
+    }
+
+    /// Mixes the VRC6 output, 0-61. The cartridge sums its channels
+    /// linearly, a pulse at full volume as loud as a 2A03 one
+    pub fn mix_vrc6(&self, level: u8) -> f32 {
+        self.pulse[15] * f32::from(level) / 15.0
+    }
+}
+
+impl Default for Mixer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_mix_is_nonlinear() {
+        let mixer = Mixer::new();
+        assert_eq!(mixer.mix([0; 5]), 0.0);
+        let one = mixer.mix([15, 0, 0, 0, 0]);
+        let both = mixer.mix([15, 15, 0, 0, 0]);
+        assert!((one - 0.1488).abs() < 1e-3);
+        assert!(both < 2.0 * one);
+
+        // Everything at full volume comes to about 1
+        let full = mixer.mix([15, 15, 15, 15, 127]);
+        assert!((full - 1.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_mute_solo_and_gain() {
+        let mut mixer = Mixer::new();
+        let levels = [15, 15, 8, 0, 0];
+        let full = mixer.mix(levels);
+        let triangle = full - mixer.mix([15, 15, 0, 0, 0]);
+
+        mixer.set_muted(Channel::Triangle, true);
+        assert!((mixer.mix(levels) - (full - triangle)).abs() < 1e-6);
+        mixer.set_muted(Channel::Triangle, false);
+
+        mixer.set_solo(Channel::Triangle, true);
+        assert!((mixer.mix(levels) - triangle).abs() < 1e-6);
+        mixer.set_gain(Channel::Triangle, 0.5);
+        assert!((mixer.mix(levels) - triangle / 2.0).abs() < 1e-6);
+
+        mixer.reset_settings();
+        assert_eq!(mixer.mix(levels), full);
+    }
+}
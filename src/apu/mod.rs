@@ -0,0 +1,574 @@
+mod dmc;
+mod frame_counter;
+pub mod mixer;
+mod noise;
+pub mod oscilloscope;
+mod pulse;
+pub mod tables;
+mod triangle;
+mod units;
+mod vrc6;
+
+use dmc::Dmc;
+use frame_counter::{FrameClock, FrameCounter};
+use mixer::Mixer;
+use noise::Noise;
+use oscilloscope::Oscilloscope;
+use pulse::{Pulse, PulseId};
+use triangle::Triangle;
+use vrc6::Vrc6;
+
+use clap::ValueEnum;
+
+use crate::audio::{Audio, DEFAULT_SAMPLE_RATE, ring::RingBuffer};
+use crate::nes::Region;
+use crate::nes::nsf::ExpansionChips;
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+const PULSE_1: u16 = 0x4000;
+const PULSE_1_END: u16 = 0x4003;
+const PULSE_2: u16 = 0x4004;
+const PULSE_2_END: u16 = 0x4007;
+const TRIANGLE: u16 = 0x4008;
+const TRIANGLE_END: u16 = 0x400B;
+const NOISE: u16 = 0x400C;
+const NOISE_END: u16 = 0x400F;
+const DMC: u16 = 0x4010;
+const DMC_END: u16 = 0x4013;
+const STATUS: u16 = 0x4015;
+const FRAME_COUNTER: u16 = 0x4017;
+
+/// The sound channels of the APU
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Channel {
+    Pulse1,
+    Pulse2,
+    Triangle,
+    Noise,
+    Dmc,
+}
+
+impl Channel {
+    pub const ALL: [Channel; 5] = [
+        Self::Pulse1,
+        Self::Pulse2,
+        Self::Triangle,
+        Self::Noise,
+        Self::Dmc,
+    ];
+}
+
+/// The 2A03 audio processing unit, clocked once per CPU cycle
+pub struct Apu {
+    region: Region,
+    pulse_1: Pulse,
+    pulse_2: Pulse,
+    triangle: Triangle,
+    noise: Noise,
+    dmc: Dmc,
+    /// Expansion audio on the cartridge, when it has the chip
+    vrc6: Option<Vrc6>,
+    frame_counter: FrameCounter,
+    mixer: Mixer,
+    oscilloscope: Oscilloscope,
+    audio: Audio,
+    /// CPU cycles since power on, the channel timers run on every other one
+    cycles: u64,
+}
+
+impl Apu {
+    pub fn new(region: Region) -> Self {
+        Self {
+            region,
+            pulse_1: Pulse::new(PulseId::One),
+            pulse_2: Pulse::new(PulseId::Two),
+            triangle: Triangle::default(),
+            noise: Noise::new(region),
+            dmc: Dmc::new(region),
+            vrc6: None,
+            frame_counter: FrameCounter::new(region),
+            mixer: Mixer::new(),
+            oscilloscope: Oscilloscope::new(),
+            audio: Audio::new(region.cpu_clock(), DEFAULT_SAMPLE_RATE),
+            cycles: 0,
+        }
+    }
+
+    /// Writes one of the $4000-$4017 registers
+    pub fn write_register(&mut self, addr: u16, data: u8) {
+        match addr {
+            PULSE_1..=PULSE_1_END => self.pulse_1.write(addr & 0b11, data),
+            PULSE_2..=PULSE_2_END => self.pulse_2.write(addr & 0b11, data),
+            TRIANGLE..=TRIANGLE_END => self.triangle.write(addr & 0b11, data),
+            NOISE..=NOISE_END => self.noise.write(addr & 0b11, data),
+            DMC..=DMC_END => self.dmc.write(addr & 0b11, data),
+            STATUS => {
+                self.pulse_1.length.set_enabled(data & 0b0000_0001 != 0);
+                self.pulse_2.length.set_enabled(data & 0b0000_0010 != 0);
+                self.triangle.length.set_enabled(data & 0b0000_0100 != 0);
+                self.noise.length.set_enabled(data & 0b0000_1000 != 0);
+                self.dmc.set_enabled(data & 0b0001_0000 != 0);
+                self.dmc.interrupt = false;
+            }
+            FRAME_COUNTER => self
+                .frame_counter
+                .write(data, !self.cycles.is_multiple_of(2)),
+            _ => {}
+        }
+    }
+
+    /// Plugs in the expansion audio of `chips`, silent and at power on
+    /// state, and unplugs the rest. Returns the chips that are not emulated
+    pub fn set_expansions(&mut self, chips: ExpansionChips) -> ExpansionChips {
+        self.vrc6 = chips.contains(ExpansionChips::VRC6).then(Vrc6::default);
+        ExpansionChips(chips.0 & !ExpansionChips::VRC6)
+    }
+
+    /// Passes a write to the cartridge on to the expansion audio
+    pub fn write_expansion(&mut self, addr: u16, data: u8) {
+        if let Some(vrc6) = &mut self.vrc6 {
+            vrc6.write(addr, data);
+        }
+    }
+
+    /// $4015, one bit per channel whose length counter is still running,
+    /// whether the DMC has bytes left, and the interrupt flags. Reading it
+    /// acknowledges the frame IRQ
+    pub fn read_status(&mut self) -> u8 {
+        let status = (self.pulse_1.length.is_active() as u8)
+            | (self.pulse_2.length.is_active() as u8) << 1
+            | (self.triangle.length.is_active() as u8) << 2
+            | (self.noise.length.is_active() as u8) << 3
+            | (self.dmc.is_active() as u8) << 4
+            | (self.frame_counter.interrupt as u8) << 6
+            | (self.dmc.interrupt as u8) << 7;
+        self.frame_counter.acknowledge();
+        status
+    }
+
+    /// Level of the IRQ line, which stays asserted until acknowledged
+    pub fn irq(&self) -> bool {
+        self.frame_counter.interrupt || self.dmc.interrupt
+    }
+
+    /// What the reset line does: silences every channel and restarts the
+    /// frame counter in the mode last written to $4017
+    pub fn reset(&mut self) {
+        self.write_register(STATUS, 0);
+        self.frame_counter.reset();
+    }
+
+    /// Address the DMC wants fetched, the bus halts the CPU to do it
+    pub fn dmc_dma_request(&self) -> Option<u16> {
+        self.dmc.dma_request()
+    }
+
+    pub fn dmc_dma_complete(&mut self, data: u8) {
+        self.dmc.fill_buffer(data);
+    }
+
+    /// Runs one CPU cycle
+    pub fn tick(&mut self) {
+        self.cycles += 1;
+        self.triangle.clock();
+        self.noise.clock();
+        self.dmc.clock();
+        if self.cycles.is_multiple_of(2) {
+            self.pulse_1.clock();
+            self.pulse_2.clock();
+        }
+        match self.frame_counter.clock() {
+            Some(FrameClock::Quarter) => self.quarter_frame(),
+            Some(FrameClock::Half) => {
+                self.quarter_frame();
+                self.half_frame();
+            }
+            None => {}
+        }
+        let levels = self.levels();
+        self.oscilloscope.record(levels);
+        let mut sample = self.mixer.mix(levels);
+        if let Some(vrc6) = &mut self.vrc6 {
+            vrc6.clock();
+            sample += self.mixer.mix_vrc6(vrc6.output());
+        }
+        self.audio.clock(sample);
+    }
+
+    /// Output levels of every channel, ordered as [`Channel::ALL`]
+    fn levels(&self) -> [u8; 5] {
+        Channel::ALL.map(|channel| self.output(channel))
+    }
+
+    /// Per-channel mute, solo and gain
+    pub fn mixer_mut(&mut self) -> &mut Mixer {
+        &mut self.mixer
+    }
+
+    pub fn mixer(&self) -> &Mixer {
+        &self.mixer
+    }
+
+    pub fn oscilloscope(&self) -> &Oscilloscope {
+        &self.oscilloscope
+    }
+
+    pub fn oscilloscope_mut(&mut self) -> &mut Oscilloscope {
+        &mut self.oscilloscope
+    }
+
+    /// Changes the rate of the PCM output, dropping what was not drained
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.audio = Audio::new(self.region.cpu_clock(), sample_rate);
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.audio.sample_rate()
+    }
+
+    /// Scales the rate samples are produced at, see
+    /// [`crate::audio::rate_control::RateControl`]
+    pub fn set_rate_adjustment(&mut self, factor: f64) {
+        self.audio.set_rate_adjustment(factor);
+    }
+
+    /// PCM samples produced so far, for the frontend to drain
+    pub fn samples(&mut self) -> &mut RingBuffer {
+        self.audio.samples()
+    }
+
+    /// Clocks the envelopes and the linear counter, four times per frame
+    pub fn quarter_frame(&mut self) {
+        self.pulse_1.quarter_frame();
+        self.pulse_2.quarter_frame();
+        self.triangle.quarter_frame();
+        self.noise.quarter_frame();
+    }
+
+    /// Clocks the length counters and sweeps, twice per frame
+    pub fn half_frame(&mut self) {
+        self.pulse_1.half_frame();
+        self.pulse_2.half_frame();
+        self.triangle.half_frame();
+        self.noise.half_frame();
+    }
+
+    /// Level a channel outputs right now, 0-15 or 0-127 for the DMC
+    pub fn output(&self, channel: Channel) -> u8 {
+        match channel {
+            Channel::Pulse1 => self.pulse_1.output(),
+            Channel::Pulse2 => self.pulse_2.output(),
+            Channel::Triangle => self.triangle.output(),
+            Channel::Noise => self.noise.output(),
+            Channel::Dmc => self.dmc.output(),
+        }
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    /// CPU cycles the APU has run for since power on
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+}
+
+impl Snapshot for Apu {
+    fn save(&self, state: &mut StateWriter) {
+        self.pulse_1.save(state);
+        self.pulse_2.save(state);
+        self.triangle.save(state);
+        self.noise.save(state);
+        self.dmc.save(state);
+        self.frame_counter.save(state);
+        if let Some(vrc6) = &self.vrc6 {
+            vrc6.save(state);
+        }
+        state.u64(self.cycles);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.pulse_1.load(state)?;
+        self.pulse_2.load(state)?;
+        self.triangle.load(state)?;
+        self.noise.load(state)?;
+        self.dmc.load(state)?;
+        self.frame_counter.load(state)?;
+        if let Some(vrc6) = &mut self.vrc6 {
+            vrc6.load(state)?;
+        }
+        self.cycles = state.u64()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu::{CPU, mem::Memory};
+    use crate::mapper;
+    use crate::nes::{NesRom, RomMapper};
+    use std::{fs, path::Path};
+
+    /// Written to $6001-$6003 by blargg's test ROMs once $6000 holds a status
+    const SIGNATURE: [u8; 3] = [0xDE, 0xB0, 0x61];
+    const STATUS: u16 = 0x6000;
+    const TEXT: u16 = 0x6004;
+    const STATUS_RUNNING: u8 = 0x80;
+    const STATUS_NEEDS_RESET: u8 = 0x81;
+    /// The ROMs want the reset pressed no sooner than 100 ms after asking
+    const RESET_DELAY_FRAMES: u64 = 6;
+    const TIMEOUT_FRAMES: u64 = 60 * 60;
+
+    /// Runs a ROM speaking blargg's $6000 protocol until it reports a result,
+    /// pressing reset when asked. Ok holds the text of a pass, Err the result
+    /// code and text of a failure
+    fn run_test_rom(mut rom: NesRom) -> Result<String, String> {
+        // The ROMs report through PRG RAM even when their header has none
+        if rom.header.nes2.is_none() {
+            rom.header.len_prg_ram = rom.header.len_prg_ram.max(1);
+        }
+        let mut cpu = CPU::with_rom(rom).map_err(|err| err.to_string())?;
+        cpu.reset();
+        let mut reset_at = None;
+        for frame in 1..=TIMEOUT_FRAMES {
+            if !cpu.run_frames(frame) {
+                return Err("the CPU stopped".to_string());
+            }
+            if [1, 2, 3].map(|offset| cpu.mem_peek(STATUS + offset)) != SIGNATURE {
+                continue;
+            }
+            match cpu.mem_peek(STATUS) {
+                STATUS_RUNNING => {}
+                STATUS_NEEDS_RESET => {
+                    if frame >= *reset_at.get_or_insert(frame + RESET_DELAY_FRAMES) {
+                        cpu.reset();
+                        reset_at = None;
+                    }
+                }
+                code => {
+                    let text: String = (TEXT..=0x7FFF)
+                        .map(|addr| cpu.mem_peek(addr))
+                        .take_while(|&byte| byte != 0)
+                        .map(char::from)
+                        .collect();
+                    return match code {
+                        0 => Ok(text),
+                        code => Err(format!("result {code}: {}", text.trim_end())),
+                    };
+                }
+            }
+        }
+        Err(format!("no result after {TIMEOUT_FRAMES} frames"))
+    }
+
+    /// Runs every ROM in `test/<suite>`, the rom_singles of one of blargg's
+    /// suites, and lists the ones that fail
+    fn run_suite(suite: &str) {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("test")
+            .join(suite);
+        let mut paths: Vec<_> = fs::read_dir(&dir)
+            .unwrap_or_else(|err| panic!("{}: {err}", dir.display()))
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "nes"))
+            .collect();
+        paths.sort();
+        assert!(!paths.is_empty(), "no ROMs in {}", dir.display());
+
+        let failures: Vec<_> = paths
+            .iter()
+            .filter_map(|path| {
+                let bytes = fs::read(path).unwrap();
+                let (_, rom) = NesRom::parse(&bytes).unwrap();
+                let name = path.file_name().unwrap().to_string_lossy();
+                run_test_rom(rom).err().map(|err| format!("{name}: {err}"))
+            })
+            .collect();
+        assert!(failures.is_empty(), "{}", failures.join("\n"));
+    }
+
+    /// An NROM cart running `program` from $8000, then looping at its end
+    fn status_rom(program: &[u8]) -> NesRom {
+        let mut rom = mapper::test::rom(RomMapper::None);
+        let end = 0x8000 + program.len() as u16;
+        let [lo, hi] = end.to_le_bytes();
+        rom.prg_rom[..program.len()].copy_from_slice(program);
+        rom.prg_rom[program.len()..][..3].copy_from_slice(&[0x4C, lo, hi]);
+        rom.prg_rom[0x3FFC..0x3FFE].copy_from_slice(&[0x00, 0x80]);
+        rom
+    }
+
+    /// LDA #value, STA addr
+    fn store(program: &mut Vec<u8>, addr: u16, value: u8) {
+        let [lo, hi] = addr.to_le_bytes();
+        program.extend([0xA9, value, 0x8D, lo, hi]);
+    }
+
+    fn report(program: &mut Vec<u8>, status: u8, text: &str) {
+        for (addr, &byte) in (STATUS + 1..).zip(&SIGNATURE) {
+            store(program, addr, byte);
+        }
+        for (addr, byte) in (TEXT..).zip(text.bytes().chain([0])) {
+            store(program, addr, byte);
+        }
+        store(program, STATUS, status);
+    }
+
+    #[test]
+    fn test_status_protocol() {
+        let mut program = Vec::new();
+        store(&mut program, STATUS, STATUS_RUNNING);
+        report(&mut program, 0, "Passed");
+        assert_eq!(run_test_rom(status_rom(&program)), Ok("Passed".to_string()));
+
+        let mut program = Vec::new();
+        report(&mut program, 3, "Failed\n");
+        assert_eq!(
+            run_test_rom(status_rom(&program)),
+            Err("result 3: Failed".to_string())
+        );
+    }
+
+    #[test]
+    fn test_status_protocol_reset() {
+        // PRG RAM survives the reset, $6100 tells the two runs apart
+        let mut program = vec![0xAD, 0x00, 0x61, 0xC9, 0x42, 0xF0, 0x00];
+        store(&mut program, 0x6100, 0x42);
+        report(&mut program, STATUS_NEEDS_RESET, "");
+        let [lo, hi] = (0x8000 + program.len() as u16).to_le_bytes();
+        program.extend([0x4C, lo, hi]);
+        program[6] = (program.len() - 7) as u8;
+        report(&mut program, 0, "Reset");
+        assert_eq!(run_test_rom(status_rom(&program)), Ok("Reset".to_string()));
+    }
+
+    #[test]
+    #[ignore = "needs blargg's apu_test ROMs in test/apu_test"]
+    fn test_blargg_apu_test() {
+        run_suite("apu_test");
+    }
+
+    #[test]
+    #[ignore = "needs blargg's apu_reset ROMs in test/apu_reset"]
+    fn test_blargg_apu_reset() {
+        run_suite("apu_reset");
+    }
+
+    #[test]
+    fn test_status_follows_length_counters() {
+        let mut apu = Apu::new(Region::NTSC);
+        apu.write_register(0x4003, 0b0000_1000);
+        assert_eq!(apu.read_status(), 0b00);
+
+        apu.write_register(0x4015, 0b1111);
+        for addr in [0x4003, 0x4007, 0x400B, 0x400F] {
+            apu.write_register(addr, 0b0000_1000);
+        }
+        assert_eq!(apu.read_status(), 0b1111);
+
+        apu.write_register(0x4015, 0b1010);
+        assert_eq!(apu.read_status(), 0b1010);
+    }
+
+    #[test]
+    fn test_pulse_clocked_from_cpu_cycles() {
+        let mut apu = Apu::new(Region::NTSC);
+        apu.write_register(0x4015, 0b01);
+        // 25% duty, constant volume 15, period 1
+        apu.write_register(0x4000, 0b0101_1111);
+        apu.write_register(0x4002, 0x08);
+        apu.write_register(0x4003, 0x00);
+
+        let mut levels = Vec::new();
+        for _ in 0..8 * 9 {
+            for _ in 0..2 {
+                apu.tick();
+            }
+            levels.push(apu.output(Channel::Pulse1));
+        }
+        // Two of eight steps are high, each lasting 9 APU cycles
+        assert_eq!(levels.iter().filter(|&&level| level == 15).count(), 2 * 9);
+        assert_eq!(apu.output(Channel::Pulse2), 0);
+    }
+
+    #[test]
+    fn test_triangle_clocked_every_cpu_cycle() {
+        let mut apu = Apu::new(Region::NTSC);
+        apu.write_register(0x4015, 0b100);
+        apu.write_register(0x4008, 0b0111_1111);
+        apu.write_register(0x400A, 0x00);
+        apu.write_register(0x400B, 0x00);
+        apu.quarter_frame();
+
+        assert_eq!(apu.output(Channel::Triangle), 15);
+        apu.tick();
+        assert_eq!(apu.output(Channel::Triangle), 14);
+        apu.tick();
+        assert_eq!(apu.output(Channel::Triangle), 13);
+    }
+
+    #[test]
+    fn test_frame_irq_acknowledged_by_status_read() {
+        let mut apu = Apu::new(Region::NTSC);
+        for _ in 0..29830 {
+            apu.tick();
+        }
+        assert!(apu.irq());
+        assert_eq!(apu.read_status(), 0b0100_0000);
+        assert!(!apu.irq());
+        assert_eq!(apu.read_status(), 0);
+    }
+
+    #[test]
+    fn test_frame_counter_clocks_length_counters() {
+        let mut apu = Apu::new(Region::NTSC);
+        apu.write_register(0x4015, 0b01);
+        // Length index 3 loads 2, gone after two half frames
+        apu.write_register(0x4003, 0b0001_1000);
+        // Five steps, clocking a half frame right after the write
+        apu.write_register(0x4017, 0b1000_0000);
+        for _ in 0..4 {
+            apu.tick();
+        }
+        assert_eq!(apu.read_status(), 0b01);
+        for _ in 0..14913 {
+            apu.tick();
+        }
+        assert_eq!(apu.read_status(), 0b00);
+    }
+
+    #[test]
+    fn test_reset_silences_and_rewrites_frame_counter() {
+        let mut apu = Apu::new(Region::NTSC);
+        apu.write_register(0x4015, 0b01);
+        apu.write_register(0x4003, 0b0000_1000);
+        apu.write_register(0x4017, 0b0100_0000);
+        apu.reset();
+        assert_eq!(apu.read_status(), 0);
+        assert_eq!(apu.frame_counter.last_write(), 0b0100_0000);
+        for _ in 0..29830 {
+            apu.tick();
+        }
+        assert!(!apu.irq());
+    }
+
+    #[test]
+    fn test_produces_samples_at_sample_rate() {
+        let mut apu = Apu::new(Region::NTSC);
+        apu.set_sample_rate(44_100);
+        apu.write_register(0x4015, 0b01);
+        apu.write_register(0x4000, 0b1011_1111);
+        apu.write_register(0x4002, 0xFD);
+        apu.write_register(0x4003, 0x00);
+        for _ in 0..Region::NTSC.cpu_clock() / 10 {
+            apu.tick();
+        }
+        let samples = apu.samples();
+        assert!((4409..=4410).contains(&samples.len()));
+        let mut out = vec![0.0; samples.len()];
+        samples.drain_into(&mut out);
+        assert!(out.iter().any(|&sample| sample > 0.05));
+    }
+}
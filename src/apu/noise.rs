@@ -0,0 +1,151 @@
+use super::tables;
+use super::units::{Envelope, LengthCounter};
+use crate::nes::Region;
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+/// The noise channel, $400C-$400F: a pseudo-random bit stream out of a 15-bit
+/// linear feedback shift register
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Noise {
+    periods: &'static [u16; 16],
+    /// Feeds back from bit 6 instead of bit 1, for a 93 step metallic tone
+    short_mode: bool,
+    shift: u16,
+    /// Timer period in CPU cycles
+    period: u16,
+    timer: u16,
+    envelope: Envelope,
+    pub(super) length: LengthCounter,
+}
+
+impl Noise {
+    pub fn new(region: Region) -> Self {
+        let periods = tables::noise_periods(region);
+        Self {
+            periods,
+            short_mode: false,
+            shift: 1,
+            period: periods[0],
+            timer: 0,
+            envelope: Envelope::default(),
+            length: LengthCounter::default(),
+        }
+    }
+
+    /// Writes one of the four registers, `register` being the address & 3
+    pub fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => {
+                self.length.halted = data & 0b0010_0000 != 0;
+                self.envelope.write(data);
+            }
+            1 => {}
+            2 => {
+                self.short_mode = data & 0b1000_0000 != 0;
+                self.period = self.periods[(data & 0b1111) as usize];
+            }
+            3 => {
+                self.length.reload(data);
+                self.envelope.restart();
+            }
+            _ => unreachable!("the noise channel has four registers"),
+        }
+    }
+
+    /// Clocks the timer, once every CPU cycle
+    pub fn clock(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period - 1;
+            let tap = if self.short_mode { 6 } else { 1 };
+            let feedback = (self.shift ^ (self.shift >> tap)) & 1;
+            self.shift = (self.shift >> 1) | feedback << 14;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub fn quarter_frame(&mut self) {
+        self.envelope.quarter_frame();
+    }
+
+    pub fn half_frame(&mut self) {
+        self.length.half_frame();
+    }
+
+    /// The current level, 0-15
+    pub fn output(&self) -> u8 {
+        if !self.length.is_active() || self.shift & 1 != 0 {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+}
+
+impl Snapshot for Noise {
+    fn save(&self, state: &mut StateWriter) {
+        state.bool(self.short_mode);
+        state.u16(self.shift);
+        state.u16(self.period);
+        state.u16(self.timer);
+        self.envelope.save(state);
+        self.length.save(state);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.short_mode = state.bool()?;
+        self.shift = state.u16()?;
+        self.period = state.u16()?.max(1);
+        self.timer = state.u16()?;
+        self.envelope.load(state)?;
+        self.length.load(state)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Steps the shift register until it is back to its start, one step per
+    /// period of the fastest rate
+    fn sequence_length(noise: &mut Noise) -> usize {
+        let start = noise.shift;
+        let mut steps = 0;
+        loop {
+            for _ in 0..noise.period {
+                noise.clock();
+            }
+            steps += 1;
+            if noise.shift == start {
+                return steps;
+            }
+        }
+    }
+
+    #[test]
+    fn test_long_and_short_modes() {
+        let mut noise = Noise::new(Region::NTSC);
+        noise.write(2, 0x00);
+        assert_eq!(sequence_length(&mut noise), 32767);
+        noise.write(2, 0x80);
+        assert_eq!(sequence_length(&mut noise), 93);
+    }
+
+    #[test]
+    fn test_output_and_region_periods() {
+        let mut noise = Noise::new(Region::NTSC);
+        noise.length.set_enabled(true);
+        noise.write(0, 0b0001_0101);
+        noise.write(3, 0b0000_1000);
+        // The register starts at 1, so the first output is muted
+        assert_eq!(noise.output(), 0);
+        noise.clock();
+        assert_eq!(noise.output(), 5);
+
+        noise.write(2, 0x0F);
+        assert_eq!(noise.period, 4068);
+        let mut pal = Noise::new(Region::PAL);
+        pal.write(2, 0x0F);
+        assert_eq!(pal.period, 3778);
+    }
+}
@@ -0,0 +1,198 @@
+use std::collections::VecDeque;
+
+use super::Channel;
+use crate::image::Image;
+
+/// Points kept per channel, twice what a view shows so that it can start
+/// on a trigger
+const HISTORY: usize = 2048;
+/// CPU cycles between two points, about 45 kHz on NTSC
+const DEFAULT_DECIMATION: u32 = 40;
+/// A grid line and a row each for the lowest and highest level
+const MIN_LANE_HEIGHT: usize = 3;
+
+const BACKGROUND: [u8; 3] = [0x10, 0x10, 0x18];
+const GRID: [u8; 3] = [0x30, 0x30, 0x40];
+const COLORS: [[u8; 3]; 5] = [
+    [0xF0, 0x60, 0x60],
+    [0xF0, 0xB0, 0x40],
+    [0x60, 0xD0, 0x60],
+    [0x60, 0xA0, 0xF0],
+    [0xC0, 0x80, 0xF0],
+];
+
+impl Channel {
+    /// Highest level the channel outputs
+    pub fn max_level(self) -> u8 {
+        match self {
+            Self::Dmc => 127,
+            _ => 15,
+        }
+    }
+}
+
+/// Recent levels of every channel, drawn one lane per channel
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Oscilloscope {
+    decimation: u32,
+    countdown: u32,
+    history: [VecDeque<u8>; 5],
+}
+
+impl Oscilloscope {
+    pub fn new() -> Self {
+        Self {
+            decimation: DEFAULT_DECIMATION,
+            countdown: 0,
+            history: Default::default(),
+        }
+    }
+
+    /// Records a point every `decimation` CPU cycles, lower values show
+    /// shorter spans in more detail
+    pub fn set_decimation(&mut self, decimation: u32) {
+        self.decimation = decimation.max(1);
+    }
+
+    /// Takes the channel levels of one CPU cycle, ordered as [`Channel::ALL`]
+    pub fn record(&mut self, levels: [u8; 5]) {
+        if self.countdown > 0 {
+            self.countdown -= 1;
+            return;
+        }
+        self.countdown = self.decimation - 1;
+        for (history, level) in self.history.iter_mut().zip(levels) {
+            if history.len() == HISTORY {
+                history.pop_front();
+            }
+            history.push_back(level);
+        }
+    }
+
+    /// Recorded levels of a channel, oldest first
+    pub fn history(&self, channel: Channel) -> &VecDeque<u8> {
+        &self.history[channel as usize]
+    }
+
+    /// The last `width` points of a channel, moved back to the latest rising
+    /// edge when there is one so that periodic waves stand still from frame
+    /// to frame
+    fn window(&self, channel: Channel, width: usize) -> Vec<u8> {
+        let history = self.history(channel);
+        let width = width.min(history.len());
+        let latest = history.len() - width;
+        let start = (1..=latest)
+            .rev()
+            .find(|&i| history[i - 1] < history[i])
+            .unwrap_or(latest);
+        history.range(start..start + width).copied().collect()
+    }
+
+    /// Draws every channel in a lane of its own, `width` points wide. Lanes
+    /// are at least 3 pixels high
+    pub fn render(&self, width: usize, lane_height: usize) -> Image {
+        let lane_height = lane_height.max(MIN_LANE_HEIGHT);
+        let mut image = Image::new(width, lane_height * Channel::ALL.len());
+        for y in 0..image.height {
+            for x in 0..width {
+                let color = if y % lane_height == 0 {
+                    GRID
+                } else {
+                    BACKGROUND
+                };
+                image.set_pixel(x, y, color);
+            }
+        }
+
+        for (lane, channel) in Channel::ALL.into_iter().enumerate() {
+            let top = lane * lane_height;
+            let bottom = top + lane_height - 1;
+            let scale = (lane_height - 2) as f32 / channel.max_level() as f32;
+            let row = |level: u8| bottom - (level as f32 * scale).round() as usize;
+
+            let points = self.window(channel, width);
+            let mut previous = points.first().map(|&level| row(level));
+            for (x, &level) in points.iter().enumerate() {
+                let current = row(level);
+                // Vertical strokes join the steps of square waves
+                let from = previous.unwrap_or(current);
+                for y in from.min(current)..=from.max(current) {
+                    image.set_pixel(x, y, COLORS[lane]);
+                }
+                previous = Some(current);
+            }
+        }
+        image
+    }
+}
+
+impl Default for Oscilloscope {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_records_every_decimation_cycles() {
+        let mut scope = Oscilloscope::new();
+        scope.set_decimation(4);
+        for level in 0..12 {
+            scope.record([level, 0, 0, 0, 0]);
+        }
+        assert_eq!(scope.history(Channel::Pulse1), &[0, 4, 8]);
+    }
+
+    #[test]
+    fn test_render_lanes() {
+        let mut scope = Oscilloscope::new();
+        scope.set_decimation(1);
+        for n in 0..64 {
+            let high = (n / 8) % 2 == 1;
+            scope.record([if high { 15 } else { 0 }, 0, 0, 0, 127]);
+        }
+        let image = scope.render(32, 20);
+        assert_eq!((image.width, image.height), (32, 100));
+
+        // The view starts on the rising edge, so the pulse is high from x 0
+        assert_eq!(image.pixel(0, 1), COLORS[0]);
+        assert_eq!(image.pixel(8, 19), COLORS[0]);
+        // A silent channel is a flat line at the bottom of its lane
+        assert_eq!(image.pixel(5, 39), COLORS[1]);
+        assert_eq!(image.pixel(5, 30), BACKGROUND);
+        // The DMC scales against its own maximum
+        assert_eq!(image.pixel(5, 81), COLORS[4]);
+    }
+
+    #[test]
+    fn test_window_starts_on_latest_rising_edge() {
+        let mut scope = Oscilloscope::new();
+        scope.set_decimation(1);
+        // Pulses of 5 at 10 and 9 at 70, both early enough to start a view
+        for n in 0..100 {
+            let level = match n {
+                10..20 => 5,
+                70..80 => 9,
+                _ => 0,
+            };
+            scope.record([level, 0, 0, 0, 0]);
+        }
+        let window = scope.window(Channel::Pulse1, 20);
+        assert_eq!(&window[..10], &[9; 10]);
+        assert_eq!(&window[10..], &[0; 10]);
+        // Too short a history to move back keeps the last points
+        assert_eq!(scope.window(Channel::Pulse1, 100).len(), 100);
+    }
+
+    #[test]
+    fn test_render_tiny_lanes() {
+        let scope = Oscilloscope::new();
+        for lane_height in 0..MIN_LANE_HEIGHT {
+            let image = scope.render(8, lane_height);
+            assert_eq!(image.height, MIN_LANE_HEIGHT * Channel::ALL.len());
+        }
+    }
+}
@@ -0,0 +1,246 @@
+use super::units::{Envelope, LengthCounter};
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+/// Output of the eight sequencer steps for each duty cycle
+const DUTY_CYCLES: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0],
+    [0, 1, 1, 1, 1, 0, 0, 0],
+    [1, 0, 0, 1, 1, 1, 1, 1],
+];
+
+/// Periods below this one are muted, the channel could not reproduce them
+const MIN_PERIOD: u16 = 8;
+const MAX_PERIOD: u16 = 0x07FF;
+
+/// Which of the two pulse channels, they differ in how the sweep negates
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PulseId {
+    /// Negates with one's complement, subtracting one more
+    One,
+    /// Negates with two's complement
+    Two,
+}
+
+/// Periodically bends the pitch of a pulse channel up or down
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+struct Sweep {
+    enabled: bool,
+    period: u8,
+    negate: bool,
+    shift: u8,
+    divider: u8,
+    reload: bool,
+}
+
+impl Snapshot for Sweep {
+    fn save(&self, state: &mut StateWriter) {
+        state.bool(self.enabled);
+        state.u8(self.period);
+        state.bool(self.negate);
+        state.u8(self.shift);
+        state.u8(self.divider);
+        state.bool(self.reload);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = state.bool()?;
+        self.period = state.u8()?;
+        self.negate = state.bool()?;
+        self.shift = state.u8()?;
+        self.divider = state.u8()?;
+        self.reload = state.bool()?;
+        Ok(())
+    }
+}
+
+/// A square wave channel, $4000-$4003 or $4004-$4007
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pulse {
+    id: PulseId,
+    duty: u8,
+    step: u8,
+    /// Timer period in APU cycles, minus one
+    period: u16,
+    timer: u16,
+    envelope: Envelope,
+    sweep: Sweep,
+    pub(super) length: LengthCounter,
+}
+
+impl Pulse {
+    pub fn new(id: PulseId) -> Self {
+        Self {
+            id,
+            duty: 0,
+            step: 0,
+            period: 0,
+            timer: 0,
+            envelope: Envelope::default(),
+            sweep: Sweep::default(),
+            length: LengthCounter::default(),
+        }
+    }
+
+    /// Writes one of the four registers, `register` being the address & 3
+    pub fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => {
+                self.duty = data >> 6;
+                self.length.halted = data & 0b0010_0000 != 0;
+                self.envelope.write(data);
+            }
+            1 => {
+                self.sweep.enabled = data & 0b1000_0000 != 0;
+                self.sweep.period = (data >> 4) & 0b111;
+                self.sweep.negate = data & 0b0000_1000 != 0;
+                self.sweep.shift = data & 0b111;
+                self.sweep.reload = true;
+            }
+            2 => self.period = (self.period & 0xFF00) | data as u16,
+            3 => {
+                self.period = (self.period & 0x00FF) | ((data & 0b111) as u16) << 8;
+                self.length.reload(data);
+                self.step = 0;
+                self.envelope.restart();
+            }
+            _ => unreachable!("pulse channels have four registers"),
+        }
+    }
+
+    /// Clocks the timer, once every APU cycle (two CPU cycles)
+    pub fn clock(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period;
+            self.step = (self.step + 1) % 8;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub fn quarter_frame(&mut self) {
+        self.envelope.quarter_frame();
+    }
+
+    pub fn half_frame(&mut self) {
+        self.length.half_frame();
+
+        let sweep = &self.sweep;
+        if sweep.divider == 0 && sweep.enabled && sweep.shift > 0 && !self.sweep_muted() {
+            self.period = self.sweep_target();
+        }
+        if self.sweep.divider == 0 || self.sweep.reload {
+            self.sweep.divider = self.sweep.period;
+            self.sweep.reload = false;
+        } else {
+            self.sweep.divider -= 1;
+        }
+    }
+
+    /// The period the sweep moves to, computed continuously
+    fn sweep_target(&self) -> u16 {
+        let change = self.period >> self.sweep.shift;
+        match (self.sweep.negate, self.id) {
+            (false, _) => self.period + change,
+            (true, PulseId::One) => self.period.saturating_sub(change + 1),
+            (true, PulseId::Two) => self.period.saturating_sub(change),
+        }
+    }
+
+    /// The sweep mutes the channel even when it is disabled
+    fn sweep_muted(&self) -> bool {
+        self.period < MIN_PERIOD || self.sweep_target() > MAX_PERIOD
+    }
+
+    /// The current level, 0-15
+    pub fn output(&self) -> u8 {
+        if !self.length.is_active()
+            || self.sweep_muted()
+            || DUTY_CYCLES[self.duty as usize][self.step as usize] == 0
+        {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+}
+
+impl Snapshot for Pulse {
+    fn save(&self, state: &mut StateWriter) {
+        state.u8(self.duty);
+        state.u8(self.step);
+        state.u16(self.period);
+        state.u16(self.timer);
+        self.envelope.save(state);
+        self.sweep.save(state);
+        self.length.save(state);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.duty = state.u8()? & 0b11;
+        self.step = state.u8()? % 8;
+        self.period = state.u16()?;
+        self.timer = state.u16()?;
+        self.envelope.load(state)?;
+        self.sweep.load(state)?;
+        self.length.load(state)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn playing(id: PulseId, period: u16) -> Pulse {
+        let mut pulse = Pulse::new(id);
+        pulse.length.set_enabled(true);
+        // 50% duty, constant volume 9
+        pulse.write(0, 0b1001_1001);
+        pulse.write(2, period as u8);
+        pulse.write(3, (period >> 8) as u8);
+        pulse
+    }
+
+    #[test]
+    fn test_duty_sequence() {
+        let mut pulse = playing(PulseId::One, 8);
+        let mut levels = Vec::new();
+        for _ in 0..8 {
+            // Each step lasts period + 1 APU cycles
+            for _ in 0..9 {
+                pulse.clock();
+            }
+            levels.push(pulse.output());
+        }
+        assert_eq!(levels, [9, 9, 9, 9, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_sweep_negation_differs_between_channels() {
+        let mut one = playing(PulseId::One, 0x100);
+        let mut two = playing(PulseId::Two, 0x100);
+        for pulse in [&mut one, &mut two] {
+            // Enabled, period 0, negate, shift 1
+            pulse.write(1, 0b1000_1001);
+            pulse.half_frame();
+        }
+        assert_eq!(one.period, 0x100 - 0x80 - 1);
+        assert_eq!(two.period, 0x100 - 0x80);
+    }
+
+    #[test]
+    fn test_sweep_mutes_out_of_range_periods() {
+        let mut pulse = playing(PulseId::Two, 0x3FF);
+        pulse.step = 1;
+        assert_eq!(pulse.output(), 9);
+        // Shift 0 makes the target twice the period, which mutes even with
+        // the sweep disabled
+        let mut pulse = playing(PulseId::Two, 0x400);
+        pulse.step = 1;
+        assert_eq!(pulse.output(), 0);
+
+        let mut pulse = playing(PulseId::Two, 7);
+        pulse.step = 1;
+        assert_eq!(pulse.output(), 0);
+    }
+}
@@ -0,0 +1,61 @@
+//! Periods and step timings of the APU, which differ between regions. Dendy
+//! clones run their APU at NTSC rates
+
+use crate::nes::Region;
+
+/// Noise channel timer periods in CPU cycles, indexed by $400E bits 0-3
+const NTSC_NOISE_PERIODS: [u16; 16] = [
+    4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
+];
+const PAL_NOISE_PERIODS: [u16; 16] = [
+    4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944, 1890, 3778,
+];
+
+/// DMC output rates in CPU cycles, indexed by $4010 bits 0-3
+const NTSC_DMC_RATES: [u16; 16] = [
+    428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+];
+const PAL_DMC_RATES: [u16; 16] = [
+    398, 354, 316, 298, 276, 236, 210, 198, 176, 148, 132, 118, 98, 78, 66, 50,
+];
+
+/// CPU cycles at which the frame counter clocks the units, the last entry
+/// being the end of the sequence
+const NTSC_FOUR_STEP: [u32; 4] = [7457, 14913, 22371, 29829];
+const NTSC_FIVE_STEP: [u32; 5] = [7457, 14913, 22371, 29829, 37281];
+const PAL_FOUR_STEP: [u32; 4] = [8313, 16627, 24939, 33253];
+const PAL_FIVE_STEP: [u32; 5] = [8313, 16627, 24939, 33253, 41565];
+
+pub fn noise_periods(region: Region) -> &'static [u16; 16] {
+    match region {
+        Region::PAL => &PAL_NOISE_PERIODS,
+        Region::NTSC | Region::Dendy => &NTSC_NOISE_PERIODS,
+    }
+}
+
+pub fn dmc_rates(region: Region) -> &'static [u16; 16] {
+    match region {
+        Region::PAL => &PAL_DMC_RATES,
+        Region::NTSC | Region::Dendy => &NTSC_DMC_RATES,
+    }
+}
+
+pub fn four_step_sequence(region: Region) -> &'static [u32; 4] {
+    match region {
+        Region::PAL => &PAL_FOUR_STEP,
+        Region::NTSC | Region::Dendy => &NTSC_FOUR_STEP,
+    }
+}
+
+pub fn five_step_sequence(region: Region) -> &'static [u32; 5] {
+    match region {
+        Region::PAL => &PAL_FIVE_STEP,
+        Region::NTSC | Region::Dendy => &NTSC_FIVE_STEP,
+    }
+}
+
+/// Length counter loads, indexed by bits 3-7 of the channel's last register
+pub const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14, 12, 16, 24, 18, 48, 20, 96, 22,
+    192, 24, 72, 26, 16, 28, 32, 30,
+];
@@ -0,0 +1,162 @@
+use super::units::LengthCounter;
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+/// The 32 steps of the triangle wave, down then up
+const SEQUENCE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12,
+    13, 14, 15,
+];
+
+/// The triangle channel, $4008-$400B. It has no volume control, only the
+/// linear and length counters that stop its sequencer
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Triangle {
+    step: u8,
+    /// Timer period in CPU cycles, minus one
+    period: u16,
+    timer: u16,
+    /// Also the length counter halt flag
+    control: bool,
+    linear_reload_value: u8,
+    linear_counter: u8,
+    linear_reload: bool,
+    pub(super) length: LengthCounter,
+}
+
+impl Triangle {
+    /// Writes one of the four registers, `register` being the address & 3
+    pub fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => {
+                self.control = data & 0b1000_0000 != 0;
+                self.length.halted = self.control;
+                self.linear_reload_value = data & 0b0111_1111;
+            }
+            1 => {}
+            2 => self.period = (self.period & 0xFF00) | data as u16,
+            3 => {
+                self.period = (self.period & 0x00FF) | ((data & 0b111) as u16) << 8;
+                self.length.reload(data);
+                self.linear_reload = true;
+            }
+            _ => unreachable!("the triangle channel has four registers"),
+        }
+    }
+
+    /// Clocks the timer, once every CPU cycle. Periods of 0 and 1 step at
+    /// ultrasonic rates, which the output filters average out to a flat level
+    pub fn clock(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period;
+            if self.linear_counter > 0 && self.length.is_active() {
+                self.step = (self.step + 1) % 32;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub fn quarter_frame(&mut self) {
+        if self.linear_reload {
+            self.linear_counter = self.linear_reload_value;
+        } else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+        if !self.control {
+            self.linear_reload = false;
+        }
+    }
+
+    pub fn half_frame(&mut self) {
+        self.length.half_frame();
+    }
+
+    /// The current level, 0-15. A stopped sequencer holds its last step
+    /// instead of dropping to 0, games rely on that to avoid pops
+    pub fn output(&self) -> u8 {
+        SEQUENCE[self.step as usize]
+    }
+}
+
+impl Snapshot for Triangle {
+    fn save(&self, state: &mut StateWriter) {
+        state.u8(self.step);
+        state.u16(self.period);
+        state.u16(self.timer);
+        state.bool(self.control);
+        state.u8(self.linear_reload_value);
+        state.u8(self.linear_counter);
+        state.bool(self.linear_reload);
+        self.length.save(state);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.step = state.u8()? % 32;
+        self.period = state.u16()?;
+        self.timer = state.u16()?;
+        self.control = state.bool()?;
+        self.linear_reload_value = state.u8()?;
+        self.linear_counter = state.u8()?;
+        self.linear_reload = state.bool()?;
+        self.length.load(state)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn playing(period: u16) -> Triangle {
+        let mut triangle = Triangle::default();
+        triangle.length.set_enabled(true);
+        triangle.write(0, 0b0000_0010);
+        triangle.write(2, period as u8);
+        triangle.write(3, (period >> 8) as u8);
+        triangle.quarter_frame();
+        triangle
+    }
+
+    #[test]
+    fn test_waveform() {
+        let mut triangle = playing(3);
+        let mut levels = Vec::new();
+        for _ in 0..32 {
+            for _ in 0..4 {
+                triangle.clock();
+            }
+            levels.push(triangle.output());
+        }
+        let mut expected = SEQUENCE.to_vec();
+        expected.rotate_left(1);
+        assert_eq!(levels, expected);
+    }
+
+    #[test]
+    fn test_linear_counter_stops_and_holds_level() {
+        let mut triangle = playing(0);
+        for _ in 0..5 {
+            triangle.clock();
+        }
+        assert_eq!(triangle.output(), 10);
+        // The counter was loaded with 2
+        triangle.quarter_frame();
+        triangle.quarter_frame();
+        for _ in 0..10 {
+            triangle.clock();
+        }
+        assert_eq!(triangle.output(), 10);
+    }
+
+    #[test]
+    fn test_ultrasonic_periods_keep_stepping() {
+        let mut triangle = playing(0);
+        let levels: Vec<u8> = (0..64)
+            .map(|_| {
+                triangle.clock();
+                triangle.output()
+            })
+            .collect();
+        let average = levels.iter().map(|&level| level as f32).sum::<f32>() / 64.0;
+        assert_eq!(average, 7.5);
+    }
+}
@@ -0,0 +1,175 @@
+//! Building blocks shared by several channels
+
+use super::tables::LENGTH_TABLE;
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+/// Volume of the pulse and noise channels, either constant or a sawtooth
+/// decaying from 15 at a rate set by the same four bits
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Envelope {
+    start: bool,
+    looping: bool,
+    constant: bool,
+    /// Constant volume, or the period of the divider
+    volume: u8,
+    divider: u8,
+    decay: u8,
+}
+
+impl Envelope {
+    /// Takes the `--LC VVVV` bits of the channel's first register
+    pub fn write(&mut self, data: u8) {
+        self.looping = data & 0b0010_0000 != 0;
+        self.constant = data & 0b0001_0000 != 0;
+        self.volume = data & 0b0000_1111;
+    }
+
+    /// Restarts the decay, on writes to the channel's last register
+    pub fn restart(&mut self) {
+        self.start = true;
+    }
+
+    pub fn quarter_frame(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay = 15;
+            self.divider = self.volume;
+        } else if self.divider == 0 {
+            self.divider = self.volume;
+            if self.decay > 0 {
+                self.decay -= 1;
+            } else if self.looping {
+                self.decay = 15;
+            }
+        } else {
+            self.divider -= 1;
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        if self.constant {
+            self.volume
+        } else {
+            self.decay
+        }
+    }
+}
+
+impl Snapshot for Envelope {
+    fn save(&self, state: &mut StateWriter) {
+        state.bool(self.start);
+        state.bool(self.looping);
+        state.bool(self.constant);
+        state.u8(self.volume);
+        state.u8(self.divider);
+        state.u8(self.decay);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.start = state.bool()?;
+        self.looping = state.bool()?;
+        self.constant = state.bool()?;
+        self.volume = state.u8()?;
+        self.divider = state.u8()?;
+        self.decay = state.u8()?;
+        Ok(())
+    }
+}
+
+/// Silences a channel after a number of half frames, unless halted
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct LengthCounter {
+    enabled: bool,
+    pub halted: bool,
+    counter: u8,
+}
+
+impl LengthCounter {
+    /// The enable bit of $4015, disabling clears the counter right away
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.counter = 0;
+        }
+    }
+
+    /// Loads the counter from bits 3-7 of the channel's last register
+    pub fn reload(&mut self, data: u8) {
+        if self.enabled {
+            self.counter = LENGTH_TABLE[(data >> 3) as usize];
+        }
+    }
+
+    pub fn half_frame(&mut self) {
+        if !self.halted && self.counter > 0 {
+            self.counter -= 1;
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.counter > 0
+    }
+}
+
+impl Snapshot for LengthCounter {
+    fn save(&self, state: &mut StateWriter) {
+        state.bool(self.enabled);
+        state.bool(self.halted);
+        state.u8(self.counter);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = state.bool()?;
+        self.halted = state.bool()?;
+        self.counter = state.u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_envelope_decays_and_loops() {
+        let mut envelope = Envelope::default();
+        envelope.write(0b0010_0001);
+        envelope.restart();
+        envelope.quarter_frame();
+        assert_eq!(envelope.output(), 15);
+        // A period of 1 decays every other quarter frame
+        for _ in 0..30 {
+            envelope.quarter_frame();
+        }
+        assert_eq!(envelope.output(), 0);
+        envelope.quarter_frame();
+        envelope.quarter_frame();
+        assert_eq!(envelope.output(), 15);
+
+        envelope.write(0b0001_0111);
+        assert_eq!(envelope.output(), 7);
+    }
+
+    #[test]
+    fn test_length_counter() {
+        let mut length = LengthCounter::default();
+        length.reload(0b0000_1000);
+        assert!(!length.is_active());
+
+        length.set_enabled(true);
+        length.reload(0b0000_1000);
+        for _ in 0..253 {
+            length.half_frame();
+        }
+        assert!(length.is_active());
+        length.half_frame();
+        assert!(!length.is_active());
+
+        length.reload(0b0000_0000);
+        length.halted = true;
+        length.half_frame();
+        assert!(length.is_active());
+        length.set_enabled(false);
+        assert!(!length.is_active());
+    }
+}
@@ -0,0 +1,334 @@
+//! Konami's VRC6 expansion audio: two pulse channels with eight duty
+//! cycles and a sawtooth, summed linearly on the cartridge
+
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+const PULSE_1: u16 = 0x9000;
+const PULSE_1_END: u16 = 0x9002;
+const FREQUENCY_CONTROL: u16 = 0x9003;
+const PULSE_2: u16 = 0xA000;
+const PULSE_2_END: u16 = 0xA002;
+const SAWTOOTH: u16 = 0xB000;
+const SAWTOOTH_END: u16 = 0xB002;
+
+/// The sawtooth resets after this many steps, its accumulator growing
+/// on every other one
+const SAWTOOTH_STEPS: u8 = 14;
+
+/// The 12-bit period and enable bit every VRC6 channel has in its last two
+/// registers
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+struct Timer {
+    enabled: bool,
+    /// Period in CPU cycles, minus one
+    period: u16,
+    counter: u16,
+}
+
+impl Timer {
+    fn write_low(&mut self, data: u8) {
+        self.period = (self.period & 0x0F00) | data as u16;
+    }
+
+    fn write_high(&mut self, data: u8) {
+        self.period = (self.period & 0x00FF) | ((data & 0x0F) as u16) << 8;
+        self.enabled = data & 0b1000_0000 != 0;
+    }
+
+    /// Counts down one CPU cycle, the period shortened by `shift` bits, and
+    /// tells when the channel steps
+    fn clock(&mut self, shift: u8) -> bool {
+        if !self.enabled {
+            return false;
+        }
+        if self.counter == 0 {
+            self.counter = self.period >> shift;
+            true
+        } else {
+            self.counter -= 1;
+            false
+        }
+    }
+}
+
+impl Snapshot for Timer {
+    fn save(&self, state: &mut StateWriter) {
+        state.bool(self.enabled);
+        state.u16(self.period);
+        state.u16(self.counter);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = state.bool()?;
+        self.period = state.u16()? & 0x0FFF;
+        self.counter = state.u16()?;
+        Ok(())
+    }
+}
+
+/// A pulse channel, $9000-$9002 or $A000-$A002
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+struct Pulse {
+    /// Outputs the volume constantly, ignoring the duty cycle
+    digitized: bool,
+    /// High for `duty + 1` of the 16 steps
+    duty: u8,
+    volume: u8,
+    /// Counts down from 15, the output is high once it reaches `duty`
+    step: u8,
+    timer: Timer,
+}
+
+impl Pulse {
+    fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => {
+                self.digitized = data & 0b1000_0000 != 0;
+                self.duty = (data >> 4) & 0b111;
+                self.volume = data & 0b1111;
+            }
+            1 => self.timer.write_low(data),
+            2 => {
+                self.timer.write_high(data);
+                // Disabling restarts the duty cycle
+                if !self.timer.enabled {
+                    self.step = 15;
+                }
+            }
+            _ => unreachable!("VRC6 pulse channels have three registers"),
+        }
+    }
+
+    fn clock(&mut self, shift: u8) {
+        if self.timer.clock(shift) {
+            self.step = self.step.checked_sub(1).unwrap_or(15);
+        }
+    }
+
+    /// The current level, 0-15
+    fn output(&self) -> u8 {
+        if self.timer.enabled && (self.digitized || self.step <= self.duty) {
+            self.volume
+        } else {
+            0
+        }
+    }
+}
+
+impl Snapshot for Pulse {
+    fn save(&self, state: &mut StateWriter) {
+        state.bool(self.digitized);
+        state.u8(self.duty);
+        state.u8(self.volume);
+        state.u8(self.step);
+        self.timer.save(state);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.digitized = state.bool()?;
+        self.duty = state.u8()? & 0b111;
+        self.volume = state.u8()? & 0b1111;
+        self.step = state.u8()? & 0b1111;
+        self.timer.load(state)
+    }
+}
+
+/// The sawtooth channel, $B000-$B002. Its accumulator grows by `rate` on
+/// every other step and the top five bits are the output
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+struct Sawtooth {
+    rate: u8,
+    accumulator: u8,
+    step: u8,
+    timer: Timer,
+}
+
+impl Sawtooth {
+    fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => self.rate = data & 0b0011_1111,
+            1 => self.timer.write_low(data),
+            2 => {
+                self.timer.write_high(data);
+                if !self.timer.enabled {
+                    self.step = 0;
+                    self.accumulator = 0;
+                }
+            }
+            _ => unreachable!("the VRC6 sawtooth has three registers"),
+        }
+    }
+
+    fn clock(&mut self, shift: u8) {
+        if !self.timer.clock(shift) {
+            return;
+        }
+        self.step += 1;
+        if self.step == SAWTOOTH_STEPS {
+            self.step = 0;
+            self.accumulator = 0;
+        } else if self.step.is_multiple_of(2) {
+            self.accumulator = self.accumulator.wrapping_add(self.rate);
+        }
+    }
+
+    /// The current level, 0-31
+    fn output(&self) -> u8 {
+        self.accumulator >> 3
+    }
+}
+
+impl Snapshot for Sawtooth {
+    fn save(&self, state: &mut StateWriter) {
+        state.u8(self.rate);
+        state.u8(self.accumulator);
+        state.u8(self.step);
+        self.timer.save(state);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.rate = state.u8()? & 0b0011_1111;
+        self.accumulator = state.u8()?;
+        self.step = state.u8()? % SAWTOOTH_STEPS;
+        self.timer.load(state)
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Vrc6 {
+    pulse_1: Pulse,
+    pulse_2: Pulse,
+    sawtooth: Sawtooth,
+    /// Stops every channel where it is
+    halted: bool,
+    /// Bits the periods are shifted right by, to test the chip quickly
+    shift: u8,
+}
+
+impl Vrc6 {
+    /// Writes one of the sound registers, other addresses are left to the
+    /// mapper
+    pub fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            PULSE_1..=PULSE_1_END => self.pulse_1.write(addr - PULSE_1, data),
+            FREQUENCY_CONTROL => {
+                self.halted = data & 0b001 != 0;
+                self.shift = match data & 0b110 {
+                    0b000 => 0,
+                    0b010 => 4,
+                    _ => 8,
+                };
+            }
+            PULSE_2..=PULSE_2_END => self.pulse_2.write(addr - PULSE_2, data),
+            SAWTOOTH..=SAWTOOTH_END => self.sawtooth.write(addr - SAWTOOTH, data),
+            _ => {}
+        }
+    }
+
+    /// Runs one CPU cycle
+    pub fn clock(&mut self) {
+        if self.halted {
+            return;
+        }
+        self.pulse_1.clock(self.shift);
+        self.pulse_2.clock(self.shift);
+        self.sawtooth.clock(self.shift);
+    }
+
+    /// Sum of the three channels, 0-61
+    pub fn output(&self) -> u8 {
+        self.pulse_1.output() + self.pulse_2.output() + self.sawtooth.output()
+    }
+}
+
+impl Snapshot for Vrc6 {
+    fn save(&self, state: &mut StateWriter) {
+        self.pulse_1.save(state);
+        self.pulse_2.save(state);
+        self.sawtooth.save(state);
+        state.bool(self.halted);
+        state.u8(self.shift);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.pulse_1.load(state)?;
+        self.pulse_2.load(state)?;
+        self.sawtooth.load(state)?;
+        self.halted = state.bool()?;
+        self.shift = state.u8()?.min(8);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Levels of the next `cycles` CPU cycles
+    fn levels(vrc6: &mut Vrc6, cycles: usize) -> Vec<u8> {
+        (0..cycles)
+            .map(|_| {
+                vrc6.clock();
+                vrc6.output()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_pulse_duty() {
+        let mut vrc6 = Vrc6::default();
+        // Duty 3 is high for 4 of 16 steps, volume 10, a step every 2 cycles
+        vrc6.write(0x9000, 0b0011_1010);
+        vrc6.write(0x9001, 1);
+        assert_eq!(vrc6.output(), 0);
+        vrc6.write(0x9002, 0x80);
+
+        let period = levels(&mut vrc6, 32);
+        assert_eq!(period.iter().filter(|&&level| level == 10).count(), 8);
+        assert_eq!(period.iter().filter(|&&level| level == 0).count(), 24);
+        assert_eq!(levels(&mut vrc6, 32), period);
+
+        // Digitized mode ignores the duty cycle
+        vrc6.write(0x9000, 0b1000_0110);
+        assert!(levels(&mut vrc6, 32).iter().all(|&level| level == 6));
+
+        vrc6.write(0x9002, 0x00);
+        assert_eq!(vrc6.output(), 0);
+    }
+
+    #[test]
+    fn test_sawtooth_ramp() {
+        let mut vrc6 = Vrc6::default();
+        vrc6.write(0xB000, 42);
+        vrc6.write(0xB002, 0x80);
+        // A period of 0 steps every cycle, adding the rate on every other one
+        let ramp = levels(&mut vrc6, 14);
+        let expected: Vec<u8> = (1..=14u8)
+            .map(|step| {
+                if step == 14 {
+                    0
+                } else {
+                    (42 * (step / 2)) >> 3
+                }
+            })
+            .collect();
+        assert_eq!(ramp, expected);
+        assert_eq!(ramp[12], 31);
+    }
+
+    #[test]
+    fn test_halt_and_frequency_shift() {
+        let mut vrc6 = Vrc6::default();
+        vrc6.write(0xB000, 8);
+        vrc6.write(0xB001, 0xFF);
+        vrc6.write(0xB002, 0x80);
+        vrc6.write(0x9003, 0b001);
+        assert!(levels(&mut vrc6, 1000).iter().all(|&level| level == 0));
+
+        // Shifted by 8 bits, the period of 255 becomes 0
+        vrc6.write(0x9003, 0b100);
+        assert_eq!(levels(&mut vrc6, 2), [0, 1]);
+        vrc6.write(0x9003, 0b000);
+        assert_eq!(levels(&mut vrc6, 256), [1; 256]);
+    }
+}
@@ -0,0 +1,160 @@
+use std::sync::{Arc, Mutex};
+
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use cpal::{
+    FromSample, SampleFormat, SampleRate, SizedSample, StreamConfig, SupportedStreamConfig,
+    SupportedStreamConfigRange,
+};
+use tracing::warn;
+
+use super::ring::RingBuffer;
+use super::sink::{AudioError, AudioSink};
+
+/// Sample formats the stream converts to, in order of preference
+const SAMPLE_FORMATS: [SampleFormat; 4] = [
+    SampleFormat::F32,
+    SampleFormat::I16,
+    SampleFormat::U16,
+    SampleFormat::I32,
+];
+
+/// Plays the samples on the default output device. The device pulls them
+/// from a ring buffer on its own thread, and plays silence when it runs dry
+pub struct CpalSink {
+    samples: Arc<Mutex<RingBuffer>>,
+    sample_rate: u32,
+    // Dropping the stream stops playback
+    _stream: cpal::Stream,
+}
+
+impl CpalSink {
+    /// Opens the default device at `sample_rate`, or at its own rate when it
+    /// does not support that one, buffering up to `capacity_millis` of audio
+    pub fn new(sample_rate: u32, capacity_millis: usize) -> Result<Self, AudioError> {
+        let device = cpal::default_host()
+            .default_output_device()
+            .ok_or_else(|| AudioError::Device("no output device".to_string()))?;
+        let ranges = device
+            .supported_output_configs()
+            .map_err(|err| AudioError::Device(err.to_string()))?;
+        let supported = match pick_config(ranges, sample_rate) {
+            Some(supported) => supported,
+            None => device
+                .default_output_config()
+                .map_err(|err| AudioError::Device(err.to_string()))?,
+        };
+        let config = supported.config();
+        let sample_rate = config.sample_rate.0;
+
+        let capacity = sample_rate as usize * capacity_millis / 1000;
+        let samples = Arc::new(Mutex::new(RingBuffer::with_capacity(capacity)));
+        let source = samples.clone();
+        let stream = match supported.sample_format() {
+            SampleFormat::F32 => output_stream::<f32>(&device, &config, source),
+            SampleFormat::I16 => output_stream::<i16>(&device, &config, source),
+            SampleFormat::U16 => output_stream::<u16>(&device, &config, source),
+            SampleFormat::I32 => output_stream::<i32>(&device, &config, source),
+            format => {
+                return Err(AudioError::Device(format!(
+                    "unsupported sample format {format}"
+                )));
+            }
+        }
+        .map_err(|err| AudioError::Device(err.to_string()))?;
+        stream
+            .play()
+            .map_err(|err| AudioError::Device(err.to_string()))?;
+
+        Ok(Self {
+            samples,
+            sample_rate,
+            _stream: stream,
+        })
+    }
+}
+
+/// A config at `sample_rate` in the first of [`SAMPLE_FORMATS`] a device
+/// supports at that rate
+fn pick_config(
+    ranges: impl IntoIterator<Item = SupportedStreamConfigRange>,
+    sample_rate: u32,
+) -> Option<SupportedStreamConfig> {
+    let rate = SampleRate(sample_rate);
+    let ranges: Vec<_> = ranges
+        .into_iter()
+        .filter(|range| (range.min_sample_rate()..=range.max_sample_rate()).contains(&rate))
+        .collect();
+    let range = SAMPLE_FORMATS
+        .iter()
+        .find_map(|&format| ranges.iter().find(|range| range.sample_format() == format))?;
+    Some(range.with_sample_rate(rate))
+}
+
+/// A stream converting the mono f32 samples to `T` on every channel
+fn output_stream<T>(
+    device: &cpal::Device,
+    config: &StreamConfig,
+    source: Arc<Mutex<RingBuffer>>,
+) -> Result<cpal::Stream, cpal::BuildStreamError>
+where
+    T: SizedSample + FromSample<f32>,
+{
+    let channels = config.channels as usize;
+    device.build_output_stream(
+        config,
+        move |data: &mut [T], _| {
+            let mut source = source.lock().unwrap();
+            for frame in data.chunks_mut(channels) {
+                frame.fill(T::from_sample(source.pop().unwrap_or(0.0)));
+            }
+        },
+        |err| warn!(target: "audio", "Stream error: {}", err),
+        None,
+    )
+}
+
+impl AudioSink for CpalSink {
+    fn write(&mut self, samples: &[f32]) -> Result<(), AudioError> {
+        let mut buffer = self.samples.lock().unwrap();
+        for &sample in samples {
+            buffer.push(sample);
+        }
+        Ok(())
+    }
+
+    fn buffered(&self) -> Option<usize> {
+        Some(self.samples.lock().unwrap().len())
+    }
+
+    fn sample_rate(&self) -> Option<u32> {
+        Some(self.sample_rate)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use cpal::SupportedBufferSize;
+
+    use super::*;
+
+    fn range(min: u32, max: u32, format: SampleFormat) -> SupportedStreamConfigRange {
+        let buffer_size = SupportedBufferSize::Unknown;
+        SupportedStreamConfigRange::new(2, SampleRate(min), SampleRate(max), buffer_size, format)
+    }
+
+    #[test]
+    fn test_pick_config() {
+        let ranges = [
+            range(44_100, 44_100, SampleFormat::F32),
+            range(8_000, 48_000, SampleFormat::I16),
+            range(8_000, 192_000, SampleFormat::F64),
+        ];
+        let config = pick_config(ranges, 44_100).unwrap();
+        assert_eq!(config.sample_format(), SampleFormat::F32);
+        let config = pick_config(ranges, 48_000).unwrap();
+        assert_eq!(config.sample_format(), SampleFormat::I16);
+        assert_eq!(config.sample_rate(), SampleRate(48_000));
+        // Nothing converts to f64, the caller falls back to the device rate
+        assert_eq!(pick_config(ranges, 96_000), None);
+    }
+}
@@ -0,0 +1,117 @@
+//! First-order RC filters, the ones between the 2A03 and the audio jack
+
+use std::f32::consts::PI;
+
+/// Blocks DC and low frequencies
+#[derive(Debug, Clone, PartialEq)]
+pub struct HighPass {
+    alpha: f32,
+    previous_input: f32,
+    previous_output: f32,
+}
+
+impl HighPass {
+    pub fn new(cutoff: f32, sample_rate: u32) -> Self {
+        let rc = 1.0 / (2.0 * PI * cutoff);
+        let dt = 1.0 / sample_rate as f32;
+        Self {
+            alpha: rc / (rc + dt),
+            previous_input: 0.0,
+            previous_output: 0.0,
+        }
+    }
+
+    pub fn process(&mut self, input: f32) -> f32 {
+        let output = self.alpha * (self.previous_output + input - self.previous_input);
+        self.previous_input = input;
+        self.previous_output = output;
+        output
+    }
+}
+
+/// Softens high frequencies
+#[derive(Debug, Clone, PartialEq)]
+pub struct LowPass {
+    alpha: f32,
+    previous_output: f32,
+}
+
+impl LowPass {
+    pub fn new(cutoff: f32, sample_rate: u32) -> Self {
+        let rc = 1.0 / (2.0 * PI * cutoff);
+        let dt = 1.0 / sample_rate as f32;
+        Self {
+            alpha: dt / (rc + dt),
+            previous_output: 0.0,
+        }
+    }
+
+    pub fn process(&mut self, input: f32) -> f32 {
+        self.previous_output += self.alpha * (input - self.previous_output);
+        self.previous_output
+    }
+}
+
+/// What the NES does to its signal: high-pass at 90 Hz and 440 Hz, then
+/// low-pass at 14 kHz
+#[derive(Debug, Clone, PartialEq)]
+pub struct FilterChain {
+    high_pass_90: HighPass,
+    high_pass_440: HighPass,
+    low_pass_14k: LowPass,
+}
+
+impl FilterChain {
+    pub fn new(sample_rate: u32) -> Self {
+        Self {
+            high_pass_90: HighPass::new(90.0, sample_rate),
+            high_pass_440: HighPass::new(440.0, sample_rate),
+            low_pass_14k: LowPass::new(14_000.0, sample_rate),
+        }
+    }
+
+    pub fn process(&mut self, input: f32) -> f32 {
+        let output = self.high_pass_90.process(input);
+        let output = self.high_pass_440.process(output);
+        self.low_pass_14k.process(output)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Peak output once a sine of `frequency` has settled
+    fn peak(mut filter: impl FnMut(f32) -> f32, frequency: f32) -> f32 {
+        let sample_rate = 48_000;
+        (0..sample_rate)
+            .map(|n| filter((2.0 * PI * frequency * n as f32 / sample_rate as f32).sin()))
+            .skip(sample_rate as usize / 2)
+            .fold(0.0, |peak, sample| sample.abs().max(peak))
+    }
+
+    #[test]
+    fn test_high_pass_blocks_dc() {
+        let mut filter = HighPass::new(90.0, 48_000);
+        let settled = (0..48_000).map(|_| filter.process(1.0)).last().unwrap();
+        assert!(settled.abs() < 1e-3);
+        let mut filter = HighPass::new(90.0, 48_000);
+        assert!(peak(|x| filter.process(x), 5_000.0) > 0.99);
+    }
+
+    #[test]
+    fn test_low_pass_cutoff() {
+        let mut filter = LowPass::new(14_000.0, 48_000);
+        assert!(peak(|x| filter.process(x), 100.0) > 0.99);
+        let mut filter = LowPass::new(14_000.0, 48_000);
+        // About -3 dB at the cutoff, a first order filter rolls off gently
+        assert!(peak(|x| filter.process(x), 14_000.0) < 0.8);
+    }
+
+    #[test]
+    fn test_chain_passes_midrange() {
+        let mut chain = FilterChain::new(48_000);
+        let level = peak(|x| chain.process(x), 2_000.0);
+        assert!((0.9..1.0).contains(&level));
+    }
+}
@@ -0,0 +1,88 @@
+//! Turns the mixed APU level into PCM samples for the frontend
+
+#[cfg(feature = "cpal")]
+pub mod cpal_sink;
+pub mod filter;
+pub mod rate_control;
+pub mod resampler;
+pub mod ring;
+pub mod sink;
+
+use filter::FilterChain;
+use resampler::Resampler;
+use ring::RingBuffer;
+
+pub const DEFAULT_SAMPLE_RATE: u32 = 48_000;
+
+/// How much audio the ring buffer holds
+const BUFFER_MILLIS: u32 = 250;
+
+/// Resamples a level given once per CPU cycle, runs it through the console's
+/// filters and queues the result
+#[derive(Debug, Clone)]
+pub struct Audio {
+    sample_rate: u32,
+    resampler: Resampler,
+    filters: FilterChain,
+    samples: RingBuffer,
+}
+
+impl Audio {
+    pub fn new(clock_rate: u32, sample_rate: u32) -> Self {
+        Self {
+            sample_rate,
+            resampler: Resampler::new(clock_rate, sample_rate),
+            filters: FilterChain::new(sample_rate),
+            samples: RingBuffer::with_capacity((sample_rate * BUFFER_MILLIS / 1000).max(1) as usize),
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Runs one clock with the mixed level, 0.0-1.0
+    pub fn clock(&mut self, level: f32) {
+        let Self {
+            resampler,
+            filters,
+            samples,
+            ..
+        } = self;
+        resampler.clock(level, |sample| samples.push(filters.process(sample)));
+    }
+
+    /// The samples produced so far, for the frontend to drain
+    pub fn samples(&mut self) -> &mut RingBuffer {
+        &mut self.samples
+    }
+
+    pub fn set_rate_adjustment(&mut self, factor: f64) {
+        self.resampler.set_rate_adjustment(factor);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_square_wave_comes_out_centered() {
+        let mut audio = Audio::new(1_789_773, 44_100);
+        // 440 Hz square between 0 and 0.5
+        let half_period = 1_789_773 / 440 / 2;
+        for n in 0..1_789_773 / 8 {
+            audio.clock(if (n / half_period) % 2 == 0 { 0.0 } else { 0.5 });
+        }
+        let samples = audio.samples();
+        assert_eq!(samples.len(), 44_100 / 8);
+
+        let mut out = vec![0.0; samples.len()];
+        samples.drain_into(&mut out);
+        let settled = &out[out.len() / 2..];
+        let mean = settled.iter().sum::<f32>() / settled.len() as f32;
+        assert!(mean.abs() < 0.02);
+        assert!(settled.iter().any(|&sample| sample > 0.2));
+        assert!(settled.iter().all(|&sample| sample.abs() < 0.6));
+    }
+}
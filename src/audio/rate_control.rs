@@ -0,0 +1,51 @@
+/// Dynamic rate control: the emulator and the sound card never run at
+/// exactly the same speed, so instead of letting the device buffer drain
+/// (crackles) or fill up (latency, then dropped samples), the resampling
+/// ratio is nudged by a fraction of a percent towards a target fill level.
+/// The pitch change is far too small to hear
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateControl {
+    /// Samples the device buffer should hold
+    target: usize,
+    /// Largest change of the ratio, either way
+    max_adjustment: f64,
+}
+
+impl RateControl {
+    /// Half a percent, well under what anyone hears as a pitch change
+    pub const DEFAULT_MAX_ADJUSTMENT: f64 = 0.005;
+
+    pub fn new(target: usize) -> Self {
+        Self {
+            target: target.max(1),
+            max_adjustment: Self::DEFAULT_MAX_ADJUSTMENT,
+        }
+    }
+
+    pub fn with_max_adjustment(mut self, max_adjustment: f64) -> Self {
+        self.max_adjustment = max_adjustment;
+        self
+    }
+
+    /// Factor to scale the resampling ratio by, above 1 when the buffer runs
+    /// low so that more samples get produced
+    pub fn adjustment(&self, buffered: usize) -> f64 {
+        let error = (self.target as f64 - buffered as f64) / self.target as f64;
+        1.0 + error.clamp(-1.0, 1.0) * self.max_adjustment
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_adjustment_follows_fill_level() {
+        let control = RateControl::new(1000);
+        assert_eq!(control.adjustment(1000), 1.0);
+        assert_eq!(control.adjustment(0), 1.005);
+        assert!((control.adjustment(1500) - 0.9975).abs() < 1e-9);
+        // A buffer way past the target does not push any further
+        assert_eq!(control.adjustment(5000), 0.995);
+    }
+}
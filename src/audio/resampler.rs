@@ -0,0 +1,155 @@
+//! Band-limited synthesis: every change of the input level is drawn into the
+//! output as a step with no content above the output Nyquist frequency, so
+//! the square waves of the APU do not alias when going from ~1.79 MHz down to
+//! 48 kHz
+
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+
+/// Fractional positions a step can start at between two output samples
+const PHASES: usize = 64;
+/// Output samples each step is spread over, half of it being latency
+const TAPS: usize = 16;
+/// Fraction of the output Nyquist frequency the steps keep, leaving room for
+/// the kernel to roll off
+const CUTOFF: f64 = 0.9;
+
+type Kernel = [[f32; TAPS]; PHASES + 1];
+
+/// Windowed sinc impulses, one row per phase, each summing to 1
+fn kernel() -> Box<Kernel> {
+    let mut kernel = Box::new([[0.0; TAPS]; PHASES + 1]);
+    for (phase, row) in kernel.iter_mut().enumerate() {
+        let center = (TAPS / 2 - 1) as f64 + phase as f64 / PHASES as f64;
+        let mut impulse = [0.0; TAPS];
+        for (k, tap) in impulse.iter_mut().enumerate() {
+            let x = k as f64 - center;
+            let sinc = if x == 0.0 {
+                1.0
+            } else {
+                (PI * CUTOFF * x).sin() / (PI * CUTOFF * x)
+            };
+            // Blackman window spanning the taps
+            let t = (x + (TAPS / 2) as f64) / TAPS as f64;
+            let window = 0.42 - 0.5 * (2.0 * PI * t).cos() + 0.08 * (4.0 * PI * t).cos();
+            *tap = sinc * window;
+        }
+        let sum: f64 = impulse.iter().sum();
+        for (out, tap) in row.iter_mut().zip(impulse) {
+            *out = (tap / sum) as f32;
+        }
+    }
+    kernel
+}
+
+/// Converts a level sampled once per input clock to output samples
+#[derive(Debug, Clone)]
+pub struct Resampler {
+    /// Output samples per input clock, as configured
+    base_ratio: f64,
+    ratio: f64,
+    /// Where the next input clock falls after the oldest pending output
+    /// sample, 0.0-1.0
+    position: f64,
+    level: f32,
+    /// Steps drawn into the output samples that are not complete yet
+    deltas: VecDeque<f32>,
+    integrator: f32,
+    kernel: Box<Kernel>,
+}
+
+impl Resampler {
+    pub fn new(clock_rate: u32, sample_rate: u32) -> Self {
+        let ratio = sample_rate as f64 / clock_rate as f64;
+        Self {
+            base_ratio: ratio,
+            ratio,
+            position: 0.0,
+            level: 0.0,
+            deltas: VecDeque::from(vec![0.0; TAPS]),
+            integrator: 0.0,
+            kernel: kernel(),
+        }
+    }
+
+    /// Scales the output rate by `factor`, which stays close to 1 so that
+    /// the change is inaudible
+    pub fn set_rate_adjustment(&mut self, factor: f64) {
+        self.ratio = self.base_ratio * factor;
+    }
+
+    /// Runs one input clock at `level`, passing every output sample it
+    /// completes to `output`
+    pub fn clock(&mut self, level: f32, mut output: impl FnMut(f32)) {
+        if level != self.level {
+            let delta = level - self.level;
+            self.level = level;
+            let phase = (self.position * PHASES as f64).round() as usize;
+            for (pending, tap) in self.deltas.iter_mut().zip(&self.kernel[phase]) {
+                *pending += delta * tap;
+            }
+        }
+
+        self.position += self.ratio;
+        while self.position >= 1.0 {
+            self.position -= 1.0;
+            self.integrator += self.deltas.pop_front().unwrap();
+            self.deltas.push_back(0.0);
+            output(self.integrator);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn run(resampler: &mut Resampler, levels: impl IntoIterator<Item = f32>) -> Vec<f32> {
+        let mut samples = Vec::new();
+        for level in levels {
+            resampler.clock(level, |sample| samples.push(sample));
+        }
+        samples
+    }
+
+    #[test]
+    fn test_kernel_rows_sum_to_one() {
+        for row in kernel().iter() {
+            assert!((row.iter().sum::<f32>() - 1.0).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn test_step_settles_at_level() {
+        let mut resampler = Resampler::new(1_789_773, 48_000);
+        let samples = run(&mut resampler, std::iter::repeat_n(0.5, 1_789_773 / 10));
+        assert!((4799..=4800).contains(&samples.len()));
+        assert!(
+            samples[..TAPS / 2 - 1]
+                .iter()
+                .all(|&sample| sample.abs() < 0.1)
+        );
+        assert!((samples.last().unwrap() - 0.5).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_rate_adjustment() {
+        let mut resampler = Resampler::new(1_000_000, 50_000);
+        resampler.set_rate_adjustment(1.01);
+        let samples = run(&mut resampler, std::iter::repeat_n(0.0, 1_000_000));
+        assert!((samples.len() as i32 - 50_500).abs() <= 1);
+    }
+
+    #[test]
+    fn test_ultrasonic_square_is_filtered() {
+        // A 30 kHz square wave would alias down to 18 kHz at 48 kHz if it were
+        // point sampled, band-limited it averages out to its midpoint
+        let clock_rate = 1_920_000;
+        let mut resampler = Resampler::new(clock_rate, 48_000);
+        let half_period = clock_rate as usize / 30_000 / 2;
+        let levels = (0..clock_rate as usize / 10).map(|n| ((n / half_period) % 2) as f32);
+        let samples = run(&mut resampler, levels);
+        let settled = &samples[TAPS..];
+        assert!(settled.iter().all(|&sample| (sample - 0.5).abs() < 0.2));
+    }
+}
@@ -0,0 +1,97 @@
+/// A fixed capacity FIFO of samples between the emulator and the frontend.
+/// When nobody drains it the oldest samples are overwritten, so a stalled
+/// frontend hears a skip instead of the emulator growing without bound
+#[derive(Debug, Clone, PartialEq)]
+pub struct RingBuffer {
+    data: Box<[f32]>,
+    start: usize,
+    len: usize,
+    /// Samples dropped because the buffer was full
+    overruns: u64,
+}
+
+impl RingBuffer {
+    pub fn with_capacity(capacity: usize) -> Self {
+        assert!(capacity > 0, "a ring buffer needs room for one sample");
+        Self {
+            data: vec![0.0; capacity].into_boxed_slice(),
+            start: 0,
+            len: 0,
+            overruns: 0,
+        }
+    }
+
+    pub fn push(&mut self, sample: f32) {
+        let end = (self.start + self.len) % self.data.len();
+        self.data[end] = sample;
+        if self.len == self.data.len() {
+            self.start = (self.start + 1) % self.data.len();
+            self.overruns += 1;
+        } else {
+            self.len += 1;
+        }
+    }
+
+    pub fn pop(&mut self) -> Option<f32> {
+        if self.len == 0 {
+            return None;
+        }
+        let sample = self.data[self.start];
+        self.start = (self.start + 1) % self.data.len();
+        self.len -= 1;
+        Some(sample)
+    }
+
+    /// Moves the oldest samples into `out`, returning how many there were
+    pub fn drain_into(&mut self, out: &mut [f32]) -> usize {
+        let count = out.len().min(self.len);
+        for sample in &mut out[..count] {
+            *sample = self.pop().unwrap();
+        }
+        count
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn overruns(&self) -> u64 {
+        self.overruns
+    }
+
+    pub fn clear(&mut self) {
+        self.start = 0;
+        self.len = 0;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_wraps_and_overwrites_oldest() {
+        let mut ring = RingBuffer::with_capacity(3);
+        for sample in [1.0, 2.0, 3.0, 4.0] {
+            ring.push(sample);
+        }
+        assert_eq!(ring.len(), 3);
+        assert_eq!(ring.overruns(), 1);
+
+        let mut out = [0.0; 2];
+        assert_eq!(ring.drain_into(&mut out), 2);
+        assert_eq!(out, [2.0, 3.0]);
+        ring.push(5.0);
+        assert_eq!(ring.drain_into(&mut out), 2);
+        assert_eq!(out, [4.0, 5.0]);
+        assert_eq!(ring.pop(), None);
+    }
+}
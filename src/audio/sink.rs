@@ -0,0 +1,120 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Seek, Write},
+    path::Path,
+};
+
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum AudioError {
+    #[error("WAV error: {0}")]
+    Wav(#[from] hound::Error),
+    #[error("audio device error: {0}")]
+    Device(String),
+}
+
+/// Where the PCM samples of the emulator end up, mono f32 in -1.0-1.0
+pub trait AudioSink {
+    fn write(&mut self, samples: &[f32]) -> Result<(), AudioError>;
+
+    /// Samples queued and not played yet, for sinks that play in real time
+    fn buffered(&self) -> Option<usize> {
+        None
+    }
+
+    /// Rate the sink plays at when it could not take the one asked for, the
+    /// samples written have to be produced at it
+    fn sample_rate(&self) -> Option<u32> {
+        None
+    }
+
+    /// Flushes whatever the sink holds, it takes no samples afterwards
+    fn finish(&mut self) -> Result<(), AudioError> {
+        Ok(())
+    }
+}
+
+/// Throws the samples away, for headless runs
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NullSink;
+
+impl AudioSink for NullSink {
+    fn write(&mut self, _samples: &[f32]) -> Result<(), AudioError> {
+        Ok(())
+    }
+}
+
+/// Records the samples to a 16-bit mono WAV file
+pub struct WavSink<W: Write + Seek = BufWriter<File>> {
+    writer: Option<hound::WavWriter<W>>,
+}
+
+impl WavSink {
+    pub fn create(path: impl AsRef<Path>, sample_rate: u32) -> Result<Self, AudioError> {
+        Ok(Self {
+            writer: Some(hound::WavWriter::create(path, Self::spec(sample_rate))?),
+        })
+    }
+}
+
+impl<W: Write + Seek> WavSink<W> {
+    pub fn new(writer: W, sample_rate: u32) -> Result<Self, AudioError> {
+        Ok(Self {
+            writer: Some(hound::WavWriter::new(writer, Self::spec(sample_rate))?),
+        })
+    }
+
+    fn spec(sample_rate: u32) -> hound::WavSpec {
+        hound::WavSpec {
+            channels: 1,
+            sample_rate,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        }
+    }
+}
+
+impl<W: Write + Seek> AudioSink for WavSink<W> {
+    fn write(&mut self, samples: &[f32]) -> Result<(), AudioError> {
+        let Some(writer) = &mut self.writer else {
+            return Ok(());
+        };
+        for &sample in samples {
+            writer.write_sample((sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16)?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), AudioError> {
+        match self.writer.take() {
+            Some(writer) => Ok(writer.finalize()?),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn test_wav_round_trip() {
+        let mut data = Cursor::new(Vec::new());
+        {
+            let mut sink = WavSink::new(&mut data, 44_100).unwrap();
+            sink.write(&[0.0, 0.5, -1.0, 2.0]).unwrap();
+            sink.finish().unwrap();
+            // Finished sinks ignore anything written later
+            sink.write(&[0.25]).unwrap();
+        }
+
+        data.set_position(0);
+        let mut reader = hound::WavReader::new(data).unwrap();
+        assert_eq!(reader.spec().sample_rate, 44_100);
+        let samples: Vec<i16> = reader.samples().map(Result::unwrap).collect();
+        assert_eq!(samples, [0, 16383, -32767, 32767]);
+    }
+}
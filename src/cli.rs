@@ -0,0 +1,533 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    thread,
+    time::Duration,
+};
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use nes_rs::{
+    apu::{Channel, mixer::Mixer},
+    audio::{
+        DEFAULT_SAMPLE_RATE,
+        sink::{AudioError, AudioSink, NullSink, WavSink},
+    },
+    config::{Config, Enhancements, PowerOnState},
+    cpu::CPU,
+    image::Image,
+    mapper::MapperError,
+    nes::{
+        NesRom, Region,
+        nsf::{Nsf, NsfError, player::NsfPlayer},
+    },
+    ppu::{
+        debug, frame,
+        ntsc::{NTSC_WIDTH, NtscFilter, NtscPreset},
+        palette::{Palette, PaletteError},
+    },
+};
+use thiserror::Error;
+use tracing::{info, warn};
+use winit::error::EventLoopError;
+
+/// Audio the output device buffer aims to hold, in milliseconds
+const AUDIO_LATENCY_MILLIS: usize = 50;
+/// Length of NSF tracks whose file does not give one
+const DEFAULT_TRACK_MILLIS: u64 = 150_000;
+/// Fade out at the end of NSF tracks whose file does not give one
+const DEFAULT_FADE_MILLIS: u64 = 8_000;
+
+/// What stops a command, reported before exiting with a failure status
+#[derive(Debug, Error)]
+pub enum CliError {
+    #[error("{}: {}", .0.display(), .1)]
+    Read(PathBuf, io::Error),
+    #[error("{}: not an iNES or NES 2.0 ROM this emulator can read", .0.display())]
+    BadRom(PathBuf),
+    #[error("{}: {}", .0.display(), .1)]
+    Nsf(PathBuf, NsfError),
+    #[error("{}: {}", .0.display(), .1)]
+    Palette(PathBuf, PaletteError),
+    #[error(transparent)]
+    Mapper(#[from] MapperError),
+    #[error(transparent)]
+    Audio(#[from] AudioError),
+    #[error("PNG error: {0}")]
+    Png(#[from] png::EncodingError),
+    #[error("window error: {0}")]
+    Window(#[from] EventLoopError),
+}
+
+/// Contents of `path`, with the path in the error
+fn read(path: &Path) -> Result<Vec<u8>, CliError> {
+    fs::read(path).map_err(|err| CliError::Read(path.to_owned(), err))
+}
+
+#[derive(Debug, Parser)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Play a ROM in a window
+    Run(RunArgs),
+    /// Run a ROM headless and save the picture and PPU debug views of one
+    /// frame as PNGs
+    DumpPpu(DumpPpuArgs),
+    /// Play a track of an NSF or NSFe music file, or render it to a WAV
+    PlayNsf(PlayNsfArgs),
+}
+
+/// What every command needs to build the console
+#[derive(Debug, Args)]
+pub struct MachineArgs {
+    pub rom: PathBuf,
+    /// Contents of RAM at power-on
+    #[arg(long, value_enum, default_value_t)]
+    pub power_on: PowerOnState,
+    /// Seed for every source of randomness, picked at random when omitted
+    #[arg(long)]
+    pub seed: Option<u64>,
+    /// Timing to run with, for ROMs whose header has the wrong region
+    #[arg(long, value_enum)]
+    pub region: Option<Region>,
+    /// Draw every sprite of a scanline instead of the first eight, which
+    /// removes flicker but is not accurate
+    #[arg(long)]
+    pub unlimited_sprites: bool,
+    /// Extra vblank scanlines where only the CPU runs, reduces slowdown but
+    /// is not accurate
+    #[arg(long, default_value_t = 0)]
+    pub overclock_scanlines: u16,
+    /// Rate of the audio output in Hz, usually 44100 or 48000
+    #[arg(long, default_value_t = DEFAULT_SAMPLE_RATE, value_parser = clap::value_parser!(u32).range(8000..=192_000))]
+    pub sample_rate: u32,
+    #[command(flatten)]
+    pub mix: MixArgs,
+    /// A 192 or 1536 byte .pal file to use instead of the built-in palette
+    #[arg(long)]
+    pub palette: Option<PathBuf>,
+    /// Palette the pattern table view is drawn with, 0-3 for the background
+    /// and 4-7 for sprites
+    #[arg(long, default_value_t = 0, value_parser = clap::value_parser!(u8).range(0..8))]
+    pub pattern_palette: u8,
+}
+
+impl MachineArgs {
+    pub fn cpu(&self) -> Result<CPU, CliError> {
+        let rom_bytes = read(&self.rom)?;
+        let (_, rom) = NesRom::parse(&rom_bytes).map_err(|_| CliError::BadRom(self.rom.clone()))?;
+        let config = Config {
+            power_on: self.power_on,
+            seed: self.seed(),
+            region: self.region,
+            enhancements: Enhancements {
+                unlimited_sprites: self.unlimited_sprites,
+                overclock_scanlines: self.overclock_scanlines,
+            },
+            sample_rate: Some(self.sample_rate),
+        };
+        let mut cpu = CPU::with_config(rom, &config)?;
+        cpu.reset();
+        self.mix.apply(cpu.apu_mut().mixer_mut());
+        Ok(cpu)
+    }
+
+    /// The seed given, or a random one that is printed so the run can be
+    /// repeated
+    fn seed(&self) -> u64 {
+        self.seed.unwrap_or_else(|| {
+            let seed = rand::random();
+            eprintln!("Seed {seed}, pass --seed {seed} to repeat this run");
+            seed
+        })
+    }
+
+    pub fn palette(&self) -> Result<Palette, CliError> {
+        match &self.palette {
+            Some(path) => Palette::load(path).map_err(|err| CliError::Palette(path.clone(), err)),
+            None => Ok(Palette::default()),
+        }
+    }
+}
+
+/// Per-channel volume settings
+#[derive(Debug, Args)]
+pub struct MixArgs {
+    /// Silence a sound channel, can be repeated
+    #[arg(long, value_enum)]
+    pub mute: Vec<Channel>,
+    /// Only play the soloed sound channels, can be repeated
+    #[arg(long, value_enum)]
+    pub solo: Vec<Channel>,
+    /// Volume of a sound channel as CHANNEL=GAIN, 1.0 being unchanged, can be
+    /// repeated
+    #[arg(long, value_parser = parse_gain)]
+    pub gain: Vec<(Channel, f32)>,
+}
+
+impl MixArgs {
+    pub fn apply(&self, mixer: &mut Mixer) {
+        for &channel in &self.mute {
+            mixer.set_muted(channel, true);
+        }
+        for &channel in &self.solo {
+            mixer.set_solo(channel, true);
+        }
+        for &(channel, gain) in &self.gain {
+            mixer.set_gain(channel, gain);
+        }
+    }
+}
+
+fn parse_gain(arg: &str) -> Result<(Channel, f32), String> {
+    let (channel, gain) = arg
+        .split_once('=')
+        .ok_or_else(|| format!("expected CHANNEL=GAIN, got {arg}"))?;
+    let channel = Channel::from_str(channel, true)?;
+    let gain = gain
+        .parse()
+        .map_err(|err| format!("bad gain {gain}: {err}"))?;
+    Ok((channel, gain))
+}
+
+#[derive(Debug, Args)]
+pub struct RunArgs {
+    #[command(flatten)]
+    pub machine: MachineArgs,
+    /// Filter screenshots through an emulated NTSC signal of this kind
+    #[arg(long, value_enum)]
+    pub ntsc: Option<NtscPreset>,
+    /// Write the audio to this WAV file instead of playing it
+    #[arg(long)]
+    pub record_audio: Option<PathBuf>,
+}
+
+impl RunArgs {
+    /// Size of the picture shown, the NTSC filter widens it and its lines
+    /// are drawn twice to keep the aspect ratio
+    pub fn screen_size(&self) -> (u32, u32) {
+        match self.ntsc {
+            Some(_) => (NTSC_WIDTH as u32, frame::HEIGHT as u32 * 2),
+            None => (frame::WIDTH as u32, frame::HEIGHT as u32),
+        }
+    }
+
+    /// The WAV recorder when asked for, or the sound card when built with
+    /// the cpal feature
+    pub fn audio_sink(&self) -> Result<Box<dyn AudioSink>, CliError> {
+        audio_sink(self.record_audio.as_deref(), self.machine.sample_rate)
+    }
+}
+
+/// Samples the output device buffer is kept at by rate control
+pub fn audio_target(sample_rate: u32) -> usize {
+    sample_rate as usize * AUDIO_LATENCY_MILLIS / 1000
+}
+
+/// A WAV recorder writing to `record` when given, or else the sound card
+/// when built with the cpal feature, which may pick another sample rate
+fn audio_sink(record: Option<&Path>, sample_rate: u32) -> Result<Box<dyn AudioSink>, CliError> {
+    if let Some(path) = record {
+        return Ok(Box::new(WavSink::create(path, sample_rate)?));
+    }
+    #[cfg(feature = "cpal")]
+    {
+        use nes_rs::audio::cpal_sink::CpalSink;
+        match CpalSink::new(sample_rate, AUDIO_LATENCY_MILLIS * 4) {
+            Ok(sink) => return Ok(Box::new(sink)),
+            Err(err) => warn!("No sound: {}", err),
+        }
+    }
+    Ok(Box::new(NullSink))
+}
+
+#[derive(Debug, Args)]
+pub struct DumpPpuArgs {
+    #[command(flatten)]
+    pub machine: MachineArgs,
+    /// Frame to stop at, counted from power on
+    #[arg(long)]
+    pub frame: u64,
+    /// Directory the PNGs are written to
+    #[arg(long, default_value = ".")]
+    pub out: PathBuf,
+    /// Filter the picture through an emulated NTSC signal of this kind
+    #[arg(long, value_enum)]
+    pub ntsc: Option<NtscPreset>,
+}
+
+pub fn dump_ppu(args: &DumpPpuArgs) -> Result<(), CliError> {
+    let mut cpu = args.machine.cpu()?;
+    if !cpu.run_frames(args.frame) {
+        info!(
+            "Program stopped at frame {}, dumping it",
+            cpu.ppu().frame_count()
+        );
+    }
+    let ppu = cpu.ppu();
+    let palette = args.machine.palette()?;
+    let name = format!("frame-{}", ppu.frame_count());
+    let screen = match args.ntsc {
+        Some(preset) => NtscFilter::new(preset.params()).apply(ppu.frame(), ppu.frame_count()),
+        None => Image::from_frame(ppu.frame(), &palette),
+    };
+    let screen_path = args.out.join(format!("{name}-screen.png"));
+    screen.save_png(&screen_path)?;
+    let views = debug::save_all(
+        ppu,
+        &palette,
+        args.machine.pattern_palette,
+        &args.out,
+        &name,
+    )?;
+    for path in [screen_path].into_iter().chain(views) {
+        println!("{}", path.display());
+    }
+    Ok(())
+}
+
+#[derive(Debug, Args)]
+pub struct PlayNsfArgs {
+    pub file: PathBuf,
+    /// Track to play, counted from 1 and in playlist order when the file
+    /// has a playlist, the file's starting track when omitted
+    #[arg(long, value_parser = clap::value_parser!(u8).range(1..))]
+    pub track: Option<u8>,
+    /// Render the track to this WAV file as fast as possible instead of
+    /// playing it
+    #[arg(long)]
+    pub wav: Option<PathBuf>,
+    /// Seconds to play before fading out, the length in the file when it
+    /// has one or 150 otherwise
+    #[arg(long)]
+    pub seconds: Option<u64>,
+    /// Timing to play with instead of the one the file asks for
+    #[arg(long, value_enum)]
+    pub region: Option<Region>,
+    /// Rate of the audio output in Hz, usually 44100 or 48000
+    #[arg(long, default_value_t = DEFAULT_SAMPLE_RATE, value_parser = clap::value_parser!(u32).range(8000..=192_000))]
+    pub sample_rate: u32,
+    #[command(flatten)]
+    pub mix: MixArgs,
+}
+
+pub fn play_nsf(args: &PlayNsfArgs) -> Result<(), CliError> {
+    let nsf_error = |err| CliError::Nsf(args.file.clone(), err);
+    let nsf = Nsf::parse(&read(&args.file)?).map_err(nsf_error)?;
+    let config = Config {
+        region: args.region,
+        sample_rate: Some(args.sample_rate),
+        ..Config::default()
+    };
+    let mut player = NsfPlayer::new(nsf, &config);
+    args.mix.apply(player.apu_mut().mixer_mut());
+    let mut sink = audio_sink(args.wav.as_deref(), args.sample_rate)?;
+    let sample_rate = sink.sample_rate().unwrap_or(args.sample_rate);
+    if sample_rate != args.sample_rate {
+        player.apu_mut().set_sample_rate(sample_rate);
+    }
+    let track = player.nsf().track(args.track).map_err(nsf_error)?;
+    player.start_track(track).map_err(nsf_error)?;
+
+    let nsf = player.nsf();
+    let info = &nsf.tracks[track as usize];
+    println!("{} - {} ({})", nsf.title, nsf.artist, nsf.copyright);
+    println!(
+        "Track {}/{}{}",
+        track + 1,
+        nsf.total_songs,
+        info.title
+            .as_ref()
+            .map(|title| format!(": {title}"))
+            .unwrap_or_default()
+    );
+    let length_ms = args
+        .seconds
+        .map(|seconds| seconds * 1000)
+        .or(info.duration_ms.map(u64::from))
+        .unwrap_or(DEFAULT_TRACK_MILLIS);
+    let fade_ms = info.fade_ms.map_or(DEFAULT_FADE_MILLIS, u64::from);
+
+    let target = audio_target(sample_rate);
+    // Samples up to the end of the fade, the last frame is cut there
+    let total = ((length_ms + fade_ms) * sample_rate as u64).div_ceil(1000);
+    let mut buffer = Vec::new();
+    let mut written = 0u64;
+    while written < total {
+        if !player.run_frame() {
+            warn!("PLAY did not return, stopping");
+            break;
+        }
+        let samples = player.apu_mut().samples();
+        buffer.resize(samples.len(), 0.0);
+        samples.drain_into(&mut buffer);
+        buffer.truncate((total - written).try_into().unwrap_or(usize::MAX));
+        for sample in &mut buffer {
+            let ms = written * 1000 / sample_rate as u64;
+            *sample *= fade_gain(ms, length_ms, fade_ms);
+            written += 1;
+        }
+        sink.write(&buffer)?;
+        // Real-time sinks are fed as fast as they play
+        while sink.buffered().is_some_and(|buffered| buffered > target) {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+    sink.finish()?;
+    Ok(())
+}
+
+/// Volume `ms` into a track that fades out over `fade_ms` after `length_ms`
+fn fade_gain(ms: u64, length_ms: u64, fade_ms: u64) -> f32 {
+    if ms <= length_ms {
+        1.0
+    } else if fade_ms == 0 {
+        0.0
+    } else {
+        (1.0 - (ms - length_ms) as f32 / fade_ms as f32).max(0.0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Parses `args` after the program name
+    fn command(args: &[&str]) -> Command {
+        Cli::parse_from(["nes-rs"].iter().chain(args)).command
+    }
+
+    /// Width and height of a saved PNG
+    fn png_size(path: &Path) -> (u32, u32) {
+        let decoder = png::Decoder::new(fs::File::open(path).unwrap());
+        let info = decoder.read_info().unwrap().info().clone();
+        (info.width, info.height)
+    }
+
+    /// An NSFe whose one track plays a steady square wave for 100 ms, then
+    /// fades out over `fade_ms`
+    fn tone_nsfe(fade_ms: i32) -> Vec<u8> {
+        let chunk = |id: &[u8; 4], data: &[u8]| {
+            let mut chunk = (data.len() as u32).to_le_bytes().to_vec();
+            chunk.extend(id);
+            chunk.extend(data);
+            chunk
+        };
+        #[rustfmt::skip]
+        let program = [
+            // INIT: pulse 1 at constant volume 15 and length halted, ~440 Hz
+            0xA9, 0x3F, 0x8D, 0x00, 0x40,
+            0xA9, 0xFD, 0x8D, 0x02, 0x40,
+            0xA9, 0x00, 0x8D, 0x03, 0x40,
+            // PLAY at $8010
+            0x60, 0x60,
+        ];
+        let mut file = b"NSFE".to_vec();
+        file.extend(chunk(
+            b"INFO",
+            &[0x00, 0x80, 0x00, 0x80, 0x10, 0x80, 0, 0, 1, 0],
+        ));
+        file.extend(chunk(b"DATA", &program));
+        file.extend(chunk(b"time", &100i32.to_le_bytes()));
+        file.extend(chunk(b"fade", &fade_ms.to_le_bytes()));
+        file.extend(chunk(b"NEND", &[]));
+        file
+    }
+
+    /// Renders the track of `nsfe` to a WAV and reads its samples back
+    fn render_nsf(nsfe: &[u8], name: &str) -> Vec<i16> {
+        let dir = std::env::temp_dir();
+        let id = std::process::id();
+        let file = dir.join(format!("nes-rs-{name}-{id}.nsfe"));
+        let wav = dir.join(format!("nes-rs-{name}-{id}.wav"));
+        fs::write(&file, nsfe).unwrap();
+        let Command::PlayNsf(args) = command(&[
+            "play-nsf",
+            file.to_str().unwrap(),
+            "--wav",
+            wav.to_str().unwrap(),
+        ]) else {
+            unreachable!()
+        };
+        play_nsf(&args).unwrap();
+        let samples = hound::WavReader::open(&wav)
+            .unwrap()
+            .samples()
+            .map(Result::unwrap)
+            .collect();
+        fs::remove_file(file).unwrap();
+        fs::remove_file(wav).unwrap();
+        samples
+    }
+
+    #[test]
+    fn test_fade_gain() {
+        assert_eq!(fade_gain(100, 100, 50), 1.0);
+        assert_eq!(fade_gain(125, 100, 50), 0.5);
+        assert_eq!(fade_gain(160, 100, 50), 0.0);
+        assert_eq!(fade_gain(100, 100, 0), 1.0);
+        assert_eq!(fade_gain(101, 100, 0), 0.0);
+    }
+
+    #[test]
+    fn test_play_nsf_without_fade() {
+        let samples = render_nsf(&tone_nsfe(0), "no-fade");
+        // 100 ms at 48 kHz, cut right at the end of the track
+        assert_eq!(samples.len(), 4800);
+        assert!(
+            samples
+                .iter()
+                .all(|sample| sample.unsigned_abs() < i16::MAX as u16 / 2)
+        );
+        assert!(samples[4000..].iter().any(|&sample| sample != 0));
+    }
+
+    #[test]
+    fn test_play_nsf_fade_tail() {
+        let samples = render_nsf(&tone_nsfe(50), "fade");
+        assert_eq!(samples.len(), 7200);
+        let peak = |range: std::ops::Range<usize>| {
+            samples[range]
+                .iter()
+                .map(|sample| sample.unsigned_abs())
+                .max()
+                .unwrap()
+        };
+        // Halfway through the fade the wave is about half as loud, and only
+        // gets quieter to the end
+        let full = peak(4000..4800);
+        let half = peak(5760..6240);
+        assert!(half > full / 3 && half < full * 2 / 3, "{half} of {full}");
+        assert!(peak(7000..7200) < full / 10);
+    }
+
+    #[test]
+    fn test_dump_ppu_screen() {
+        let out = std::env::temp_dir().join(format!("nes-rs-dump-{}", std::process::id()));
+        fs::create_dir_all(&out).unwrap();
+        let rom = concat!(env!("CARGO_MANIFEST_DIR"), "/test/nestest.nes");
+        let out_arg = out.to_str().unwrap();
+        let base = [
+            "dump-ppu", rom, "--seed", "0", "--frame", "2", "--out", out_arg,
+        ];
+
+        let Command::DumpPpu(args) = command(&base) else {
+            unreachable!()
+        };
+        dump_ppu(&args).unwrap();
+        assert_eq!(png_size(&out.join("frame-2-screen.png")), (256, 240));
+        assert!(out.join("frame-2-nametables.png").exists());
+
+        let Command::DumpPpu(args) = command(&[&base[..], &["--ntsc", "s-video"]].concat()) else {
+            unreachable!()
+        };
+        assert_eq!(args.ntsc, Some(NtscPreset::SVideo));
+        dump_ppu(&args).unwrap();
+        let size = png_size(&out.join("frame-2-screen.png"));
+        fs::remove_dir_all(&out).unwrap();
+        assert_eq!(size, (NTSC_WIDTH as u32, frame::HEIGHT as u32));
+    }
+}
@@ -0,0 +1,99 @@
+use clap::ValueEnum;
+use rand::{Rng, SeedableRng, rngs::StdRng};
+
+use crate::nes::Region;
+
+/// Contents of the console memories right after power-on.
+///
+/// Real hardware leaves RAM in an unpredictable state, and a handful of games
+/// read it before initializing it, so the policy is configurable.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum PowerOnState {
+    /// Every byte is $00
+    #[default]
+    Zeroed,
+    /// Every byte is $FF
+    Filled,
+    /// Four bytes of $00 followed by four bytes of $FF, repeated
+    Pattern,
+    /// Random bytes taken from the seeded emulator RNG
+    Random,
+}
+
+impl PowerOnState {
+    pub fn fill(&self, memory: &mut [u8], rng: &mut impl Rng) {
+        match self {
+            Self::Zeroed => memory.fill(0x00),
+            Self::Filled => memory.fill(0xFF),
+            Self::Pattern => {
+                for (i, byte) in memory.iter_mut().enumerate() {
+                    *byte = if i & 0b100 == 0 { 0x00 } else { 0xFF };
+                }
+            }
+            Self::Random => rng.fill(memory),
+        }
+    }
+}
+
+/// Deliberately inaccurate tweaks for play, all off by default so that
+/// accuracy tests never see them
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Enhancements {
+    /// Draws every sprite of a scanline instead of the first eight, which
+    /// removes flicker. The overflow flag still behaves as on hardware
+    pub unlimited_sprites: bool,
+    /// Scanlines added at the end of vblank where only the CPU runs, giving
+    /// games that slow down more time per frame
+    pub overclock_scanlines: u16,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Config {
+    pub power_on: PowerOnState,
+    /// Seed of the single RNG every source of randomness in the emulator draws from
+    pub seed: u64,
+    /// Timing to use instead of the one in the ROM header, which is
+    /// sometimes wrong
+    pub region: Option<Region>,
+    pub enhancements: Enhancements,
+    /// Rate of the PCM audio output, 48 kHz when not set
+    pub sample_rate: Option<u32>,
+}
+
+impl Config {
+    pub fn rng(&self) -> StdRng {
+        StdRng::seed_from_u64(self.seed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pattern_fill() {
+        let mut memory = [0x55; 12];
+        PowerOnState::Pattern.fill(&mut memory, &mut Config::default().rng());
+        assert_eq!(
+            memory,
+            [
+                0x00, 0x00, 0x00, 0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0x00, 0x00, 0x00, 0x00
+            ]
+        );
+    }
+
+    #[test]
+    fn test_random_fill_is_deterministic() {
+        let config = Config {
+            power_on: PowerOnState::Random,
+            seed: 0xC0FFEE,
+            ..Default::default()
+        };
+        let mut first = [0; 64];
+        let mut second = [0; 64];
+        config.power_on.fill(&mut first, &mut config.rng());
+        config.power_on.fill(&mut second, &mut config.rng());
+        assert_eq!(first, second);
+        assert_ne!(first, [0; 64]);
+    }
+}
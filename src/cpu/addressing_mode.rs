@@ -35,65 +35,96 @@ pub enum AddressingResult {
 }
 
 impl AddressingMode {
-    pub fn get_operand_address(&self, cpu: &CPU) -> AddressingResult {
-        AddressingResult::ReadAddress(match self {
-            AddressingMode::Implicit => return AddressingResult::ImplicitOperation,
-            AddressingMode::Accumulator => return AddressingResult::AccumulatorOperation,
+    pub fn get_operand_address(&self, cpu: &mut CPU) -> AddressingResult {
+        let (pc, x, y) = (cpu.program_counter, cpu.registers.x, cpu.registers.y);
+        let (result, page_crossed) = self.resolve(pc, x, y, |addr| cpu.mem_read(addr));
+        cpu.page_crossed = page_crossed;
+        result
+    }
+
+    /// Resolves the operand like [`AddressingMode::get_operand_address`], without
+    /// any bus side effects, for traces and debuggers
+    pub fn peek_operand_address(&self, cpu: &CPU) -> AddressingResult {
+        let (pc, x, y) = (cpu.program_counter, cpu.registers.x, cpu.registers.y);
+        self.resolve(pc, x, y, |addr| cpu.mem_peek(addr)).0
+    }
+
+    /// Also reports whether indexing crossed a page, which costs read instructions a cycle
+    fn resolve(
+        &self,
+        pc: u16,
+        x: u8,
+        y: u8,
+        mut read: impl FnMut(u16) -> u8,
+    ) -> (AddressingResult, bool) {
+        let page_crossed = |base: u16, addr: u16| base & 0xFF00 != addr & 0xFF00;
+
+        let addr = match self {
+            AddressingMode::Implicit => return (AddressingResult::ImplicitOperation, false),
+            AddressingMode::Accumulator => return (AddressingResult::AccumulatorOperation, false),
             AddressingMode::Relative => {
-                let offset = cpu.mem_read(cpu.program_counter);
-                return AddressingResult::RelativeOffset(offset as i8);
+                let offset = read(pc);
+                return (AddressingResult::RelativeOffset(offset as i8), false);
             }
 
-            AddressingMode::Immediate => cpu.program_counter,
+            AddressingMode::Immediate => pc,
 
-            AddressingMode::ZeroPage => cpu.mem_read(cpu.program_counter) as u16,
+            AddressingMode::ZeroPage => read(pc) as u16,
             AddressingMode::ZeroPage_X => {
-                let pos = cpu.mem_read(cpu.program_counter);
-                let addr = pos.wrapping_add(cpu.registers.x) as u16;
-                addr
+                let pos = read(pc);
+                pos.wrapping_add(x) as u16
             }
             AddressingMode::ZeroPage_Y => {
-                let pos = cpu.mem_read(cpu.program_counter);
-                let addr = pos.wrapping_add(cpu.registers.y) as u16;
-                addr
+                let pos = read(pc);
+                pos.wrapping_add(y) as u16
             }
 
-            AddressingMode::Absolute => cpu.mem_read_u16(cpu.program_counter),
+            AddressingMode::Absolute => u16::from_le_bytes([read(pc), read(pc + 1)]),
             AddressingMode::Absolute_X => {
-                let base = cpu.mem_read_u16(cpu.program_counter);
-                let addr = base.wrapping_add(cpu.registers.x as u16);
-                addr
+                let base = u16::from_le_bytes([read(pc), read(pc + 1)]);
+                let addr = base.wrapping_add(x as u16);
+                return (
+                    AddressingResult::ReadAddress(addr),
+                    page_crossed(base, addr),
+                );
             }
             AddressingMode::Absolute_Y => {
-                let base = cpu.mem_read_u16(cpu.program_counter);
-                let addr = base.wrapping_add(cpu.registers.y as u16);
-                addr
+                let base = u16::from_le_bytes([read(pc), read(pc + 1)]);
+                let addr = base.wrapping_add(y as u16);
+                return (
+                    AddressingResult::ReadAddress(addr),
+                    page_crossed(base, addr),
+                );
             }
 
             AddressingMode::Indirect => {
-                let ptr = cpu.mem_read(cpu.program_counter);
-                let lo = cpu.mem_read(ptr as u16);
-                let hi = cpu.mem_read(ptr.wrapping_add(1) as u16);
+                let ptr = read(pc);
+                let lo = read(ptr as u16);
+                let hi = read(ptr.wrapping_add(1) as u16);
                 u16::from_le_bytes([lo, hi])
             }
             AddressingMode::Indirect_X => {
-                let base = cpu.mem_read(cpu.program_counter);
+                let base = read(pc);
 
-                let ptr = base.wrapping_add(cpu.registers.x);
-                let lo = cpu.mem_read(ptr as u16);
-                let hi = cpu.mem_read(ptr.wrapping_add(1) as u16);
+                let ptr = base.wrapping_add(x);
+                let lo = read(ptr as u16);
+                let hi = read(ptr.wrapping_add(1) as u16);
                 u16::from_le_bytes([lo, hi])
             }
             AddressingMode::Indirect_Y => {
-                let base = cpu.mem_read(cpu.program_counter);
+                let base = read(pc);
 
-                let lo = cpu.mem_read(base as u16);
-                let hi = cpu.mem_read(base.wrapping_add(1) as u16);
+                let lo = read(base as u16);
+                let hi = read(base.wrapping_add(1) as u16);
                 let deref_base = u16::from_le_bytes([lo, hi]);
-                let deref = deref_base.wrapping_add(cpu.registers.y as u16);
-                deref
+                let deref = deref_base.wrapping_add(y as u16);
+                return (
+                    AddressingResult::ReadAddress(deref),
+                    page_crossed(deref_base, deref),
+                );
             }
-        })
+        };
+        (AddressingResult::ReadAddress(addr), false)
     }
 }
 
@@ -1,55 +1,223 @@
-use tracing::{error, warn};
+use std::cell::RefCell;
+use std::rc::Rc;
 
-use crate::nes::{Header, Mirroring, NesRom, PRG_ROM_PAGE_SIZE, Region, RomMapper};
+use rand::rngs::StdRng;
+use tracing::warn;
+
+use crate::apu::Apu;
+use crate::config::Config;
+use crate::mapper::{self, CPU_CARTRIDGE, CPU_CARTRIDGE_END, Mapper, MapperError, SharedMapper};
+use crate::nes::{Header, Mirroring, NesRom, Region, RomMapper};
+use crate::ppu::PPU;
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
 
 use crate::cpu::mem::Memory;
 
 pub struct Bus {
     cpu_vram: [u8; 2048],
-    rom: NesRom,
+    mapper: SharedMapper,
+    ppu: PPU,
+    apu: Apu,
+    cycles: usize,
+    rng: StdRng,
+    /// Last value driven on the CPU data bus, returned by reads nothing answers
+    open_bus: u8,
+    region: Region,
+    /// Fifths of a PPU dot carried over between CPU cycles on PAL
+    dot_remainder: u16,
 }
 
 impl Bus {
-    pub fn new(rom: NesRom) -> Self {
+    pub fn new(rom: NesRom) -> Result<Self, MapperError> {
+        Self::with_config(rom, &Config::default())
+    }
+
+    /// Fails when the board the ROM header asks for is not supported
+    pub fn with_config(rom: NesRom, config: &Config) -> Result<Self, MapperError> {
+        let region = config.region.unwrap_or(rom.header.region);
+        Ok(Self::with_mapper(mapper::from_rom(rom)?, region, config))
+    }
+
+    /// A console with `mapper` plugged in, running with the timing of `region`
+    pub fn with_mapper(mut mapper: Box<dyn Mapper>, region: Region, config: &Config) -> Self {
+        let mut rng = config.rng();
+
+        let mut cpu_vram = [0; 2048];
+        config.power_on.fill(&mut cpu_vram, &mut rng);
+        mapper.power_on(&config.power_on, &mut rng);
+        let mapper = Rc::new(RefCell::new(mapper));
+        let mut ppu = PPU::with_mapper(mapper.clone());
+        ppu.power_on(&config.power_on, &mut rng);
+        ppu.set_region(region);
+        ppu.set_enhancements(config.enhancements);
+        let mut apu = Apu::new(region);
+        if let Some(sample_rate) = config.sample_rate {
+            apu.set_sample_rate(sample_rate);
+        }
+
         Self {
-            cpu_vram: [0; 2048],
-            rom,
+            cpu_vram,
+            mapper,
+            ppu,
+            apu,
+            cycles: 0,
+            rng,
+            open_bus: 0,
+            region,
+            dot_remainder: 0,
         }
     }
+
+    pub fn rng(&mut self) -> &mut StdRng {
+        &mut self.rng
+    }
+
+    pub fn ppu(&self) -> &PPU {
+        &self.ppu
+    }
+
+    pub fn apu(&self) -> &Apu {
+        &self.apu
+    }
+
+    pub fn apu_mut(&mut self) -> &mut Apu {
+        &mut self.apu
+    }
+
+    /// Runs the rest of the system for a number of CPU cycles
+    pub fn tick(&mut self, cycles: u8) {
+        self.cycles += cycles as usize;
+        let (dots, per_cycles) = self.region.ppu_dots_per_cycle();
+        for _ in 0..cycles {
+            let dots = dots + self.dot_remainder;
+            self.dot_remainder = dots % per_cycles;
+            self.ppu.tick(dots / per_cycles);
+            let mut mapper = self.mapper.borrow_mut();
+            mapper.cpu_clock();
+            // Only the CPU runs on overclock lines, the rest of the console
+            // keeps the timing of a normal frame
+            if !self.ppu.overclocking() {
+                self.apu.tick();
+                mapper.timer_clock();
+            }
+        }
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    pub fn cycles(&self) -> usize {
+        self.cycles
+    }
+
+    pub fn poll_nmi(&mut self) -> bool {
+        self.ppu.poll_nmi()
+    }
+
+    /// Resets the parts of the system wired to the reset line
+    pub fn reset(&mut self) {
+        self.apu.reset();
+        self.mapper.borrow_mut().reset();
+        self.sync_mirroring();
+    }
+
+    /// Level of the shared IRQ line, the CPU services it unless masked
+    pub fn irq(&self) -> bool {
+        self.apu.irq() || self.mapper.borrow().irq()
+    }
 }
 
 impl Bus {
-    fn read_prg_rom(&self, addr: u16) -> u8 {
-        let mut addr = (addr - PRG_ROM) as usize;
-        let size = self.rom.header.len_prg_rom as usize * PRG_ROM_PAGE_SIZE;
-        if addr >= size {
-            addr %= size;
+    /// Hands the nametable layout the board selects to the PPU
+    fn sync_mirroring(&mut self) {
+        let mirroring = self.mapper.borrow().mirroring();
+        self.ppu.set_mirroring(mirroring);
+    }
+
+    /// Copies page `$XX00` into OAM, halting the CPU for 513 cycles, or 514
+    /// when the transfer has to wait for a read cycle first
+    fn oam_dma(&mut self, page: u8) {
+        let alignment = (self.cycles % 2) as u8;
+        self.tick(1 + alignment);
+        for low in 0..=0xFF {
+            let data = self.mem_read(u16::from_le_bytes([low, page]));
+            self.tick(1);
+            self.ppu.write_to_oam_data(data);
         }
-        self.rom.prg_rom[addr as usize]
+    }
+
+    /// Halts the CPU on its read of `addr` while the DMC fetches a sample
+    /// byte, taking 3 cycles or 4 when the fetch has to wait for a get cycle
+    fn dmc_dma(&mut self, addr: u16, sample_addr: u16) {
+        // The halted read repeats, so registers see it once more
+        self.tick(1);
+        if let PPU_REGISTERS..=PPU_REGISTERS_MIRRORS_END | APU_STATUS | JOYPAD_1 | JOYPAD_2 = addr {
+            self.read(addr);
+        }
+        self.tick(1 + (self.cycles % 2) as u8);
+        self.tick(1);
+        let data = self.read(sample_addr);
+        self.apu.dmc_dma_complete(data);
+    }
+
+    /// What a read of `addr` returns, with its side effects but no cycle
+    fn read(&mut self, addr: u16) -> u8 {
+        let data = match addr {
+            RAM..=RAM_MIRRORS_END => {
+                let mirror_down_addr = addr & 0b00000111_11111111;
+                self.cpu_vram[mirror_down_addr as usize]
+            }
+            PPU_REGISTERS..=PPU_REGISTERS_MIRRORS_END => {
+                let mirror_down_addr = addr & 0b00100000_00000111;
+                match mirror_down_addr {
+                    PPU_STATUS => self.ppu.read_status(),
+                    OAM_DATA => self.ppu.read_oam_data(),
+                    PPU_DATA => self.ppu.read_data(),
+                    _ => self.ppu.read_open_bus(),
+                }
+            }
+            APU_STATUS => {
+                // $4015 is internal to the CPU, so reading it leaves the data bus untouched
+                return self.apu.read_status() | self.open_bus & 0b0010_0000;
+            }
+            JOYPAD_1 | JOYPAD_2 => self.open_bus & 0b1110_0000,
+            CPU_CARTRIDGE..=CPU_CARTRIDGE_END => self
+                .mapper
+                .borrow_mut()
+                .cpu_read(addr)
+                .unwrap_or(self.open_bus),
+            _ => self.open_bus,
+        };
+        self.open_bus = data;
+        data
     }
 }
 
 impl Default for Bus {
     fn default() -> Self {
-        Self {
-            cpu_vram: [0; 2048],
-            rom: NesRom {
-                header: Header {
-                    len_prg_rom: 0x00,
-                    len_chr_rom: 0x00,
-                    rom_mapper: RomMapper::None,
-                    mirroring: Mirroring::Horizontal,
-                    vs_system: false,
-                    trainer: false,
-                    battery_backed_ram: false,
-                    len_prg_ram: 0x00,
-                    region: Region::NTSC,
-                },
-                trainer: None,
-                prg_rom: vec![],
-                chr_rom: vec![],
-            },
-        }
+        Self::new(empty_rom()).unwrap()
+    }
+}
+
+/// A cartridge with nothing on it, the CPU runs programs from RAM
+fn empty_rom() -> NesRom {
+    NesRom {
+        header: Header {
+            len_prg_rom: 0x00,
+            len_chr_rom: 0x00,
+            rom_mapper: RomMapper::None,
+            mirroring: Mirroring::Horizontal,
+            vs_system: false,
+            trainer: false,
+            battery_backed_ram: false,
+            len_prg_ram: 0x00,
+            region: Region::NTSC,
+            nes2: None,
+        },
+        trainer: None,
+        prg_rom: vec![],
+        chr_rom: vec![],
     }
 }
 
@@ -57,40 +225,79 @@ const RAM: u16 = 0x0000;
 const RAM_MIRRORS_END: u16 = 0x1FFF;
 const PPU_REGISTERS: u16 = 0x2000;
 const PPU_REGISTERS_MIRRORS_END: u16 = 0x3FFF;
-const PRG_ROM: u16 = 0x8000;
-const PRG_ROM_END: u16 = 0xFFFF;
+const PPU_CTRL: u16 = 0x2000;
+const PPU_MASK: u16 = 0x2001;
+const PPU_STATUS: u16 = 0x2002;
+const OAM_ADDR: u16 = 0x2003;
+const OAM_DATA: u16 = 0x2004;
+const PPU_SCROLL: u16 = 0x2005;
+const PPU_ADDR: u16 = 0x2006;
+const PPU_DATA: u16 = 0x2007;
+const APU_REGISTERS: u16 = 0x4000;
+const APU_REGISTERS_END: u16 = 0x4013;
+const OAM_DMA: u16 = 0x4014;
+const APU_STATUS: u16 = 0x4015;
+const JOYPAD_1: u16 = 0x4016;
+const JOYPAD_2: u16 = 0x4017;
+/// Shares its address with the second joypad, which only answers reads
+const APU_FRAME_COUNTER: u16 = 0x4017;
 
 impl Memory for Bus {
-    fn mem_read(&self, addr: u16) -> u8 {
+    fn mem_read(&mut self, addr: u16) -> u8 {
+        // The DMC only halts the CPU on read cycles
+        if let Some(sample_addr) = self.apu.dmc_dma_request() {
+            self.dmc_dma(addr, sample_addr);
+        }
+        // Each access takes a CPU cycle, the PPU catches up before seeing it
+        self.tick(1);
+        self.read(addr)
+    }
+
+    fn mem_peek(&self, addr: u16) -> u8 {
         match addr {
             RAM..=RAM_MIRRORS_END => {
                 let mirror_down_addr = addr & 0b00000111_11111111;
                 self.cpu_vram[mirror_down_addr as usize]
             }
-            PPU_REGISTERS..=PPU_REGISTERS_MIRRORS_END => {
-                let _mirror_down_addr = addr & 0b00100000_00000111;
-                todo!("PPU is not supported yet")
-            }
-            PRG_ROM..=PRG_ROM_END => self.read_prg_rom(addr),
-            _ => {
-                warn!("Ignoring mem access at {addr}, returning 0");
-                0
+            // Register reads have side effects, so only the I/O latch is visible
+            PPU_REGISTERS..=PPU_REGISTERS_MIRRORS_END => self.ppu.read_open_bus(),
+            CPU_CARTRIDGE..=CPU_CARTRIDGE_END => {
+                self.mapper.borrow().cpu_peek(addr).unwrap_or(self.open_bus)
             }
+            _ => self.open_bus,
         }
     }
 
     fn mem_write(&mut self, addr: u16, data: u8) {
+        self.tick(1);
+        self.open_bus = data;
         match addr {
             RAM..=RAM_MIRRORS_END => {
                 let mirror_down_addr = addr & 0b00000111_11111111;
                 self.cpu_vram[mirror_down_addr as usize] = data
             }
             PPU_REGISTERS..=PPU_REGISTERS_MIRRORS_END => {
-                let _mirror_down_addr = addr & 0b00100000_00000111;
-                todo!("PPU is not supported yet")
+                let mirror_down_addr = addr & 0b00100000_00000111;
+                match mirror_down_addr {
+                    PPU_CTRL => self.ppu.write_to_ctrl(data),
+                    PPU_MASK => self.ppu.write_to_mask(data),
+                    PPU_STATUS => warn!("Attempted to write to PPU status register"),
+                    OAM_ADDR => self.ppu.write_to_oam_addr(data),
+                    OAM_DATA => self.ppu.write_to_oam_data(data),
+                    PPU_SCROLL => self.ppu.write_to_scroll(data),
+                    PPU_ADDR => self.ppu.write_to_ppu_addr(data),
+                    PPU_DATA => self.ppu.write_to_data(data),
+                    _ => unreachable!(),
+                }
+            }
+            APU_REGISTERS..=APU_REGISTERS_END | APU_STATUS | APU_FRAME_COUNTER => {
+                self.apu.write_register(addr, data)
             }
-            PRG_ROM..=PRG_ROM_END => {
-                error!("Attempted to write to Cartridge Read-only Memory Space")
+            OAM_DMA => self.oam_dma(data),
+            CPU_CARTRIDGE..=CPU_CARTRIDGE_END => {
+                self.mapper.borrow_mut().cpu_write(addr, data);
+                self.apu.write_expansion(addr, data);
+                self.sync_mirroring();
             }
             _ => {
                 warn!("Ignoring mem write at {addr}, nothing done");
@@ -98,3 +305,217 @@ impl Memory for Bus {
         }
     }
 }
+
+impl Snapshot for Bus {
+    fn save(&self, state: &mut StateWriter) {
+        state.bytes(&self.cpu_vram);
+        state.u64(self.cycles as u64);
+        state.u8(self.open_bus);
+        state.u16(self.dot_remainder);
+        self.mapper.borrow().save(state);
+        self.ppu.save(state);
+        self.apu.save(state);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.bytes("RAM", &mut self.cpu_vram)?;
+        self.cycles = state.u64()? as usize;
+        self.open_bus = state.u8()?;
+        self.dot_remainder = state.u16()?;
+        self.mapper.borrow_mut().load(state)?;
+        self.ppu.load(state)?;
+        self.apu.load(state)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::{Enhancements, PowerOnState};
+
+    fn rom_with_prg_ram() -> NesRom {
+        let mut rom = empty_rom();
+        rom.header.len_prg_ram = 1;
+        rom
+    }
+
+    #[test]
+    fn test_power_on_state() {
+        let config = Config {
+            power_on: PowerOnState::Filled,
+            seed: 0,
+            ..Default::default()
+        };
+        let mut bus = Bus::with_config(rom_with_prg_ram(), &config).unwrap();
+        assert_eq!(bus.mem_read(0x0000), 0xFF);
+        assert_eq!(bus.mem_read(0x1FFF), 0xFF);
+        assert_eq!(bus.mem_read(0x6000), 0xFF);
+        assert_eq!(bus.mem_read(0x7FFF), 0xFF);
+    }
+
+    #[test]
+    fn test_seeded_power_on_is_reproducible() {
+        let config = Config {
+            power_on: PowerOnState::Random,
+            seed: 42,
+            ..Default::default()
+        };
+        let first = Bus::with_config(rom_with_prg_ram(), &config).unwrap();
+        let second = Bus::with_config(rom_with_prg_ram(), &config).unwrap();
+        assert_eq!(first.cpu_vram, second.cpu_vram);
+        assert_eq!(
+            first.mapper.borrow().cartridge().prg_ram,
+            second.mapper.borrow().cartridge().prg_ram
+        );
+    }
+
+    #[test]
+    fn test_unmapped_reads_return_open_bus() {
+        let mut bus = Bus::default();
+        bus.mem_write(0x0010, 0xA5);
+        assert_eq!(bus.mem_read(0x0010), 0xA5);
+        assert_eq!(bus.mem_read(0x4018), 0xA5);
+        assert_eq!(bus.mem_read(0x5000), 0xA5);
+        assert_eq!(bus.mem_read(0x6000), 0xA5);
+
+        bus.mem_write(0x4000, 0x3C);
+        assert_eq!(bus.mem_read(0x4000), 0x3C);
+    }
+
+    #[test]
+    fn test_joypad_and_apu_status_open_bus_bits() {
+        let mut bus = Bus::default();
+        bus.mem_write(0x0010, 0xFF);
+        bus.mem_read(0x0010);
+        assert_eq!(bus.mem_read(0x4016), 0b1110_0000);
+        assert_eq!(bus.mem_read(0x4015), 0b0010_0000);
+        // $4015 does not update the latch, the joypad read before it does
+        assert_eq!(bus.mem_read(0x4017), 0b1110_0000);
+    }
+
+    #[test]
+    fn test_ppu_registers_are_mirrored() {
+        let mut bus = Bus::default();
+        bus.mem_write(0x3FFE, 0x20);
+        bus.mem_write(0x2006, 0x10);
+        bus.mem_write(0x2FFF, 0xAB);
+        bus.mem_write(0x2006, 0x20);
+        bus.mem_write(0x2006, 0x10);
+        bus.mem_read(0x2007);
+        assert_eq!(bus.mem_read(0x3FF7), 0xAB);
+    }
+
+    #[test]
+    fn test_oam_dma_copies_page_and_stalls() {
+        let mut bus = Bus::default();
+        for i in 0..=0xFF {
+            bus.mem_write(0x0200 + i, i as u8);
+        }
+        bus.mem_write(0x2003, 0x00);
+
+        // The write itself lands on an even cycle, so the copy can start right away
+        let start = bus.cycles();
+        bus.mem_write(0x4014, 0x02);
+        assert_eq!((start + 1) % 2, 0);
+        assert_eq!(bus.cycles() - start, 1 + 513);
+        assert_eq!(bus.ppu().oam()[0x00], 0x00);
+        assert_eq!(bus.ppu().oam()[0x7F], 0x7F);
+
+        bus.mem_read(0x0000);
+        let start = bus.cycles();
+        bus.mem_write(0x4014, 0x02);
+        assert_eq!((start + 1) % 2, 1);
+        assert_eq!(bus.cycles() - start, 1 + 514);
+    }
+
+    #[test]
+    fn test_accesses_advance_ppu() {
+        let mut bus = Bus::default();
+        bus.mem_write(0x0000, 0x01);
+        bus.mem_read(0x0000);
+        assert_eq!(bus.cycles(), 2);
+        assert_eq!(bus.ppu().cycle(), 6);
+
+        // Peeking is free and leaves the registers alone
+        bus.mem_peek(0x2002);
+        assert_eq!(bus.cycles(), 2);
+    }
+
+    #[test]
+    fn test_region_override_and_pal_dot_ratio() {
+        let config = Config {
+            region: Some(Region::PAL),
+            ..Default::default()
+        };
+        let mut bus = Bus::with_config(empty_rom(), &config).unwrap();
+        assert_eq!(bus.region(), Region::PAL);
+        assert_eq!(bus.ppu().region(), Region::PAL);
+        bus.tick(1);
+        assert_eq!(bus.ppu().cycle(), 3);
+        // 3.2 dots per cycle, the fractions add up to a whole dot every 5 cycles
+        bus.tick(4);
+        assert_eq!(bus.ppu().cycle(), 16);
+    }
+
+    #[test]
+    fn test_dmc_dma_stalls_reads() {
+        let mut bus = Bus::default();
+        bus.mem_write(0x4013, 0x00);
+        bus.mem_write(0x4015, 0b0001_0000);
+
+        // Halt, dummy and alignment cycles, the fetch, then the read itself
+        let start = bus.cycles();
+        bus.mem_read(0x0000);
+        assert_eq!(bus.cycles() - start, 5);
+        // The buffer is full now, a one byte sample needs no more fetches
+        let start = bus.cycles();
+        bus.mem_read(0x0000);
+        assert_eq!(bus.cycles() - start, 1);
+    }
+
+    #[test]
+    fn test_dmc_dma_repeats_register_reads() {
+        let mut bus = Bus::default();
+        bus.mem_write(0x2006, 0x20);
+        bus.mem_write(0x2006, 0x00);
+        for data in 1..=3 {
+            bus.mem_write(0x2007, data);
+        }
+        bus.mem_write(0x2006, 0x20);
+        bus.mem_write(0x2006, 0x00);
+
+        bus.mem_write(0x4013, 0x00);
+        bus.mem_write(0x4015, 0b0001_0000);
+        // The halted read already moved the read buffer along
+        assert_eq!(bus.mem_read(0x2007), 1);
+        assert_eq!(bus.mem_read(0x2007), 2);
+    }
+
+    #[test]
+    fn test_overclock_scanlines_leave_apu_timing_alone() {
+        // CPU and APU cycles of the second frame
+        let frame_cycles = |overclock_scanlines| {
+            let config = Config {
+                enhancements: Enhancements {
+                    overclock_scanlines,
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            let mut bus = Bus::with_config(empty_rom(), &config).unwrap();
+            while bus.ppu().frame_count() < 1 {
+                bus.tick(1);
+            }
+            let start = (bus.cycles(), bus.apu().cycles());
+            while bus.ppu().frame_count() < 2 {
+                bus.tick(1);
+            }
+            (bus.cycles() - start.0, bus.apu().cycles() - start.1)
+        };
+        let (cycles, apu_cycles) = frame_cycles(0);
+        // 30 lines of 341 dots are a whole number of CPU cycles
+        let (overclocked, overclocked_apu) = frame_cycles(30);
+        assert_eq!(overclocked, cycles + 30 * 341 / 3);
+        assert_eq!(overclocked_apu, apu_cycles);
+    }
+}
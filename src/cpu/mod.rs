@@ -12,20 +12,39 @@ use mem::{Memory, STACK, STACK_RESET, Stack};
 use registers::Registers;
 use status::ProcessorStatus;
 
-use crate::nes::NesRom;
+use rand::rngs::StdRng;
+
+use crate::apu::Apu;
+use crate::config::Config;
+use crate::mapper::{Mapper, MapperError};
+use crate::nes::{NesRom, Region};
+use crate::ppu::PPU;
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
 
 use self::opcodes::{Instruction, OpCodeInfo};
 
+const NMI_VECTOR: u16 = 0xFFFA;
+const IRQ_VECTOR: u16 = 0xFFFE;
+/// Both interrupts take as long to service
+const INTERRUPT_CYCLES: u8 = 7;
+/// Where [`CPU::call`] makes subroutines return to, in the expansion area
+/// where no program code runs
+const CALL_RETURN: u16 = 0x5FF6;
+
 pub struct CPU {
     program_counter: u16,
     registers: Registers,
     status: ProcessorStatus,
     bus: Bus,
     stack_pointer: u8,
+    /// Set by the last operand lookup that indexed across a page boundary
+    page_crossed: bool,
+    /// Cycles the current instruction takes on top of its base count
+    extra_cycles: u8,
 }
 
 impl Memory for CPU {
-    fn mem_read_u16(&self, addr: u16) -> u16 {
+    fn mem_read_u16(&mut self, addr: u16) -> u16 {
         self.bus.mem_read_u16(addr)
     }
 
@@ -33,10 +52,14 @@ impl Memory for CPU {
         self.bus.mem_write_u16(addr, data)
     }
 
-    fn mem_read(&self, addr: u16) -> u8 {
+    fn mem_read(&mut self, addr: u16) -> u8 {
         self.bus.mem_read(addr)
     }
 
+    fn mem_peek(&self, addr: u16) -> u8 {
+        self.bus.mem_peek(addr)
+    }
+
     fn mem_write(&mut self, addr: u16, data: u8) {
         self.bus.mem_write(addr, data)
     }
@@ -54,25 +77,44 @@ impl Stack for CPU {
     }
 }
 
+impl Snapshot for CPU {
+    fn save(&self, state: &mut StateWriter) {
+        state.u16(self.program_counter);
+        state.u8(self.stack_pointer);
+        state.u8(self.registers.a);
+        state.u8(self.registers.x);
+        state.u8(self.registers.y);
+        state.u8(self.status.into());
+        self.bus.save(state);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.program_counter = state.u16()?;
+        self.stack_pointer = state.u8()?;
+        self.registers.a = state.u8()?;
+        self.registers.x = state.u8()?;
+        self.registers.y = state.u8()?;
+        self.status = state.u8()?.into();
+        self.bus.load(state)
+    }
+}
+
 impl CPU {
     pub fn new() -> Self {
-        CPU {
-            program_counter: 0,
-            stack_pointer: STACK_RESET,
-            registers: Registers::default(),
-            status: ProcessorStatus::default(),
-            bus: Bus::default(),
-        }
+        Self::with_bus(Bus::default())
     }
 
-    pub fn with_rom(rom: NesRom) -> Self {
-        CPU {
-            program_counter: 0,
-            stack_pointer: STACK_RESET,
-            registers: Registers::default(),
-            status: ProcessorStatus::default(),
-            bus: Bus::new(rom),
-        }
+    /// Fails when the board the ROM header asks for is not supported
+    pub fn with_rom(rom: NesRom) -> Result<Self, MapperError> {
+        Ok(Self::with_bus(Bus::new(rom)?))
+    }
+
+    pub fn with_config(rom: NesRom, config: &Config) -> Result<Self, MapperError> {
+        Ok(Self::with_bus(Bus::with_config(rom, config)?))
+    }
+
+    pub fn with_mapper(mapper: Box<dyn Mapper>, region: Region, config: &Config) -> Self {
+        Self::with_bus(Bus::with_mapper(mapper, region, config))
     }
 
     pub fn with_bus(bus: Bus) -> Self {
@@ -80,15 +122,62 @@ impl CPU {
             program_counter: 0,
             stack_pointer: STACK_RESET,
             registers: Registers::default(),
-            status: ProcessorStatus::default(),
+            // The 6502 powers on with interrupts disabled
+            status: ProcessorStatus {
+                interrupt_disable: true,
+                ..ProcessorStatus::default()
+            },
             bus,
+            page_crossed: false,
+            extra_cycles: 0,
         }
     }
 
+    /// The emulator-wide RNG, seeded from [`Config::seed`]
+    pub fn rng(&mut self) -> &mut StdRng {
+        self.bus.rng()
+    }
+
+    pub fn ppu(&self) -> &PPU {
+        self.bus.ppu()
+    }
+
+    pub fn apu(&self) -> &Apu {
+        self.bus.apu()
+    }
+
+    pub fn apu_mut(&mut self) -> &mut Apu {
+        self.bus.apu_mut()
+    }
+
+    /// CPU cycles since power on, including the ones spent halted for DMA
+    pub fn cycles(&self) -> usize {
+        self.bus.cycles()
+    }
+
+    /// Dumps the whole machine, to be restored with [`CPU::load_state`] on
+    /// one running the same cartridge
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::new();
+        self.save(&mut state);
+        state.finish()
+    }
+
+    /// Restores a state from [`CPU::save_state`], a state that fails to load
+    /// can leave the machine partially overwritten
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        self.load(&mut StateReader::new(data)?)
+    }
+
     pub fn reset(&mut self) {
         self.registers.reset();
         self.status.reset();
+        self.status.interrupt_disable = true;
         self.stack_pointer = STACK_RESET;
+        self.bus.reset();
+        // Like an interrupt without the writes, the vector is read on the
+        // last two of its 7 cycles
+        self.bus.tick(INTERRUPT_CYCLES - 2);
         self.program_counter = self.mem_read_u16(0xFFFC);
     }
 
@@ -117,36 +206,94 @@ impl CPU {
         self.run();
     }
 
-    pub fn load_rom(&mut self, rom: NesRom) {
-        self.bus = Bus::new(rom)
+    pub fn load_rom(&mut self, rom: NesRom) -> Result<(), MapperError> {
+        self.bus = Bus::new(rom)?;
+        Ok(())
     }
 
-    pub fn load_rom_and_run(&mut self, rom: NesRom) {
-        self.load_rom(rom);
+    pub fn load_rom_and_run(&mut self, rom: NesRom) -> Result<(), MapperError> {
+        self.load_rom(rom)?;
         self.reset();
         self.run();
+        Ok(())
     }
 
-    pub fn load_rom_modify_and_run<Func>(&mut self, rom: NesRom, modify: Func)
+    pub fn load_rom_modify_and_run<Func>(
+        &mut self,
+        rom: NesRom,
+        modify: Func,
+    ) -> Result<(), MapperError>
     where
         Func: Fn(&mut Self),
     {
-        self.load_rom(rom);
+        self.load_rom(rom)?;
         self.reset();
         modify(self);
         self.run();
+        Ok(())
     }
 
     pub fn run(&mut self) {
         self.run_with_callback(|_| {});
     }
 
+    /// Runs until the PPU has output `frames` frames since power on, returns
+    /// false if the program stopped first
+    pub fn run_frames(&mut self, frames: u64) -> bool {
+        while self.ppu().frame_count() < frames {
+            if self.tick() {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Runs the subroutine at `addr` with A and X set, as if called with a
+    /// JSR from outside the program, until it returns. Returns false when it
+    /// did not within `max_cycles`, or the program stopped
+    pub fn call(&mut self, addr: u16, a: u8, x: u8, max_cycles: usize) -> bool {
+        self.registers.a = a;
+        self.registers.x = x;
+        // Pushed the way jsr does, which RTS undoes
+        self.stack_push_u16(CALL_RETURN + 1);
+        self.program_counter = addr;
+        let start = self.cycles();
+        while self.program_counter != CALL_RETURN {
+            if self.cycles() - start > max_cycles || self.tick() {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Lets the rest of the system run for `cycles` while the CPU sits idle
+    pub fn idle(&mut self, cycles: usize) {
+        for _ in 0..cycles / u8::MAX as usize {
+            self.bus.tick(u8::MAX);
+        }
+        self.bus.tick((cycles % u8::MAX as usize) as u8);
+    }
+
     /// Returns whether to stop the app
     pub fn tick(&mut self) -> bool {
+        let start = self.bus.cycles();
+        self.extra_cycles = 0;
+        if self.bus.poll_nmi() {
+            self.interrupt(NMI_VECTOR);
+            self.finish_cycles(start, INTERRUPT_CYCLES);
+            return false;
+        }
+        if self.bus.irq() && !self.status.interrupt_disable {
+            self.interrupt(IRQ_VECTOR);
+            self.finish_cycles(start, INTERRUPT_CYCLES);
+            return false;
+        }
+
         let code = self.mem_read(self.program_counter);
         self.program_counter += 1;
 
         let instruction = Instruction::from_opcode(code);
+        let cycles = instruction.to_opcode_info().cycles;
 
         let advance = match instruction {
             Instruction::ADC(OpCodeInfo {
@@ -178,12 +325,7 @@ impl CPU {
                 bytes,
                 ..
             }) => {
-                if self.branch(
-                    !self.status.carry_flag,
-                    addressing_mode
-                        .get_operand_address(self)
-                        .unwrap_relative_offset(),
-                ) {
+                if self.branch(!self.status.carry_flag, addressing_mode) {
                     1
                 } else {
                     bytes
@@ -194,12 +336,7 @@ impl CPU {
                 bytes,
                 ..
             }) => {
-                if self.branch(
-                    self.status.carry_flag,
-                    addressing_mode
-                        .get_operand_address(self)
-                        .unwrap_relative_offset(),
-                ) {
+                if self.branch(self.status.carry_flag, addressing_mode) {
                     1
                 } else {
                     bytes
@@ -210,12 +347,7 @@ impl CPU {
                 bytes,
                 ..
             }) => {
-                if self.branch(
-                    self.status.zero_flag,
-                    addressing_mode
-                        .get_operand_address(self)
-                        .unwrap_relative_offset(),
-                ) {
+                if self.branch(self.status.zero_flag, addressing_mode) {
                     1
                 } else {
                     bytes
@@ -234,12 +366,7 @@ impl CPU {
                 bytes,
                 ..
             }) => {
-                if self.branch(
-                    self.status.negative_flag,
-                    addressing_mode
-                        .get_operand_address(self)
-                        .unwrap_relative_offset(),
-                ) {
+                if self.branch(self.status.negative_flag, addressing_mode) {
                     1
                 } else {
                     bytes
@@ -250,12 +377,7 @@ impl CPU {
                 bytes,
                 ..
             }) => {
-                if self.branch(
-                    !self.status.zero_flag,
-                    addressing_mode
-                        .get_operand_address(self)
-                        .unwrap_relative_offset(),
-                ) {
+                if self.branch(!self.status.zero_flag, addressing_mode) {
                     1
                 } else {
                     bytes
@@ -266,12 +388,7 @@ impl CPU {
                 bytes,
                 ..
             }) => {
-                if self.branch(
-                    !self.status.negative_flag,
-                    addressing_mode
-                        .get_operand_address(self)
-                        .unwrap_relative_offset(),
-                ) {
+                if self.branch(!self.status.negative_flag, addressing_mode) {
                     1
                 } else {
                     bytes
@@ -285,12 +402,7 @@ impl CPU {
                 bytes,
                 ..
             }) => {
-                if self.branch(
-                    !self.status.overflow_flag,
-                    addressing_mode
-                        .get_operand_address(self)
-                        .unwrap_relative_offset(),
-                ) {
+                if self.branch(!self.status.overflow_flag, addressing_mode) {
                     1
                 } else {
                     bytes
@@ -301,12 +413,7 @@ impl CPU {
                 bytes,
                 ..
             }) => {
-                if self.branch(
-                    self.status.overflow_flag,
-                    addressing_mode
-                        .get_operand_address(self)
-                        .unwrap_relative_offset(),
-                ) {
+                if self.branch(self.status.overflow_flag, addressing_mode) {
                     1
                 } else {
                     bytes
@@ -560,10 +667,27 @@ impl CPU {
         };
 
         self.program_counter += (advance - 1) as u16;
+        self.finish_cycles(start, cycles + self.extra_cycles);
 
         false
     }
 
+    /// Memory accesses already ticked the bus, this runs the internal cycles left
+    fn finish_cycles(&mut self, start: usize, cycles: u8) {
+        let elapsed = self.bus.cycles() - start;
+        if cycles as usize > elapsed {
+            self.bus.tick((cycles as usize - elapsed) as u8);
+        }
+    }
+
+    /// Pushes the return address and status, then jumps through `vector`
+    fn interrupt(&mut self, vector: u16) {
+        self.stack_push_u16(self.program_counter);
+        self.stack_push(self.status.into());
+        self.status.interrupt_disable = true;
+        self.program_counter = self.mem_read_u16(vector);
+    }
+
     pub fn run_with_callback(&mut self, mut callback: impl FnMut(&mut CPU)) {
         loop {
             callback(self);
@@ -574,8 +698,7 @@ impl CPU {
     }
 
     fn adc(&mut self, mode: &AddressingMode) {
-        let addr = mode.get_operand_address(self).unwrap_read_address();
-        let value = self.mem_read(addr);
+        let value = self.read_operand(mode);
         let (result, overflow) = self.registers.a.overflowing_add(value);
         let (result, overflow_carry) = result.overflowing_add(self.status.carry_flag as u8);
         self.status.overflow_flag = (value ^ result) & (result ^ self.registers.a) & 0x80 != 0;
@@ -584,8 +707,7 @@ impl CPU {
             .update_carry_zero_neg(self.registers.a, overflow || overflow_carry);
     }
     fn sbc(&mut self, mode: &AddressingMode) {
-        let addr = mode.get_operand_address(self).unwrap_read_address();
-        let value = self.mem_read(addr);
+        let value = self.read_operand(mode);
         let (result, overflow) = self
             .registers
             .a
@@ -598,8 +720,7 @@ impl CPU {
     }
 
     fn and(&mut self, mode: &AddressingMode) {
-        let addr = mode.get_operand_address(self).unwrap_read_address();
-        let value = self.mem_read(addr);
+        let value = self.read_operand(mode);
         self.registers.a = self.registers.a & value;
         self.status.update_zero_neg_flags(self.registers.a);
     }
@@ -613,7 +734,7 @@ impl CPU {
                 (result, overflow)
             }
             addressing_mode::AddressingResult::ReadAddress(addr) => {
-                let (result, overflow) = self.mem_read(addr).overflowing_shl(1);
+                let (result, overflow) = self.read_modify_write(addr).overflowing_shl(1);
                 self.mem_write(addr, result);
                 (result, overflow)
             }
@@ -633,7 +754,7 @@ impl CPU {
                 (old_bit7, new_bit7)
             }
             addressing_mode::AddressingResult::ReadAddress(addr) => {
-                let value = self.mem_read(addr);
+                let value = self.read_modify_write(addr);
                 let old_bit7 = value & 0b1000_0000 != 0;
                 let result = (value << 1) | self.status.carry_flag as u8;
                 self.mem_write(addr, result);
@@ -657,7 +778,7 @@ impl CPU {
                 (old_bit0, new_bit7)
             }
             addressing_mode::AddressingResult::ReadAddress(addr) => {
-                let value = self.mem_read(addr);
+                let value = self.read_modify_write(addr);
                 let old_bit0 = value & 1 != 0;
                 let result = (value >> 1) | ((self.status.carry_flag as u8) << 7);
                 self.mem_write(addr, result);
@@ -681,7 +802,7 @@ impl CPU {
                 (result, carry)
             }
             addressing_mode::AddressingResult::ReadAddress(addr) => {
-                let value = self.mem_read(addr);
+                let value = self.read_modify_write(addr);
                 let carry = value & 1 == 1;
                 let result = value >> 1;
                 self.mem_write(addr, result);
@@ -704,7 +825,7 @@ impl CPU {
 
     fn dec(&mut self, mode: &AddressingMode) {
         let addr = mode.get_operand_address(self).unwrap_read_address();
-        let value = self.mem_read(addr);
+        let value = self.read_modify_write(addr);
         let result = value.wrapping_sub(1);
         self.mem_write(addr, result);
         self.status.update_zero_neg_flags(result);
@@ -721,15 +842,14 @@ impl CPU {
     }
 
     fn eor(&mut self, mode: &AddressingMode) {
-        let addr = mode.get_operand_address(self).unwrap_read_address();
-        let value = self.mem_read(addr);
+        let value = self.read_operand(mode);
         self.registers.a = self.registers.a ^ value;
         self.status.update_zero_neg_flags(self.registers.a)
     }
 
     fn inc(&mut self, mode: &AddressingMode) {
         let addr = mode.get_operand_address(self).unwrap_read_address();
-        let value = self.mem_read(addr);
+        let value = self.read_modify_write(addr);
         let result = value.wrapping_add(1);
         self.mem_write(addr, result);
         self.status.update_zero_neg_flags(result);
@@ -770,30 +890,26 @@ impl CPU {
     }
 
     fn lda(&mut self, mode: &AddressingMode) {
-        let addr = mode.get_operand_address(self).unwrap_read_address();
-        let value = self.mem_read(addr);
+        let value = self.read_operand(mode);
 
         self.registers.a = value;
         self.status.update_zero_neg_flags(self.registers.a);
     }
     fn ldx(&mut self, mode: &AddressingMode) {
-        let addr = mode.get_operand_address(self).unwrap_read_address();
-        let value = self.mem_read(addr);
+        let value = self.read_operand(mode);
 
         self.registers.x = value;
         self.status.update_zero_neg_flags(self.registers.x);
     }
     fn ldy(&mut self, mode: &AddressingMode) {
-        let addr = mode.get_operand_address(self).unwrap_read_address();
-        let value = self.mem_read(addr);
+        let value = self.read_operand(mode);
 
         self.registers.y = value;
         self.status.update_zero_neg_flags(self.registers.y);
     }
 
     fn ora(&mut self, mode: &AddressingMode) {
-        let addr = mode.get_operand_address(self).unwrap_read_address();
-        let value = self.mem_read(addr);
+        let value = self.read_operand(mode);
         self.registers.a = self.registers.a | value;
         self.status.update_zero_neg_flags(self.registers.a);
     }
@@ -829,8 +945,7 @@ impl CPU {
     }
 
     fn cmp(&mut self, mode: &AddressingMode) {
-        let addr = mode.get_operand_address(self).unwrap_read_address();
-        let value = self.mem_read(addr);
+        let value = self.read_operand(mode);
         self.status
             .update_carry_zero_neg_cmp(self.registers.a, value);
     }
@@ -873,12 +988,29 @@ impl CPU {
         self.status.update_zero_neg_flags(self.registers.a)
     }
 
-    fn branch(&mut self, condition: bool, offset: i8) -> bool {
+    /// Reads the operand of an instruction that only loads it, those pay a cycle
+    /// when indexing crosses a page
+    fn read_operand(&mut self, mode: &AddressingMode) -> u8 {
+        let addr = mode.get_operand_address(self).unwrap_read_address();
+        self.extra_cycles += self.page_crossed as u8;
+        self.mem_read(addr)
+    }
+
+    /// Reads the operand of a read-modify-write instruction, which writes it
+    /// back unchanged on the cycle before the result
+    fn read_modify_write(&mut self, addr: u16) -> u8 {
+        let value = self.mem_read(addr);
+        self.mem_write(addr, value);
+        value
+    }
+
+    fn branch(&mut self, condition: bool, mode: &AddressingMode) -> bool {
+        let offset = mode.get_operand_address(self).unwrap_relative_offset();
         if condition {
-            self.program_counter = self
-                .program_counter
-                .wrapping_add(1)
-                .wrapping_add(offset as u16);
+            let next = self.program_counter.wrapping_add(1);
+            self.program_counter = next.wrapping_add(offset as u16);
+            // Taken branches cost a cycle, and another one when landing on a new page
+            self.extra_cycles += 1 + (next & 0xFF00 != self.program_counter & 0xFF00) as u8;
             true
         } else {
             false
@@ -889,6 +1021,9 @@ impl CPU {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::config::Enhancements;
+    use crate::mapper::test::rom;
+    use crate::nes::RomMapper;
 
     #[test]
     fn test_0xa9_lda_immediate_load_data() {
@@ -939,4 +1074,203 @@ mod test {
 
         assert_eq!(cpu.registers.a, 0x55);
     }
+
+    #[test]
+    fn test_page_crossing_read_costs_a_cycle() {
+        // LDA $00FF,X
+        let cycles = |x| {
+            let mut cpu = CPU::new();
+            cpu.load_ram_modify_and_run(&[0xbd, 0xff, 0x00, 0x00], |cpu| cpu.registers.x = x);
+            cpu.bus.cycles()
+        };
+        assert_eq!(cycles(1) - cycles(0), 1);
+    }
+
+    #[test]
+    fn test_taken_branch_costs_a_cycle() {
+        // BNE to the next instruction
+        let cycles = |zero| {
+            let mut cpu = CPU::new();
+            cpu.load_ram_modify_and_run(&[0xd0, 0x00, 0x00], |cpu| cpu.status.zero_flag = zero);
+            cpu.bus.cycles()
+        };
+        assert_eq!(cycles(false) - cycles(true), 1);
+    }
+
+    #[test]
+    fn test_oam_dma_stalls_cpu() {
+        // STA $4014 against STA $0014, with and without the 3 cycles of an
+        // LDA $00 before it to try both cycle parities
+        let cycles = |lda: &[u8], hi| {
+            let mut cpu = CPU::new();
+            cpu.load_ram_and_run(&[lda, &[0x8d, 0x14, hi, 0x00]].concat());
+            cpu.cycles()
+        };
+        let stall = |lda: &[u8]| cycles(lda, 0x40) - cycles(lda, 0x00);
+        assert_eq!(stall(&[0xa5, 0x00]), 513);
+        // Getting in step with the APU takes one more cycle
+        assert_eq!(stall(&[]), 514);
+    }
+
+    #[test]
+    fn test_nmi_pushes_state_and_jumps_to_vector() {
+        let mut cpu = CPU::with_rom(
+            NesRom::parse(include_bytes!("../../test/nestest.nes"))
+                .unwrap()
+                .1,
+        )
+        .unwrap();
+        cpu.program_counter = 0xC123;
+        cpu.status.carry_flag = true;
+        cpu.status.interrupt_disable = false;
+        let vector = cpu.mem_read_u16(NMI_VECTOR);
+
+        cpu.interrupt(NMI_VECTOR);
+        assert_eq!(cpu.program_counter, vector);
+        assert!(cpu.status.interrupt_disable);
+        assert_eq!(cpu.stack_pop(), 0b0010_0001);
+        assert_eq!(cpu.stack_pop_u16(), 0xC123);
+    }
+
+    #[test]
+    fn test_dmc_irq_unless_masked() {
+        let mut cpu = CPU::with_rom(
+            NesRom::parse(include_bytes!("../../test/nestest.nes"))
+                .unwrap()
+                .1,
+        )
+        .unwrap();
+        cpu.program_counter = 0xC000;
+        let vector = cpu.mem_read_u16(IRQ_VECTOR);
+        // IRQ enabled, a one byte sample
+        cpu.mem_write(0x4010, 0x80);
+        cpu.mem_write(0x4013, 0x00);
+        cpu.mem_write(0x4015, 0b0001_0000);
+
+        cpu.status.interrupt_disable = true;
+        cpu.tick();
+        assert!(cpu.bus.irq());
+        cpu.tick();
+        assert_ne!(cpu.program_counter, vector);
+
+        cpu.status.interrupt_disable = false;
+        cpu.tick();
+        assert_eq!(cpu.program_counter, vector);
+        assert!(cpu.status.interrupt_disable);
+    }
+
+    /// An MMC1 whose 16 KiB banks hold $FF, but for their number at $x001
+    fn numbered_mmc1_banks() -> NesRom {
+        let mut rom = rom(RomMapper::NintendoMMC1);
+        rom.prg_rom = vec![0xFF; 4 * 0x4000];
+        for bank in 0..4 {
+            rom.prg_rom[bank * 0x4000 + 1] = bank as u8;
+        }
+        rom
+    }
+
+    /// Switches to PRG bank 2 after an INC on the serial port, which only
+    /// works when the second write of the INC is ignored. Returns the bank
+    /// switched to
+    fn switch_bank_after_inc(cpu: &mut CPU) -> u8 {
+        #[rustfmt::skip]
+        let program = [
+            // Two bits into the serial port
+            0xa9, 0x01, 0x8d, 0x00, 0xe0, 0x8d, 0x00, 0xe0,
+            // INC of $FF resets it, the $00 written next cycle is ignored
+            0xee, 0x00, 0x80,
+            // PRG bank 2, one bit at a time
+            0xa9, 0x00, 0x8d, 0x00, 0xe0,
+            0xa9, 0x01, 0x8d, 0x00, 0xe0,
+            0xa9, 0x00, 0x8d, 0x00, 0xe0, 0x8d, 0x00, 0xe0, 0x8d, 0x00, 0xe0,
+            0x00,
+        ];
+        for (addr, &byte) in program.iter().enumerate() {
+            cpu.mem_write(addr as u16, byte);
+        }
+        cpu.program_counter = 0x0000;
+        cpu.run();
+        cpu.mem_peek(0x8001)
+    }
+
+    #[test]
+    fn test_reset_acts_like_an_early_4017_write() {
+        // Cycles from a $4017 write to the frame IRQ
+        let write_to_irq = |odd_cycle| {
+            let mut apu = Apu::new(Region::NTSC);
+            if odd_cycle {
+                apu.tick();
+            }
+            apu.write_register(0x4017, 0);
+            (1..).find(|_| {
+                apu.tick();
+                apu.irq()
+            })
+        };
+        let mut cpu = CPU::with_rom(rom(RomMapper::None)).unwrap();
+        while !cpu.bus.irq() {
+            cpu.bus.tick(1);
+        }
+        // Which the reset clears
+        cpu.reset();
+        assert!(!cpu.bus.irq());
+        let first_instruction = cpu.bus.cycles();
+        while !cpu.bus.irq() {
+            cpu.bus.tick(1);
+        }
+        let reset_to_irq = cpu.bus.cycles() - first_instruction;
+        // As if written 9 to 12 cycles before the first instruction
+        for odd_cycle in [false, true] {
+            let early = write_to_irq(odd_cycle).unwrap() - reset_to_irq;
+            assert!((9..=12).contains(&early), "{early}");
+        }
+    }
+
+    #[test]
+    fn test_read_modify_write_writes_back_first() {
+        let mut cpu = CPU::with_rom(numbered_mmc1_banks()).unwrap();
+        assert_eq!(switch_bank_after_inc(&mut cpu), 2);
+    }
+
+    #[test]
+    fn test_read_modify_write_on_overclock_lines() {
+        let config = Config {
+            enhancements: Enhancements {
+                overclock_scanlines: 30,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut cpu = CPU::with_config(numbered_mmc1_banks(), &config).unwrap();
+        while !cpu.bus.ppu().overclocking() {
+            cpu.bus.tick(1);
+        }
+        assert_eq!(switch_bank_after_inc(&mut cpu), 2);
+        assert!(cpu.bus.ppu().overclocking());
+    }
+
+    #[test]
+    fn test_save_state_round_trip() {
+        let rom = || {
+            NesRom::parse(include_bytes!("../../test/snake.nes"))
+                .unwrap()
+                .1
+        };
+        let mut cpu = CPU::with_rom(rom()).unwrap();
+        cpu.registers.a = 0x42;
+        cpu.mem_write(0x0010, 0x99);
+        // Pattern table write through $2006/$2007 into the 8 KiB of CHR RAM
+        cpu.mem_write(0x2006, 0x00);
+        cpu.mem_write(0x2006, 0x10);
+        cpu.mem_write(0x2007, 0x77);
+        let state = cpu.save_state();
+
+        let mut restored = CPU::with_rom(rom()).unwrap();
+        restored.load_state(&state).unwrap();
+        assert_eq!(restored.registers.a, 0x42);
+        assert_eq!(restored.mem_peek(0x0010), 0x99);
+        assert_eq!(restored.cycles(), cpu.cycles());
+        assert_eq!(restored.ppu().chr_ram().unwrap()[0x0010], 0x77);
+        assert_eq!(restored.save_state(), state);
+    }
 }
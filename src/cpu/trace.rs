@@ -1,38 +1,111 @@
-use std::fmt::format;
-
-use crate::cpu::{addressing_mode::AddressingResult, mem::Memory, opcodes::Instruction, status::ProcessorStatus};
+use crate::cpu::{
+    addressing_mode::AddressingMode, mem::Memory, opcodes::Instruction, status::ProcessorStatus,
+};
 
 use super::CPU;
 
-pub fn trace(cpu: &mut CPU) -> String {
-    let code = cpu.mem_read(cpu.program_counter);
+/// The instruction at the program counter and the registers before it runs,
+/// in the format of the nestest log
+pub fn trace(cpu: &CPU) -> String {
+    let pc = cpu.program_counter;
+    let code = cpu.mem_peek(pc);
     let instruction = Instruction::from_opcode(code);
-    let addressing_mode = instruction.to_opcode_info().addressing_mode.get_operand_address(cpu);
-
-    let arguments = match addressing_mode {
-        AddressingResult::ImplicitOperation | AddressingResult::AccumulatorOperation => format!("     "),
-        AddressingResult::ReadAddress(x) => format!("{x:04X}").as_bytes().chunks(2).map(std::str::from_utf8).collect::<Result<Vec<&str>, _>>().unwrap().join(" "),
-        AddressingResult::RelativeOffset(x) => format!("{:02X}   ", x as u8),
+    let info = instruction.to_opcode_info();
+    let operands: Vec<u8> = (1..info.bytes as u16)
+        .map(|offset| cpu.mem_peek(pc.wrapping_add(offset)))
+        .collect();
+
+    let bytes = std::iter::once(code)
+        .chain(operands.iter().copied())
+        .map(|byte| format!("{byte:02X}"))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let name = instruction.to_opcode_name();
+    let jump = matches!(instruction, Instruction::JMP(_) | Instruction::JSR(_));
+    let assembly = match operand(cpu, &info.addressing_mode, &operands, jump) {
+        operand if operand.is_empty() => name.to_string(),
+        operand => format!("{name} {operand}"),
     };
 
-    // TODO:
-    //   - Format memory access
-    //   - Discover what the fuck SP is supposed to mean
-    //   - Check if P is the processor status
-
     format!(
-        "{:04X}  {:02X} {}  {} #$01                        A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:FD",
-        cpu.program_counter,
-        code,
-        arguments,
-        instruction.to_opcode_name(),
+        "{:04X}  {:<8}  {:<32}A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} CYC:{}",
+        pc,
+        bytes,
+        assembly,
         cpu.registers.a,
         cpu.registers.x,
         cpu.registers.y,
         <ProcessorStatus as Into<u8>>::into(cpu.status),
+        cpu.stack_pointer,
+        cpu.cycles(),
     )
 }
 
+/// An operand with the addresses it goes through and the value it ends at,
+/// jumps only show their target
+fn operand(cpu: &CPU, mode: &AddressingMode, operands: &[u8], jump: bool) -> String {
+    let peek = |addr: u16| cpu.mem_peek(addr);
+    let zero_page_word =
+        |addr: u8| u16::from_le_bytes([peek(addr as u16), peek(addr.wrapping_add(1) as u16)]);
+    let (x, y) = (cpu.registers.x, cpu.registers.y);
+    let byte = operands.first().copied().unwrap_or_default();
+    let word = u16::from_le_bytes([byte, operands.get(1).copied().unwrap_or_default()]);
+
+    match mode {
+        AddressingMode::Implicit => String::new(),
+        AddressingMode::Accumulator => "A".to_string(),
+        AddressingMode::Relative => {
+            let target = cpu
+                .program_counter
+                .wrapping_add(2)
+                .wrapping_add(byte as i8 as u16);
+            format!("${target:04X}")
+        }
+        AddressingMode::Immediate => format!("#${byte:02X}"),
+        AddressingMode::ZeroPage => format!("${byte:02X} = {:02X}", peek(byte as u16)),
+        AddressingMode::ZeroPage_X => {
+            let addr = byte.wrapping_add(x);
+            format!("${byte:02X},X @ {addr:02X} = {:02X}", peek(addr as u16))
+        }
+        AddressingMode::ZeroPage_Y => {
+            let addr = byte.wrapping_add(y);
+            format!("${byte:02X},Y @ {addr:02X} = {:02X}", peek(addr as u16))
+        }
+        AddressingMode::Absolute if jump => format!("${word:04X}"),
+        AddressingMode::Absolute => format!("${word:04X} = {:02X}", peek(word)),
+        AddressingMode::Absolute_X => {
+            let addr = word.wrapping_add(x as u16);
+            format!("${word:04X},X @ {addr:04X} = {:02X}", peek(addr))
+        }
+        AddressingMode::Absolute_Y => {
+            let addr = word.wrapping_add(y as u16);
+            format!("${word:04X},Y @ {addr:04X} = {:02X}", peek(addr))
+        }
+        AddressingMode::Indirect => {
+            // The high byte comes from the same page, as JMP fetches it
+            let hi = (word & 0xFF00) | (word.wrapping_add(1) & 0x00FF);
+            let target = u16::from_le_bytes([peek(word), peek(hi)]);
+            format!("(${word:04X}) = {target:04X}")
+        }
+        AddressingMode::Indirect_X => {
+            let pointer = byte.wrapping_add(x);
+            let addr = zero_page_word(pointer);
+            format!(
+                "(${byte:02X},X) @ {pointer:02X} = {addr:04X} = {:02X}",
+                peek(addr)
+            )
+        }
+        AddressingMode::Indirect_Y => {
+            let base = zero_page_word(byte);
+            let addr = base.wrapping_add(y as u16);
+            format!(
+                "(${byte:02X}),Y = {base:04X} @ {addr:04X} = {:02X}",
+                peek(addr)
+            )
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use crate::{cpu::bus::Bus, nes::NesRom};
@@ -43,7 +116,7 @@ mod test {
 
     #[test]
     fn test_format_trace() {
-        let mut bus = Bus::new(NesRom::parse(ROM_BYTES).unwrap().1);
+        let mut bus = Bus::new(NesRom::parse(ROM_BYTES).unwrap().1).unwrap();
         bus.mem_write(100, 0xa2);
         bus.mem_write(101, 0x01);
         bus.mem_write(102, 0xca);
@@ -60,22 +133,22 @@ mod test {
             result.push(trace(cpu));
         });
         assert_eq!(
-            "0064  A2 01     LDX #$01                        A:01 X:02 Y:03 P:24 SP:FD",
+            "0064  A2 01     LDX #$01                        A:01 X:02 Y:03 P:24 SP:FD CYC:5",
             result[0]
         );
         assert_eq!(
-            "0066  CA        DEX                             A:01 X:01 Y:03 P:24 SP:FD",
+            "0066  CA        DEX                             A:01 X:01 Y:03 P:24 SP:FD CYC:7",
             result[1]
         );
         assert_eq!(
-            "0067  88        DEY                             A:01 X:00 Y:03 P:26 SP:FD",
+            "0067  88        DEY                             A:01 X:00 Y:03 P:26 SP:FD CYC:9",
             result[2]
         );
     }
 
     #[test]
     fn test_format_mem_access() {
-        let mut bus = Bus::new(NesRom::parse(ROM_BYTES).unwrap().1);
+        let mut bus = Bus::new(NesRom::parse(ROM_BYTES).unwrap().1).unwrap();
 
         // ORA ($33), Y
         bus.mem_write(100, 0x11);
@@ -96,7 +169,7 @@ mod test {
             result.push(trace(cpu));
         });
         assert_eq!(
-            "0064  11 33     ORA ($33),Y = 0400 @ 0400 = AA  A:00 X:00 Y:00 P:24 SP:FD",
+            "0064  11 33     ORA ($33),Y = 0400 @ 0400 = AA  A:00 X:00 Y:00 P:24 SP:FD CYC:5",
             result[0]
         );
     }
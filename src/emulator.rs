@@ -1,13 +1,17 @@
-use std::{fs, path::PathBuf, sync::Arc};
+use std::sync::Arc;
 
-use clap::Parser;
 use nes_rs::{
+    apu::Channel,
+    audio::{rate_control::RateControl, sink::AudioSink},
     cpu::{CPU, mem::Memory},
-    nes::NesRom,
+    image::Image,
+    ppu::{debug, ntsc::NtscFilter, palette::Palette},
 };
 use pixels::{Pixels, PixelsBuilder, SurfaceTexture, wgpu::TextureFormat};
-use rand::{Rng, rng, rngs::ThreadRng};
-use tracing::{error, trace};
+use rand::Rng;
+use tracing::{error, info, trace};
+
+use crate::cli::{self, CliError, RunArgs};
 use winit::{
     dpi::PhysicalSize,
     event::{ElementState, KeyEvent},
@@ -16,33 +20,88 @@ use winit::{
     window::Window,
 };
 
+/// The console and where its sound goes, everything that runs without a
+/// window
+pub struct Console {
+    cpu: CPU,
+    audio: Box<dyn AudioSink>,
+    rate_control: RateControl,
+    audio_buffer: Vec<f32>,
+}
+
+impl Console {
+    pub fn new(mut cpu: CPU, args: &RunArgs) -> Result<Self, CliError> {
+        let audio = args.audio_sink()?;
+        // The sound card may only play at a rate of its own
+        let sample_rate = audio.sample_rate().unwrap_or(args.machine.sample_rate);
+        if sample_rate != cpu.apu().sample_rate() {
+            cpu.apu_mut().set_sample_rate(sample_rate);
+        }
+        Ok(Self {
+            cpu,
+            audio,
+            rate_control: RateControl::new(cli::audio_target(sample_rate)),
+            audio_buffer: Vec::new(),
+        })
+    }
+
+    /// Runs until the PPU completes the next frame and plays the sound of
+    /// it, returns false if the program stopped first
+    pub fn run_frame(&mut self) -> bool {
+        let frame = self.cpu.ppu().frame_count() + 1;
+        let running = self.cpu.run_frames(frame);
+        self.play_audio();
+        running
+    }
+
+    /// Hands the samples produced so far to the sink, and steers their rate
+    /// towards the sink's target fill level
+    fn play_audio(&mut self) {
+        let samples = self.cpu.apu_mut().samples();
+        if samples.is_empty() {
+            return;
+        }
+        self.audio_buffer.resize(samples.len(), 0.0);
+        samples.drain_into(&mut self.audio_buffer);
+        if let Err(err) = self.audio.write(&self.audio_buffer) {
+            error!(target: "audio", "Error: {}", err);
+        }
+        if let Some(buffered) = self.audio.buffered() {
+            let factor = self.rate_control.adjustment(buffered);
+            self.cpu.apu_mut().set_rate_adjustment(factor);
+        }
+    }
+}
+
+impl Drop for Console {
+    fn drop(&mut self) {
+        if let Err(err) = self.audio.finish() {
+            error!(target: "audio", "Error: {}", err);
+        }
+    }
+}
+
 pub struct Emulator {
     #[allow(dead_code)]
     window: Arc<Window>,
-    cpu: CPU,
+    console: Console,
+    palette: Palette,
+    ntsc: Option<NtscFilter>,
+    pattern_palette: u8,
     screen: Pixels<'static>,
-    rng: ThreadRng,
-}
-
-#[derive(Debug, Parser)]
-struct Cli {
-    rom: PathBuf,
+    /// PPU frame count of the picture on the screen
+    drawn_frame: u64,
 }
 
 impl Emulator {
-    pub fn new(window: Arc<Window>) -> Self {
-        let cli = Cli::parse();
-        let rom_bytes = fs::read(cli.rom).unwrap();
-        let (_, rom) = NesRom::parse(&rom_bytes).unwrap();
-
-        let rng = rng();
-        let mut cpu = CPU::with_rom(rom);
-        cpu.reset();
+    pub fn new(window: Arc<Window>, console: Console, palette: Palette, args: &RunArgs) -> Self {
+        let ntsc = args.ntsc.map(|preset| NtscFilter::new(preset.params()));
 
         let screen = {
             let size = window.inner_size();
             let surface_texture = SurfaceTexture::new(size.width, size.height, window.clone());
-            PixelsBuilder::new(32, 32, surface_texture)
+            let (width, height) = args.screen_size();
+            PixelsBuilder::new(width, height, surface_texture)
                 .texture_format(TextureFormat::Rgba8Unorm)
                 .build()
                 .unwrap()
@@ -51,8 +110,11 @@ impl Emulator {
         Self {
             window,
             screen,
-            rng,
-            cpu,
+            console,
+            palette,
+            ntsc,
+            pattern_palette: args.machine.pattern_palette,
+            drawn_frame: 0,
         }
     }
 
@@ -87,17 +149,25 @@ impl Emulator {
         {
             match logical_key.as_ref() {
                 Key::Named(NamedKey::Escape) => event_loop.exit(),
+                Key::Named(NamedKey::F1) => self.toggle_mute(Channel::Pulse1),
+                Key::Named(NamedKey::F2) => self.toggle_mute(Channel::Pulse2),
+                Key::Named(NamedKey::F3) => self.toggle_mute(Channel::Triangle),
+                Key::Named(NamedKey::F4) => self.toggle_mute(Channel::Noise),
+                Key::Named(NamedKey::F5) => self.toggle_mute(Channel::Dmc),
+                Key::Named(NamedKey::F10) => self.save_oscilloscope(),
+                Key::Named(NamedKey::F11) => self.dump_ppu(),
+                Key::Named(NamedKey::F12) => self.screenshot(),
                 Key::Character("w") | Key::Named(NamedKey::ArrowUp) => {
-                    self.cpu.mem_write(0xFF, 0x77)
+                    self.console.cpu.mem_write(0xFF, 0x77)
                 }
                 Key::Character("a") | Key::Named(NamedKey::ArrowLeft) => {
-                    self.cpu.mem_write(0xFF, 0x61)
+                    self.console.cpu.mem_write(0xFF, 0x61)
                 }
                 Key::Character("s") | Key::Named(NamedKey::ArrowDown) => {
-                    self.cpu.mem_write(0xFF, 0x73)
+                    self.console.cpu.mem_write(0xFF, 0x73)
                 }
                 Key::Character("d") | Key::Named(NamedKey::ArrowRight) => {
-                    self.cpu.mem_write(0xFF, 0x64)
+                    self.console.cpu.mem_write(0xFF, 0x64)
                 }
                 _ => {}
             }
@@ -105,46 +175,137 @@ impl Emulator {
     }
 
     fn update(&mut self, event_loop: &ActiveEventLoop) {
-        self.cpu.mem_write(0xfe, self.rng.random_range(1..16));
-        if self.cpu.tick() {
+        let random = self.console.cpu.rng().random_range(1..16);
+        self.console.cpu.mem_write(0xfe, random);
+        if !self.console.run_frame() {
             event_loop.exit();
-        };
-        trace!("tick happened");
+        }
+        trace!("frame {} done", self.console.cpu.ppu().frame_count());
     }
 
+    /// Copies the last frame the PPU completed to the screen, returns false
+    /// when it is already there
     fn draw(&mut self) -> bool {
-        let frame = self.screen.frame_mut();
-        let mut frame_idx = 0;
-        let mut update = false;
-        for i in 0x0200..0x0600 {
-            let (r, g, b, a) = Self::color(self.cpu.mem_read(i));
-            if frame[frame_idx] != r
-                || frame[frame_idx + 1] != g
-                || frame[frame_idx + 2] != b
-                || frame[frame_idx + 3] != a
-            {
-                frame[frame_idx] = r;
-                frame[frame_idx + 1] = g;
-                frame[frame_idx + 2] = b;
-                frame[frame_idx + 3] = a;
-                update = true;
+        let ppu = self.console.cpu.ppu();
+        if ppu.frame_count() == self.drawn_frame {
+            return false;
+        }
+        self.drawn_frame = ppu.frame_count();
+        let screen = self.screen.frame_mut();
+        match &self.ntsc {
+            Some(filter) => {
+                let image = filter.apply(ppu.frame(), ppu.frame_count());
+                let line = image.width * 4;
+                for (row, lines) in image
+                    .data
+                    .chunks_exact(line)
+                    .zip(screen.chunks_exact_mut(line * 2))
+                {
+                    lines[..line].copy_from_slice(row);
+                    lines[line..].copy_from_slice(row);
+                }
             }
-            frame_idx += 4
+            None => self.palette.to_rgba(ppu.frame(), screen),
+        }
+        true
+    }
+
+    /// Saves the current PPU frame as a PNG in the working directory
+    fn screenshot(&self) {
+        let ppu = self.console.cpu.ppu();
+        let image = match &self.ntsc {
+            Some(filter) => filter.apply(ppu.frame(), ppu.frame_count()),
+            None => Image::from_frame(ppu.frame(), &self.palette),
+        };
+        let path = format!("screenshot-{}.png", ppu.frame_count());
+        match image.save_png(&path) {
+            Ok(()) => info!("Saved {}", path),
+            Err(err) => error!(target: "screenshot", "Error: {}", err),
+        }
+    }
+
+    /// Saves the PPU debug views as PNGs in the working directory
+    fn dump_ppu(&self) {
+        let ppu = self.console.cpu.ppu();
+        let name = format!("frame-{}", ppu.frame_count());
+        match debug::save_all(ppu, &self.palette, self.pattern_palette, ".", &name) {
+            Ok(paths) => info!("Saved {:?}", paths),
+            Err(err) => error!(target: "dump_ppu", "Error: {}", err),
+        }
+    }
+
+    fn toggle_mute(&mut self, channel: Channel) {
+        let mixer = self.console.cpu.apu_mut().mixer_mut();
+        let muted = !mixer.settings(channel).muted;
+        mixer.set_muted(channel, muted);
+        info!("{:?} {}", channel, if muted { "muted" } else { "unmuted" });
+    }
+
+    /// Saves the recent waveform of every sound channel as a PNG in the
+    /// working directory
+    fn save_oscilloscope(&self) {
+        let image = self.console.cpu.apu().oscilloscope().render(512, 64);
+        let path = format!("scope-{}.png", self.console.cpu.ppu().frame_count());
+        match image.save_png(&path) {
+            Ok(()) => info!("Saved {}", path),
+            Err(err) => error!(target: "oscilloscope", "Error: {}", err),
         }
-        update
     }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use clap::Parser;
+    use nes_rs::nes::{NesRom, Region};
+
+    use super::*;
+    use crate::cli::{Cli, Command};
 
-    fn color(byte: u8) -> (u8, u8, u8, u8) {
-        match byte {
-            0 => (0, 0, 0, 255),
-            1 => (255, 255, 255, 255),
-            2 | 9 => (128, 128, 128, 255),
-            3 | 10 => (255, 0, 0, 255),
-            4 | 11 => (0, 255, 0, 255),
-            5 | 12 => (0, 0, 255, 255),
-            6 | 13 => (255, 0, 255, 255),
-            7 | 14 => (255, 255, 0, 255),
-            _ => (0, 255, 255, 255),
+    /// An NROM cart looping on a JMP at $8000
+    fn idle_rom() -> NesRom {
+        let mut bytes = vec![0; 16 + 0x4000];
+        bytes[..5].copy_from_slice(b"NES\x1A\x01");
+        bytes[16..19].copy_from_slice(&[0x4C, 0x00, 0x80]);
+        bytes[16 + 0x3FFC..16 + 0x3FFE].copy_from_slice(&[0x00, 0x80]);
+        NesRom::parse(&bytes).unwrap().1
+    }
+
+    #[test]
+    fn test_run_frame_records_a_frame_of_audio() {
+        let path = std::env::temp_dir().join(format!("nes-rs-{}.wav", std::process::id()));
+        let Command::Run(args) = Cli::parse_from([
+            "nes-rs".as_ref(),
+            "run".as_ref(),
+            "idle.nes".as_ref(),
+            "--record-audio".as_ref(),
+            path.as_os_str(),
+        ])
+        .command
+        else {
+            unreachable!()
+        };
+        let mut cpu = CPU::with_rom(idle_rom()).unwrap();
+        cpu.reset();
+
+        let frames = 30;
+        let mut console = Console::new(cpu, &args).unwrap();
+        for frame in 1..=frames {
+            assert!(console.run_frame());
+            assert_eq!(console.cpu.ppu().frame_count(), frame);
         }
+        let cycles = console.cpu.cycles() as u64;
+        drop(console);
+
+        let samples = hound::WavReader::open(&path).unwrap().duration() as u64;
+        fs::remove_file(&path).unwrap();
+        let expected = cycles * args.machine.sample_rate as u64 / Region::NTSC.cpu_clock() as u64;
+        assert!(
+            samples.abs_diff(expected) <= 2,
+            "{samples} samples, expected {expected}"
+        );
+        // About 800 a frame at 48 kHz
+        assert!(samples.abs_diff(frames * 800) < frames * 5);
     }
 }
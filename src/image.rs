@@ -0,0 +1,77 @@
+use std::{fs::File, io::BufWriter, io::Write, path::Path};
+
+use crate::ppu::{
+    frame::{self, Frame},
+    palette::Palette,
+};
+
+/// An RGBA8 picture, what screenshots and debug views are saved as
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub data: Vec<u8>,
+}
+
+impl Image {
+    pub fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            data: vec![0; width * height * 4],
+        }
+    }
+
+    /// Converts a PPU frame with a palette, one image pixel per dot
+    pub fn from_frame(frame: &Frame, palette: &Palette) -> Self {
+        let mut image = Self::new(frame::WIDTH, frame::HEIGHT);
+        palette.to_rgba(frame, &mut image.data);
+        image
+    }
+
+    pub fn set_pixel(&mut self, x: usize, y: usize, rgb: [u8; 3]) {
+        let offset = (y * self.width + x) * 4;
+        let [r, g, b] = rgb;
+        self.data[offset..offset + 4].copy_from_slice(&[r, g, b, 0xFF]);
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> [u8; 3] {
+        let offset = (y * self.width + x) * 4;
+        [
+            self.data[offset],
+            self.data[offset + 1],
+            self.data[offset + 2],
+        ]
+    }
+
+    pub fn write_png(&self, writer: impl Write) -> Result<(), png::EncodingError> {
+        let mut encoder = png::Encoder::new(writer, self.width as u32, self.height as u32);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.write_header()?.write_image_data(&self.data)
+    }
+
+    pub fn save_png(&self, path: impl AsRef<Path>) -> Result<(), png::EncodingError> {
+        self.write_png(BufWriter::new(File::create(path)?))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_png_round_trip() {
+        let mut image = Image::new(3, 2);
+        image.set_pixel(2, 1, [0x12, 0x34, 0x56]);
+        let mut png_data = Vec::new();
+        image.write_png(&mut png_data).unwrap();
+
+        let decoder = png::Decoder::new(png_data.as_slice());
+        let mut reader = decoder.read_info().unwrap();
+        let mut data = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut data).unwrap();
+        assert_eq!((info.width, info.height), (3, 2));
+        assert_eq!(&data[20..24], &[0x12, 0x34, 0x56, 0xFF]);
+    }
+}
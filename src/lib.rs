@@ -1,4 +1,11 @@
 #![allow(dead_code)]
 
+pub mod apu;
+pub mod audio;
+pub mod config;
 pub mod cpu;
+pub mod image;
+pub mod mapper;
 pub mod nes;
+pub mod ppu;
+pub mod state;
@@ -1,8 +1,12 @@
+mod cli;
 mod emulator;
 
-use emulator::Emulator;
-use std::sync::Arc;
-use tracing::info;
+use clap::Parser;
+use cli::{Cli, CliError, Command, RunArgs};
+use emulator::{Console, Emulator};
+use nes_rs::ppu::palette::Palette;
+use std::{process, sync::Arc};
+use tracing::{error, info};
 use winit::{
     application::ApplicationHandler,
     dpi::{LogicalSize, PhysicalPosition},
@@ -11,26 +15,57 @@ use winit::{
     window::{Window, WindowId},
 };
 
+/// Height of the window, the screen is scaled by a whole factor to fill it
+const WINDOW_HEIGHT: u32 = 480;
+
 fn main() {
     tracing_subscriber::fmt()
         .with_max_level(tracing::Level::WARN)
         .init();
 
-    let event_loop = EventLoop::new().unwrap();
+    let result = match Cli::parse().command {
+        Command::Run(args) => run(args),
+        Command::DumpPpu(args) => cli::dump_ppu(&args),
+        Command::PlayNsf(args) => cli::play_nsf(&args),
+    };
+    if let Err(err) = result {
+        error!("{err}");
+        process::exit(1);
+    }
+}
+
+/// Loads everything the ROM needs up front, so a bad one is reported
+/// before any window opens
+fn run(args: RunArgs) -> Result<(), CliError> {
+    let console = Console::new(args.machine.cpu()?, &args)?;
+    let palette = args.machine.palette()?;
+    let event_loop = EventLoop::new()?;
     event_loop.set_control_flow(ControlFlow::Poll);
 
-    let mut app = App::default();
-    event_loop.run_app(&mut app).unwrap();
+    let mut app = App {
+        args,
+        loaded: Some((console, palette)),
+        state: None,
+    };
+    event_loop.run_app(&mut app)?;
+    Ok(())
 }
 
-#[derive(Default)]
 struct App {
+    args: RunArgs,
+    /// What the emulator is built from once the window exists
+    loaded: Option<(Console, Palette)>,
     state: Option<Emulator>,
 }
 
 impl ApplicationHandler for App {
     fn resumed(&mut self, event_loop: &ActiveEventLoop) {
-        let size = LogicalSize::new(32 * 10 * 2, 32 * 10 * 2);
+        let Some((console, palette)) = self.loaded.take() else {
+            return;
+        };
+        let (width, height) = self.args.screen_size();
+        let scale = (WINDOW_HEIGHT / height).max(1);
+        let size = LogicalSize::new(width * scale, height * scale);
         let monitor_size = event_loop
             .primary_monitor()
             .or_else(|| event_loop.available_monitors().next())
@@ -46,7 +81,7 @@ impl ApplicationHandler for App {
             event_loop
                 .create_window(
                     Window::default_attributes()
-                        .with_title("Snake")
+                        .with_title("nes-rs")
                         .with_position(position)
                         .with_inner_size(size)
                         .with_min_inner_size(size)
@@ -54,7 +89,7 @@ impl ApplicationHandler for App {
                 )
                 .unwrap(),
         );
-        let state = Emulator::new(window.clone());
+        let state = Emulator::new(window.clone(), console, palette, &self.args);
 
         self.state = Some(state);
 
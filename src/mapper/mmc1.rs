@@ -0,0 +1,408 @@
+use super::{
+    Cartridge, Mapper, PATTERN_TABLES, PATTERN_TABLES_END, PRG_RAM, PRG_RAM_END, PRG_ROM,
+    PRG_ROM_END,
+};
+use crate::nes::{Mirroring, PRG_RAM_PAGE_SIZE, PRG_ROM_PAGE_SIZE};
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+const CHR_BANK_SIZE: usize = 0x1000;
+/// PRG ROM past this is switched in halves by SUROM and SXROM boards
+const PRG_OUTER_BANK_SIZE: usize = 0x40000;
+/// Control value on power-on and after a reset write: PRG mode 3
+const CONTROL_RESET: u8 = 0b0_1100;
+const SHIFT_RESET: u8 = 0b1000_0000;
+
+/// Mapper 1, the SxROM boards: PRG and CHR banks switched through a serial
+/// port at $8000-$FFFF, five writes of one bit each per register.
+///
+/// The CHR registers double as extra lines on boards with 8 KiB of CHR RAM,
+/// which do not need them for CHR: SNROM disables PRG RAM with bit 4,
+/// SOROM and SXROM select PRG RAM banks with bits 2-3, and SUROM and SXROM
+/// select the 256 KiB half of PRG ROM with bit 4. These are all read from
+/// the first CHR register
+pub struct Mmc1 {
+    cartridge: Cartridge,
+    shift: u8,
+    shift_count: u8,
+    control: u8,
+    chr_bank_0: u8,
+    chr_bank_1: u8,
+    prg_bank: u8,
+    /// CPU cycles since the last write to the serial port, saturating
+    cycles_since_write: u8,
+}
+
+impl Mmc1 {
+    pub fn new(mut cartridge: Cartridge) -> Self {
+        // Nearly every board has 8 KiB of PRG RAM, and iNES headers often
+        // leave it out
+        if cartridge.prg_ram.is_empty() {
+            cartridge.prg_ram = vec![0; PRG_RAM_PAGE_SIZE];
+        }
+        Self {
+            cartridge,
+            shift: 0,
+            shift_count: 0,
+            control: CONTROL_RESET,
+            chr_bank_0: 0,
+            chr_bank_1: 0,
+            prg_bank: 0,
+            cycles_since_write: u8::MAX,
+        }
+    }
+
+    fn write_serial(&mut self, addr: u16, data: u8) {
+        // The serial port only sees the first of two writes on back to back
+        // cycles, as in the two writes of a read-modify-write instruction
+        let consecutive = self.cycles_since_write == 1;
+        self.cycles_since_write = 0;
+        if consecutive {
+            return;
+        }
+
+        if data & SHIFT_RESET != 0 {
+            self.shift = 0;
+            self.shift_count = 0;
+            self.control |= CONTROL_RESET;
+            return;
+        }
+        self.shift |= (data & 1) << self.shift_count;
+        self.shift_count += 1;
+        if self.shift_count < 5 {
+            return;
+        }
+        let value = self.shift;
+        self.shift = 0;
+        self.shift_count = 0;
+        match (addr >> 13) & 0b11 {
+            0 => self.control = value,
+            1 => self.chr_bank_0 = value,
+            2 => self.chr_bank_1 = value,
+            _ => self.prg_bank = value,
+        }
+    }
+
+    fn prg_mode(&self) -> u8 {
+        (self.control >> 2) & 0b11
+    }
+
+    fn chr_4k_mode(&self) -> bool {
+        self.control & 0b1_0000 != 0
+    }
+
+    /// SUROM and SXROM hold 512 KiB of PRG ROM, in two halves the 16 KiB
+    /// banks are switched within
+    fn prg_outer_bank(&self) -> usize {
+        if self.cartridge.prg_rom.len() > PRG_OUTER_BANK_SIZE {
+            (self.chr_bank_0 as usize >> 4) & 1
+        } else {
+            0
+        }
+    }
+
+    /// Byte `addr` of PRG ROM, through the 16 KiB banks of the current mode
+    fn prg_rom_offset(&self, addr: u16) -> usize {
+        let last = (PRG_OUTER_BANK_SIZE / PRG_ROM_PAGE_SIZE - 1).min(
+            self.cartridge
+                .prg_rom
+                .len()
+                .div_ceil(PRG_ROM_PAGE_SIZE)
+                .max(1)
+                - 1,
+        );
+        let bank = self.prg_bank as usize & 0b1111;
+        let upper = addr >= 0xC000;
+        let bank = match (self.prg_mode(), upper) {
+            // 32 KiB at once, ignoring the low bit
+            (0 | 1, _) => (bank & !1) | upper as usize,
+            (2, false) => 0,
+            (2, true) => bank,
+            (_, false) => bank,
+            (_, true) => last,
+        };
+        let bank = self.prg_outer_bank() * (PRG_OUTER_BANK_SIZE / PRG_ROM_PAGE_SIZE) + bank;
+        bank * PRG_ROM_PAGE_SIZE + (addr as usize & (PRG_ROM_PAGE_SIZE - 1))
+    }
+
+    /// PRG RAM is enabled by bit 4 of the PRG register being clear, and on
+    /// SNROM by bit 4 of the CHR register too
+    fn prg_ram_enabled(&self) -> bool {
+        let snrom = self.cartridge.chr_is_ram
+            && self.cartridge.prg_rom.len() <= PRG_OUTER_BANK_SIZE
+            && self.cartridge.prg_ram.len() == PRG_RAM_PAGE_SIZE;
+        self.prg_bank & 0b1_0000 == 0 && !(snrom && self.chr_bank_0 & 0b1_0000 != 0)
+    }
+
+    /// SOROM has 16 KiB of PRG RAM and SXROM 32 KiB, in 8 KiB banks
+    fn prg_ram_offset(&self, addr: u16) -> usize {
+        let bank = match self.cartridge.prg_ram.len() / PRG_RAM_PAGE_SIZE {
+            2 => (self.chr_bank_0 as usize >> 3) & 1,
+            4 => (self.chr_bank_0 as usize >> 2) & 0b11,
+            _ => 0,
+        };
+        bank * PRG_RAM_PAGE_SIZE + (addr - PRG_RAM) as usize
+    }
+
+    fn chr_offset(&self, addr: u16) -> usize {
+        let bank = match (self.chr_4k_mode(), addr as usize >= CHR_BANK_SIZE) {
+            (true, false) => self.chr_bank_0 as usize,
+            (true, true) => self.chr_bank_1 as usize,
+            // 8 KiB at once, ignoring the low bit
+            (false, upper) => (self.chr_bank_0 as usize & !1) | upper as usize,
+        };
+        bank * CHR_BANK_SIZE + (addr as usize & (CHR_BANK_SIZE - 1))
+    }
+}
+
+impl Mapper for Mmc1 {
+    fn cartridge(&self) -> &Cartridge {
+        &self.cartridge
+    }
+
+    fn cartridge_mut(&mut self) -> &mut Cartridge {
+        &mut self.cartridge
+    }
+
+    fn cpu_peek(&self, addr: u16) -> Option<u8> {
+        match addr {
+            PRG_RAM..=PRG_RAM_END if self.prg_ram_enabled() => {
+                self.cartridge.read_prg_ram(self.prg_ram_offset(addr))
+            }
+            PRG_ROM..=PRG_ROM_END => self.cartridge.read_prg_rom(self.prg_rom_offset(addr)),
+            _ => None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            PRG_RAM..=PRG_RAM_END if self.prg_ram_enabled() => {
+                let offset = self.prg_ram_offset(addr);
+                self.cartridge.write_prg_ram(offset, data);
+            }
+            PRG_ROM..=PRG_ROM_END => self.write_serial(addr, data),
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&self, addr: u16) -> Option<u8> {
+        match addr {
+            PATTERN_TABLES..=PATTERN_TABLES_END => {
+                Some(self.cartridge.read_chr(self.chr_offset(addr)))
+            }
+            _ => None,
+        }
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) -> bool {
+        match addr {
+            PATTERN_TABLES..=PATTERN_TABLES_END => {
+                let offset = self.chr_offset(addr);
+                self.cartridge.write_chr(offset, data);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        match self.control & 0b11 {
+            0 => Mirroring::SingleScreenA,
+            1 => Mirroring::SingleScreenB,
+            2 => Mirroring::Vertical,
+            _ => Mirroring::Horizontal,
+        }
+    }
+
+    fn cpu_clock(&mut self) {
+        self.cycles_since_write = self.cycles_since_write.saturating_add(1);
+    }
+}
+
+impl Snapshot for Mmc1 {
+    fn save(&self, state: &mut StateWriter) {
+        self.cartridge.save(state);
+        state.u8(self.shift);
+        state.u8(self.shift_count);
+        state.u8(self.control);
+        state.u8(self.chr_bank_0);
+        state.u8(self.chr_bank_1);
+        state.u8(self.prg_bank);
+        state.u8(self.cycles_since_write);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.cartridge.load(state)?;
+        self.shift = state.u8()?;
+        self.shift_count = state.u8()?;
+        if self.shift_count >= 5 {
+            return Err(StateError::InvalidValue("MMC1 shift count"));
+        }
+        self.control = state.u8()?;
+        self.chr_bank_0 = state.u8()?;
+        self.chr_bank_1 = state.u8()?;
+        self.prg_bank = state.u8()?;
+        self.cycles_since_write = state.u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mapper::test::rom;
+    use crate::nes::{CHR_ROM_PAGE_SIZE, RomMapper};
+
+    /// `prg_pages` of 16 KiB and `chr_pages` of 8 KiB, each 4 KiB of them
+    /// filled with its index
+    fn board(prg_pages: usize, chr_pages: usize, prg_ram_pages: usize) -> Mmc1 {
+        let mut rom = rom(RomMapper::NintendoMMC1);
+        rom.prg_rom = (0..prg_pages * PRG_ROM_PAGE_SIZE)
+            .map(|i| (i / PRG_ROM_PAGE_SIZE) as u8)
+            .collect();
+        rom.chr_rom = (0..chr_pages * CHR_ROM_PAGE_SIZE)
+            .map(|i| (i / CHR_BANK_SIZE) as u8)
+            .collect();
+        rom.header.len_chr_rom = chr_pages as u16;
+        rom.header.len_prg_ram = prg_ram_pages as u8;
+        Mmc1::new(Cartridge::new(rom))
+    }
+
+    /// Loads a register through the serial port, spacing the writes out
+    fn write_register(mmc1: &mut Mmc1, addr: u16, value: u8) {
+        for bit in 0..5 {
+            mmc1.cpu_clock();
+            mmc1.cpu_clock();
+            mmc1.cpu_write(addr, value >> bit);
+        }
+    }
+
+    #[test]
+    fn test_serial_port_and_reset() {
+        let mut mmc1 = board(8, 2, 0);
+        // Power-on fixes the last bank at $C000
+        assert_eq!(mmc1.cpu_peek(0xC000), Some(7));
+
+        write_register(&mut mmc1, 0xE000, 3);
+        assert_eq!(mmc1.cpu_peek(0x8000), Some(3));
+
+        // A write with bit 7 set drops the bits shifted in so far
+        mmc1.cpu_clock();
+        mmc1.cpu_write(0xE000, 1);
+        mmc1.cpu_clock();
+        mmc1.cpu_clock();
+        mmc1.cpu_write(0x8000, 0x80);
+        write_register(&mut mmc1, 0xE000, 5);
+        assert_eq!(mmc1.cpu_peek(0x8000), Some(5));
+    }
+
+    #[test]
+    fn test_prg_modes() {
+        let mut mmc1 = board(8, 2, 0);
+        write_register(&mut mmc1, 0xE000, 5);
+
+        // 32 KiB mode ignores the low bit of the bank
+        write_register(&mut mmc1, 0x8000, 0b0_0000);
+        assert_eq!(mmc1.cpu_peek(0x8000), Some(4));
+        assert_eq!(mmc1.cpu_peek(0xC000), Some(5));
+        // First bank fixed at $8000
+        write_register(&mut mmc1, 0x8000, 0b0_1000);
+        assert_eq!(mmc1.cpu_peek(0x8000), Some(0));
+        assert_eq!(mmc1.cpu_peek(0xC000), Some(5));
+        // Last bank fixed at $C000
+        write_register(&mut mmc1, 0x8000, 0b0_1100);
+        assert_eq!(mmc1.cpu_peek(0x8000), Some(5));
+        assert_eq!(mmc1.cpu_peek(0xC000), Some(7));
+    }
+
+    #[test]
+    fn test_chr_modes_and_mirroring() {
+        let mut mmc1 = board(2, 4, 0);
+        write_register(&mut mmc1, 0xA000, 3);
+        write_register(&mut mmc1, 0xC000, 6);
+
+        // 8 KiB mode ignores the low bit and the second register
+        write_register(&mut mmc1, 0x8000, 0b0_0010);
+        assert_eq!(mmc1.ppu_read(0x0000), Some(2));
+        assert_eq!(mmc1.ppu_read(0x1000), Some(3));
+        assert_eq!(mmc1.mirroring(), Mirroring::Vertical);
+
+        write_register(&mut mmc1, 0x8000, 0b1_0011);
+        assert_eq!(mmc1.ppu_read(0x0000), Some(3));
+        assert_eq!(mmc1.ppu_read(0x1000), Some(6));
+        assert_eq!(mmc1.mirroring(), Mirroring::Horizontal);
+        write_register(&mut mmc1, 0x8000, 0b1_0001);
+        assert_eq!(mmc1.mirroring(), Mirroring::SingleScreenB);
+    }
+
+    #[test]
+    fn test_consecutive_writes_are_ignored() {
+        let mut mmc1 = board(8, 2, 0);
+        write_register(&mut mmc1, 0xE000, 2);
+
+        // A read-modify-write of $FF: the reset write goes through, the
+        // result written on the next cycle does not
+        mmc1.cpu_clock();
+        mmc1.cpu_clock();
+        mmc1.cpu_write(0xE000, 1);
+        mmc1.cpu_clock();
+        mmc1.cpu_clock();
+        mmc1.cpu_write(0xE000, 0xFF);
+        mmc1.cpu_clock();
+        mmc1.cpu_write(0xE000, 0x00);
+        write_register(&mut mmc1, 0xE000, 6);
+        assert_eq!(mmc1.cpu_peek(0x8000), Some(6));
+    }
+
+    #[test]
+    fn test_prg_ram_enable() {
+        let mut mmc1 = board(2, 2, 1);
+        mmc1.cpu_write(0x6000, 0x42);
+        assert_eq!(mmc1.cpu_peek(0x6000), Some(0x42));
+        write_register(&mut mmc1, 0xE000, 0b1_0000);
+        assert_eq!(mmc1.cpu_peek(0x6000), None);
+
+        // SNROM also disables it with the CHR register
+        let mut snrom = board(2, 0, 1);
+        snrom.cpu_write(0x6000, 0x42);
+        write_register(&mut snrom, 0xA000, 0b1_0000);
+        assert_eq!(snrom.cpu_peek(0x6000), None);
+        write_register(&mut snrom, 0xA000, 0b0_0000);
+        assert_eq!(snrom.cpu_peek(0x6000), Some(0x42));
+    }
+
+    #[test]
+    fn test_surom_outer_bank() {
+        let mut surom = board(32, 0, 1);
+        write_register(&mut surom, 0xE000, 2);
+        assert_eq!(surom.cpu_peek(0x8000), Some(2));
+        assert_eq!(surom.cpu_peek(0xC000), Some(15));
+
+        // Bit 4 of the CHR register picks the second 256 KiB, where the fixed
+        // bank is too
+        write_register(&mut surom, 0xA000, 0b1_0000);
+        assert_eq!(surom.cpu_peek(0x8000), Some(18));
+        assert_eq!(surom.cpu_peek(0xC000), Some(31));
+        // It is not an SNROM, PRG RAM stays enabled
+        assert!(surom.cpu_peek(0x6000).is_some());
+    }
+
+    #[test]
+    fn test_sorom_and_sxrom_prg_ram_banks() {
+        let mut sorom = board(16, 0, 2);
+        sorom.cpu_write(0x6000, 1);
+        write_register(&mut sorom, 0xA000, 0b0_1000);
+        assert_eq!(sorom.cpu_peek(0x6000), Some(0));
+        sorom.cpu_write(0x6000, 2);
+        write_register(&mut sorom, 0xA000, 0b0_0000);
+        assert_eq!(sorom.cpu_peek(0x6000), Some(1));
+
+        let mut sxrom = board(32, 0, 4);
+        for bank in 0..4 {
+            write_register(&mut sxrom, 0xA000, bank << 2);
+            sxrom.cpu_write(0x7FFF, bank + 10);
+        }
+        write_register(&mut sxrom, 0xA000, 0b1_1000);
+        assert_eq!(sxrom.cpu_peek(0x7FFF), Some(12));
+        assert_eq!(sxrom.cpu_peek(0xC000), Some(31));
+    }
+}
@@ -0,0 +1,244 @@
+//! Cartridge boards, which decide what the CPU and the PPU see of the ROM
+//! and RAM chips on them
+
+mod mmc1;
+mod nrom;
+pub mod nsf;
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use rand::rngs::StdRng;
+use thiserror::Error;
+use tracing::warn;
+
+use crate::config::PowerOnState;
+use crate::nes::{Mirroring, NesRom};
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+pub use mmc1::Mmc1;
+pub use nrom::Nrom;
+
+/// First address of the CPU space wired to the cartridge
+pub const CPU_CARTRIDGE: u16 = 0x4020;
+pub const CPU_CARTRIDGE_END: u16 = 0xFFFF;
+const PRG_RAM: u16 = 0x6000;
+const PRG_RAM_END: u16 = 0x7FFF;
+const PRG_ROM: u16 = 0x8000;
+const PRG_ROM_END: u16 = 0xFFFF;
+const PATTERN_TABLES: u16 = 0x0000;
+const PATTERN_TABLES_END: u16 = 0x1FFF;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum MapperError {
+    #[error("unsupported mapper {0}")]
+    Unsupported(u8),
+}
+
+/// The mapper is shared by the CPU bus and the PPU, which both talk to the
+/// cartridge
+pub type SharedMapper = Rc<RefCell<Box<dyn Mapper>>>;
+
+/// A cartridge board. The CPU sees it at $4020-$FFFF and the PPU at
+/// $0000-$3EFF, where nametable accesses it does not answer go to the
+/// console VRAM through [`Mapper::mirroring`]
+pub trait Mapper: Snapshot {
+    fn cartridge(&self) -> &Cartridge;
+    fn cartridge_mut(&mut self) -> &mut Cartridge;
+
+    /// None when nothing on the board answers, the CPU then reads open bus
+    fn cpu_read(&mut self, addr: u16) -> Option<u8> {
+        self.cpu_peek(addr)
+    }
+    /// Reads without side effects, for debugging output
+    fn cpu_peek(&self, addr: u16) -> Option<u8>;
+    fn cpu_write(&mut self, addr: u16, data: u8);
+
+    /// None for nametable addresses left to the console VRAM
+    fn ppu_read(&self, addr: u16) -> Option<u8>;
+    /// Returns false for nametable addresses left to the console VRAM
+    fn ppu_write(&mut self, addr: u16, data: u8) -> bool;
+
+    /// How the console VRAM is laid out as four nametables
+    fn mirroring(&self) -> Mirroring {
+        self.cartridge().mirroring
+    }
+
+    /// Level of the IRQ line of the board
+    fn irq(&self) -> bool {
+        false
+    }
+
+    /// The PPU put `addr` on its address bus, boards counting scanlines watch
+    /// its A12 line rise
+    fn notify_ppu_addr(&mut self, _addr: u16) {}
+
+    /// A CPU cycle went by, overclock lines included
+    fn cpu_clock(&mut self) {}
+
+    /// A CPU cycle of normal frame timing went by, for board timers and IRQ
+    /// counters, which stand still on overclock lines where only the CPU runs
+    fn timer_clock(&mut self) {}
+
+    /// A visible or pre-render scanline ended with rendering enabled
+    fn notify_scanline(&mut self) {}
+
+    /// The console reset button was pressed
+    fn reset(&mut self) {}
+
+    fn power_on(&mut self, state: &PowerOnState, rng: &mut StdRng) {
+        self.cartridge_mut().power_on(state, rng);
+    }
+
+    /// The CHR RAM, if the board has any
+    fn chr_ram(&self) -> Option<&[u8]> {
+        let cartridge = self.cartridge();
+        cartridge.chr_is_ram.then_some(cartridge.chr.as_slice())
+    }
+}
+
+type Constructor = fn(Cartridge) -> Box<dyn Mapper>;
+
+/// Supported boards by iNES mapper number
+const REGISTRY: &[(u8, Constructor)] = &[
+    (0, |cartridge| Box::new(Nrom::new(cartridge))),
+    (1, |cartridge| Box::new(Mmc1::new(cartridge))),
+];
+
+/// The board the ROM header asks for
+pub fn from_rom(rom: NesRom) -> Result<Box<dyn Mapper>, MapperError> {
+    let number = rom.header.rom_mapper as u8;
+    let (_, constructor) = REGISTRY
+        .iter()
+        .find(|&&(id, _)| id == number)
+        .ok_or(MapperError::Unsupported(number))?;
+    Ok(constructor(Cartridge::new(rom)))
+}
+
+/// The memory chips of a board, which its mapper banks
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cartridge {
+    pub prg_rom: Vec<u8>,
+    pub prg_ram: Vec<u8>,
+    /// Pattern tables, CHR ROM or CHR RAM
+    pub chr: Vec<u8>,
+    pub chr_is_ram: bool,
+    /// Nametable layout wired on the board
+    pub mirroring: Mirroring,
+}
+
+impl Cartridge {
+    pub fn new(rom: NesRom) -> Self {
+        let header = &rom.header;
+        let (chr, chr_is_ram) = match header.chr_ram_size() {
+            size if size > 0 && rom.chr_rom.is_empty() => (vec![0; size], true),
+            _ => (rom.chr_rom, false),
+        };
+        Self {
+            prg_ram: vec![0; header.prg_ram_size()],
+            mirroring: header.mirroring,
+            prg_rom: rom.prg_rom,
+            chr,
+            chr_is_ram,
+        }
+    }
+
+    pub fn power_on(&mut self, state: &PowerOnState, rng: &mut StdRng) {
+        state.fill(&mut self.prg_ram, rng);
+        if self.chr_is_ram {
+            state.fill(&mut self.chr, rng);
+        }
+    }
+
+    /// Byte `addr` of PRG ROM, mirrored when past the end
+    pub fn read_prg_rom(&self, addr: usize) -> Option<u8> {
+        match self.prg_rom.len() {
+            0 => None,
+            size => Some(self.prg_rom[addr % size]),
+        }
+    }
+
+    /// Byte `addr` of PRG RAM, mirrored when past the end
+    pub fn read_prg_ram(&self, addr: usize) -> Option<u8> {
+        match self.prg_ram.len() {
+            0 => None,
+            size => Some(self.prg_ram[addr % size]),
+        }
+    }
+
+    pub fn write_prg_ram(&mut self, addr: usize, data: u8) {
+        if let size @ 1.. = self.prg_ram.len() {
+            self.prg_ram[addr % size] = data;
+        }
+    }
+
+    /// Pattern table memory smaller than the address space is mirrored
+    pub fn read_chr(&self, addr: usize) -> u8 {
+        match self.chr.len() {
+            0 => 0,
+            size => self.chr[addr % size],
+        }
+    }
+
+    pub fn write_chr(&mut self, addr: usize, data: u8) {
+        match self.chr.len() {
+            size @ 1.. if self.chr_is_ram => self.chr[addr % size] = data,
+            _ => warn!("Attempted to write to CHR ROM at {addr:04X}"),
+        }
+    }
+}
+
+/// Only the RAM is saved, the ROM comes from the file
+impl Snapshot for Cartridge {
+    fn save(&self, state: &mut StateWriter) {
+        state.bytes(&self.prg_ram);
+        if self.chr_is_ram {
+            state.bytes(&self.chr);
+        }
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.bytes("PRG RAM", &mut self.prg_ram)?;
+        if self.chr_is_ram {
+            state.bytes("CHR RAM", &mut self.chr)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use crate::nes::{Header, Region, RomMapper};
+
+    pub(crate) fn rom(rom_mapper: RomMapper) -> NesRom {
+        NesRom {
+            header: Header {
+                len_prg_rom: 1,
+                len_chr_rom: 0,
+                len_prg_ram: 0,
+                mirroring: Mirroring::Vertical,
+                battery_backed_ram: false,
+                trainer: false,
+                vs_system: false,
+                rom_mapper,
+                region: Region::NTSC,
+                nes2: None,
+            },
+            trainer: None,
+            prg_rom: vec![0; 0x4000],
+            chr_rom: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_registry() {
+        let mapper = from_rom(rom(RomMapper::None)).unwrap();
+        assert_eq!(mapper.mirroring(), Mirroring::Vertical);
+        assert_eq!(mapper.chr_ram().map(<[u8]>::len), Some(0x2000));
+
+        let err = from_rom(rom(RomMapper::UNROM_Switch)).err().unwrap();
+        assert_eq!(err, MapperError::Unsupported(3));
+        assert_eq!(err.to_string(), "unsupported mapper 3");
+    }
+}
@@ -0,0 +1,75 @@
+use tracing::error;
+
+use super::{
+    Cartridge, Mapper, PATTERN_TABLES, PATTERN_TABLES_END, PRG_RAM, PRG_RAM_END, PRG_ROM,
+    PRG_ROM_END,
+};
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+/// Mapper 0: up to 32 KiB of PRG ROM and 8 KiB of CHR, nothing switched
+pub struct Nrom {
+    cartridge: Cartridge,
+}
+
+impl Nrom {
+    pub fn new(cartridge: Cartridge) -> Self {
+        Self { cartridge }
+    }
+}
+
+impl Mapper for Nrom {
+    fn cartridge(&self) -> &Cartridge {
+        &self.cartridge
+    }
+
+    fn cartridge_mut(&mut self) -> &mut Cartridge {
+        &mut self.cartridge
+    }
+
+    fn cpu_peek(&self, addr: u16) -> Option<u8> {
+        match addr {
+            PRG_RAM..=PRG_RAM_END => self.cartridge.read_prg_ram((addr - PRG_RAM) as usize),
+            PRG_ROM..=PRG_ROM_END => self.cartridge.read_prg_rom((addr - PRG_ROM) as usize),
+            _ => None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            PRG_RAM..=PRG_RAM_END => self
+                .cartridge
+                .write_prg_ram((addr - PRG_RAM) as usize, data),
+            PRG_ROM..=PRG_ROM_END => {
+                error!("Attempted to write to Cartridge Read-only Memory Space")
+            }
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&self, addr: u16) -> Option<u8> {
+        match addr {
+            PATTERN_TABLES..=PATTERN_TABLES_END => Some(self.cartridge.read_chr(addr as usize)),
+            _ => None,
+        }
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) -> bool {
+        match addr {
+            PATTERN_TABLES..=PATTERN_TABLES_END => {
+                self.cartridge.write_chr(addr as usize, data);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Snapshot for Nrom {
+    fn save(&self, state: &mut StateWriter) {
+        self.cartridge.save(state);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.cartridge.load(state)
+    }
+}
@@ -0,0 +1,84 @@
+use super::{Cartridge, Mapper, Nrom, PRG_ROM, PRG_ROM_END};
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+const BANK_SELECT: u16 = 0x5FF8;
+const BANK_SELECT_END: u16 = 0x5FFF;
+const BANK_SIZE: usize = 0x1000;
+
+/// The board of NSF players: NROM, plus PRG ROM switched in 4 KiB banks
+/// through $5FF8-$5FFF for tunes that ask for it
+pub struct NsfMapper {
+    nrom: Nrom,
+    /// Banks a reset maps back
+    initial_banks: Option<[u8; 8]>,
+    banks: Option<[u8; 8]>,
+}
+
+impl NsfMapper {
+    pub fn new(cartridge: Cartridge, banks: Option<[u8; 8]>) -> Self {
+        Self {
+            nrom: Nrom::new(cartridge),
+            initial_banks: banks,
+            banks,
+        }
+    }
+}
+
+impl Mapper for NsfMapper {
+    fn cartridge(&self) -> &Cartridge {
+        self.nrom.cartridge()
+    }
+
+    fn cartridge_mut(&mut self) -> &mut Cartridge {
+        self.nrom.cartridge_mut()
+    }
+
+    fn cpu_peek(&self, addr: u16) -> Option<u8> {
+        match (addr, &self.banks) {
+            (PRG_ROM..=PRG_ROM_END, Some(banks)) => {
+                let bank = banks[(addr - PRG_ROM) as usize / BANK_SIZE] as usize;
+                let offset = addr as usize & (BANK_SIZE - 1);
+                self.cartridge().read_prg_rom(bank * BANK_SIZE + offset)
+            }
+            _ => self.nrom.cpu_peek(addr),
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        match (addr, &mut self.banks) {
+            (BANK_SELECT..=BANK_SELECT_END, Some(banks)) => {
+                banks[(addr - BANK_SELECT) as usize] = data
+            }
+            _ => self.nrom.cpu_write(addr, data),
+        }
+    }
+
+    fn ppu_read(&self, addr: u16) -> Option<u8> {
+        self.nrom.ppu_read(addr)
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) -> bool {
+        self.nrom.ppu_write(addr, data)
+    }
+
+    fn reset(&mut self) {
+        self.banks = self.initial_banks;
+    }
+}
+
+impl Snapshot for NsfMapper {
+    fn save(&self, state: &mut StateWriter) {
+        self.nrom.save(state);
+        if let Some(banks) = &self.banks {
+            state.bytes(banks);
+        }
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.nrom.load(state)?;
+        if let Some(banks) = &mut self.banks {
+            state.bytes("NSF banks", banks)?;
+        }
+        Ok(())
+    }
+}
@@ -1,3 +1,4 @@
+use clap::ValueEnum;
 use nom::{
     IResult, Parser,
     bits::{
@@ -12,8 +13,18 @@ use nom::{
 use num_derive::FromPrimitive;
 use num_traits::FromPrimitive;
 
+pub mod nsf;
+
 pub const PRG_ROM_PAGE_SIZE: usize = 0x4000;
 pub const CHR_ROM_PAGE_SIZE: usize = 0x2000;
+pub const PRG_RAM_PAGE_SIZE: usize = 0x2000;
+pub const CHR_RAM_SIZE: usize = 0x2000;
+
+/// Value of byte 7 bits 2-3 that identifies an NES 2.0 header
+const NES2_FORMAT: u8 = 0b10;
+/// NES 2.0 size MSB nibble that switches a ROM size to exponent-multiplier
+/// form
+const NES2_EXPONENT_FORM: u8 = 0x0F;
 
 #[derive(Debug, PartialEq)]
 pub struct NesRom {
@@ -50,8 +61,10 @@ impl NesRom {
 
 #[derive(Debug, PartialEq)]
 pub struct Header {
-    pub len_prg_rom: u8,
-    pub len_chr_rom: u8,
+    /// PRG ROM size in 16 KiB pages, NES 2.0 headers give it 12 bits
+    pub len_prg_rom: u16,
+    /// CHR ROM size in 8 KiB pages, NES 2.0 headers give it 12 bits
+    pub len_chr_rom: u16,
     pub len_prg_ram: u8,
     pub mirroring: Mirroring,
     pub battery_backed_ram: bool,
@@ -59,6 +72,55 @@ pub struct Header {
     pub vs_system: bool,
     pub rom_mapper: RomMapper,
     pub region: Region,
+    pub nes2: Option<Nes2Extension>,
+}
+
+/// Fields only NES 2.0 headers carry. The memory sizes are each stored as a
+/// shift count where the size is `64 << shift` bytes and zero means none
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Nes2Extension {
+    /// Board variant within the mapper number
+    pub submapper: u8,
+    pub prg_ram_shift: u8,
+    pub prg_nvram_shift: u8,
+    pub chr_ram_shift: u8,
+    pub chr_nvram_shift: u8,
+}
+
+impl Nes2Extension {
+    /// Parses bytes 10-15, the memory sizes and timing
+    fn parse(input: &[u8], submapper: u8) -> IResult<&[u8], (Self, Region)> {
+        let (input, bytes) = take(6usize)(input)?;
+        let extension = Self {
+            submapper,
+            prg_ram_shift: bytes[0] & 0x0F,
+            prg_nvram_shift: bytes[0] >> 4,
+            chr_ram_shift: bytes[1] & 0x0F,
+            chr_nvram_shift: bytes[1] >> 4,
+        };
+        // Multi-region carts run as NTSC
+        let region = match bytes[2] & 0b11 {
+            1 => Region::PAL,
+            3 => Region::Dendy,
+            _ => Region::NTSC,
+        };
+        Ok((input, (extension, region)))
+    }
+
+    fn size(shift: u8) -> usize {
+        match shift {
+            0 => 0,
+            shift => 64 << shift,
+        }
+    }
+
+    pub fn prg_ram_size(&self) -> usize {
+        Self::size(self.prg_ram_shift) + Self::size(self.prg_nvram_shift)
+    }
+
+    pub fn chr_ram_size(&self) -> usize {
+        Self::size(self.chr_ram_shift) + Self::size(self.chr_nvram_shift)
+    }
 }
 
 impl Header {
@@ -85,11 +147,17 @@ impl Header {
                         )
                     },
                 );
-        let byte7 =
-            bits::<_, _, Error<(&[u8], usize)>, _, _>((bit_take(4usize), bit_tag(0, 3usize), bool))
-                .map(|(higher_rom_mapper, _, vs_system): (u8, u8, bool)| {
-                    (vs_system, higher_rom_mapper)
-                });
+        let byte7 = bits::<_, _, Error<(&[u8], usize)>, _, _>((
+            bit_take(4usize),
+            bit_take(2usize),
+            bool,
+            bool,
+        ))
+        .map(
+            |(higher_rom_mapper, format, _playchoice, vs_system): (u8, u8, bool, bool)| {
+                (vs_system, higher_rom_mapper, format == NES2_FORMAT)
+            },
+        );
         let region = bits::<_, _, Error<(&[u8], usize)>, _, _>((bit_tag(0, 7usize), bool))
             .map(|(_, region)| Region::from(region));
 
@@ -100,19 +168,46 @@ impl Header {
             input,
             (
                 (mirroring, battery_backed_ram, trainer, lower_rom_mapper),
-                (vs_system, higher_rom_mapper),
+                (vs_system, higher_rom_mapper, is_nes2),
             ),
         ) = (byte6, byte7).parse(input)?;
-        let (input, len_prg_ram) = u8().parse(input)?;
-        let (input, (region,)) = (region,).parse(input)?;
-        let (input, _) = tag("\x00\x00\x00\x00\x00\x00")(input)?;
-
-        let rom_mapper = RomMapper::from_u8((higher_rom_mapper << 4) | lower_rom_mapper).ok_or(
-            nom::Err::Failure(Error {
+        let (input, len_prg_ram, region, nes2, mapper_msb, size_msb) = if is_nes2 {
+            let (input, (mapper_byte, size_msb)) = (u8(), u8()).parse(input)?;
+            // Sizes in exponent-multiplier form are rare enough to refuse
+            // rather than misread
+            if size_msb & 0x0F == NES2_EXPONENT_FORM || size_msb >> 4 == NES2_EXPONENT_FORM {
+                return Err(nom::Err::Failure(Error {
+                    input,
+                    code: ErrorKind::Verify,
+                }));
+            }
+            let (input, (extension, region)) = Nes2Extension::parse(input, mapper_byte >> 4)?;
+            (
                 input,
-                code: ErrorKind::Tag,
-            }),
-        )?;
+                0,
+                region,
+                Some(extension),
+                mapper_byte & 0x0F,
+                size_msb,
+            )
+        } else {
+            let (input, len_prg_ram) = u8().parse(input)?;
+            let (input, (region,)) = (region,).parse(input)?;
+            let (input, _) = tag("\x00\x00\x00\x00\x00\x00")(input)?;
+            (input, len_prg_ram, region, None, 0, 0)
+        };
+        let len_prg_rom = u16::from_le_bytes([len_prg_rom, size_msb & 0x0F]);
+        let len_chr_rom = u16::from_le_bytes([len_chr_rom, size_msb >> 4]);
+
+        let rom_mapper = u8::try_from(
+            (mapper_msb as u16) << 8 | (higher_rom_mapper as u16) << 4 | lower_rom_mapper as u16,
+        )
+        .ok()
+        .and_then(RomMapper::from_u8)
+        .ok_or(nom::Err::Failure(Error {
+            input,
+            code: ErrorKind::Tag,
+        }))?;
 
         Ok((
             input,
@@ -126,20 +221,49 @@ impl Header {
                 rom_mapper,
                 trainer,
                 vs_system,
+                nes2,
             },
         ))
     }
+
+    /// Size of the cartridge RAM at $6000-$7FFF, a zero size byte still
+    /// implies one page when the cartridge is battery backed
+    pub fn prg_ram_size(&self) -> usize {
+        if let Some(extension) = &self.nes2 {
+            return extension.prg_ram_size();
+        }
+        match (self.len_prg_ram, self.battery_backed_ram) {
+            (0, false) => 0,
+            (0, true) => PRG_RAM_PAGE_SIZE,
+            (pages, _) => pages as usize * PRG_RAM_PAGE_SIZE,
+        }
+    }
+
+    /// Size of the writable pattern table memory, iNES carts without CHR ROM
+    /// always have 8 KiB of it
+    pub fn chr_ram_size(&self) -> usize {
+        match &self.nes2 {
+            Some(extension) => extension.chr_ram_size(),
+            None if self.len_chr_rom == 0 => CHR_RAM_SIZE,
+            None => 0,
+        }
+    }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, FromPrimitive)]
+#[repr(u8)]
 pub enum Mirroring {
     Horizontal,
     Vertical,
     FourScreen,
+    /// Every nametable shows the first KiB of VRAM
+    SingleScreenA,
+    /// Every nametable shows the second KiB of VRAM
+    SingleScreenB,
 }
 
 #[allow(non_camel_case_types)]
-#[derive(Debug, PartialEq, FromPrimitive)]
+#[derive(Debug, Clone, Copy, PartialEq, FromPrimitive)]
 #[repr(u8)]
 pub enum RomMapper {
     None,
@@ -183,10 +307,61 @@ pub enum RomMapper {
     PirateHK_SF3_Chip = 91,
 }
 
-#[derive(Debug, PartialEq)]
+/// TV system the console was built for, which sets the CPU clock and the
+/// frame timing
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
 pub enum Region {
+    #[default]
     NTSC,
     PAL,
+    /// The Famiclone sold in Russia: PAL frame rate with NTSC CPU timings
+    Dendy,
+}
+
+impl Region {
+    /// CPU clock in Hz
+    pub fn cpu_clock(&self) -> u32 {
+        match self {
+            Self::NTSC => 1_789_773,
+            Self::PAL => 1_662_607,
+            Self::Dendy => 1_773_448,
+        }
+    }
+
+    /// PPU dots per CPU cycle as a fraction, 3.2 on PAL
+    pub fn ppu_dots_per_cycle(&self) -> (u16, u16) {
+        match self {
+            Self::NTSC | Self::Dendy => (3, 1),
+            Self::PAL => (16, 5),
+        }
+    }
+
+    pub fn scanlines_per_frame(&self) -> u16 {
+        match self {
+            Self::NTSC => 262,
+            Self::PAL | Self::Dendy => 312,
+        }
+    }
+
+    /// Scanline vblank starts on, Dendy waits 51 post-render lines so its
+    /// vblank is as short as the NTSC one
+    pub fn vblank_scanline(&self) -> u16 {
+        match self {
+            Self::NTSC | Self::PAL => 241,
+            Self::Dendy => 291,
+        }
+    }
+
+    /// Only the NTSC PPU skips a dot on odd frames
+    pub fn skips_odd_frame_dot(&self) -> bool {
+        *self == Self::NTSC
+    }
+
+    /// The PAL 2C07, and the Dendy PPU after it, swap the red and green
+    /// emphasis bits of PPUMASK
+    pub fn swaps_emphasis(&self) -> bool {
+        *self != Self::NTSC
+    }
 }
 
 impl From<bool> for Region {
@@ -222,8 +397,63 @@ mod test {
                 battery_backed_ram: false,
                 len_prg_ram: 0x00,
                 region: Region::NTSC,
+                nes2: None,
             }
-        )
+        );
+        assert_eq!(header.chr_ram_size(), CHR_RAM_SIZE);
+    }
+
+    #[test]
+    fn test_nes2_header_parser() {
+        const HEADER: [u8; 16] = [
+            0x4e, 0x45, 0x53, 0x1a, 0x02, 0x00, 0x03, 0x08, 0x00, 0x00, 0x70, 0x07, 0x01, 0x00,
+            0x00, 0x00,
+        ];
+        let (input, header) = Header::parse(&HEADER).unwrap();
+        assert!(input.is_empty());
+        assert_eq!(header.region, Region::PAL);
+        let mut dendy = HEADER;
+        dendy[12] = 0x03;
+        assert_eq!(Header::parse(&dendy).unwrap().1.region, Region::Dendy);
+        assert_eq!(
+            header.nes2,
+            Some(Nes2Extension {
+                submapper: 0,
+                prg_ram_shift: 0,
+                prg_nvram_shift: 7,
+                chr_ram_shift: 7,
+                chr_nvram_shift: 0,
+            })
+        );
+        assert_eq!(header.prg_ram_size(), 0x2000);
+        assert_eq!(header.chr_ram_size(), 0x2000);
+    }
+
+    #[test]
+    fn test_nes2_size_msb_and_submapper() {
+        const HEADER: [u8; 16] = [
+            0x4e, 0x45, 0x53, 0x1a, 0x02, 0x01, 0x10, 0x08, 0x50, 0x21, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00,
+        ];
+        let (_, header) = Header::parse(&HEADER).unwrap();
+        assert_eq!(header.rom_mapper, RomMapper::NintendoMMC1);
+        assert_eq!(header.nes2.unwrap().submapper, 5);
+        assert_eq!(header.len_prg_rom, 0x102);
+        assert_eq!(header.len_chr_rom, 0x201);
+
+        // Exponent-multiplier sizes are refused, and so are mappers past 255
+        let mut exponent = HEADER;
+        exponent[9] = 0x0F;
+        assert!(matches!(
+            Header::parse(&exponent),
+            Err(nom::Err::Failure(Error {
+                code: ErrorKind::Verify,
+                ..
+            }))
+        ));
+        let mut mapper_msb = HEADER;
+        mapper_msb[8] = 0x01;
+        assert!(Header::parse(&mapper_msb).is_err());
     }
 
     #[test]
@@ -246,6 +476,7 @@ mod test {
                     battery_backed_ram: false,
                     len_prg_ram: 0x00,
                     region: Region::NTSC,
+                    nes2: None,
                 },
                 trainer: None,
                 prg_rom: rom_bytes[16..(16 + prg_rom_size)].to_vec(),
@@ -0,0 +1,503 @@
+//! NSF and NSFe music files: the sound code and data of a game, ripped out
+//! with entry points to start a track and to run it one frame at a time
+
+pub mod player;
+
+use nom::{
+    IResult, Parser,
+    bytes::complete::{tag, take},
+    number::complete::{le_u16, le_u32, u8},
+};
+use thiserror::Error;
+
+use super::{Header, Mirroring, NesRom, PRG_ROM_PAGE_SIZE, Region, RomMapper};
+
+const NSF_MAGIC: &[u8] = b"NESM\x1A";
+const NSFE_MAGIC: &[u8] = b"NSFE";
+const HEADER_SIZE: usize = 0x80;
+/// PRG ROM is switched in banks this large
+pub const BANK_SIZE: usize = 0x1000;
+const PRG_ROM_START: u16 = 0x8000;
+const PRG_ROM_SIZE: usize = 0x8000;
+
+/// Play rates in microseconds for files that leave them out
+const DEFAULT_NTSC_SPEED: u16 = 16639;
+const DEFAULT_PAL_SPEED: u16 = 19997;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum NsfError {
+    #[error("not an NSF or NSFe file")]
+    BadMagic,
+    #[error("file ends in the middle of its {0}")]
+    Truncated(&'static str),
+    #[error("NSFe file has no {0} chunk")]
+    MissingChunk(&'static str),
+    #[error("NSFe chunk {0} is required to play the file but is not supported")]
+    UnsupportedChunk(String),
+    #[error("load address {0:#06X} is outside of $8000-$FFFF")]
+    BadLoadAddress(u16),
+    #[error("there is no track {0}")]
+    NoSuchTrack(u8),
+    #[error("the playlist has no entry {0}")]
+    NoSuchPlaylistEntry(u8),
+}
+
+/// Sound chips on the cartridge besides the 2A03, byte $7B of the header
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ExpansionChips(pub u8);
+
+impl ExpansionChips {
+    pub const VRC6: u8 = 0b0000_0001;
+    pub const VRC7: u8 = 0b0000_0010;
+    pub const FDS: u8 = 0b0000_0100;
+    pub const MMC5: u8 = 0b0000_1000;
+    pub const NAMCO_163: u8 = 0b0001_0000;
+    pub const SUNSOFT_5B: u8 = 0b0010_0000;
+
+    const NAMES: [(u8, &'static str); 6] = [
+        (Self::VRC6, "VRC6"),
+        (Self::VRC7, "VRC7"),
+        (Self::FDS, "FDS"),
+        (Self::MMC5, "MMC5"),
+        (Self::NAMCO_163, "Namco 163"),
+        (Self::SUNSOFT_5B, "Sunsoft 5B"),
+    ];
+
+    pub fn contains(self, chip: u8) -> bool {
+        self.0 & chip != 0
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn names(self) -> Vec<&'static str> {
+        Self::NAMES
+            .iter()
+            .filter(|&&(chip, _)| self.contains(chip))
+            .map(|&(_, name)| name)
+            .collect()
+    }
+}
+
+/// What an NSFe file says about one track
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Track {
+    pub title: Option<String>,
+    pub duration_ms: Option<u32>,
+    pub fade_ms: Option<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Nsf {
+    pub total_songs: u8,
+    /// Track to play first, counted from 0
+    pub starting_song: u8,
+    pub load_addr: u16,
+    pub init_addr: u16,
+    pub play_addr: u16,
+    pub title: String,
+    pub artist: String,
+    pub copyright: String,
+    pub ripper: String,
+    /// Microseconds between PLAY calls on NTSC and PAL
+    pub ntsc_speed: u16,
+    pub pal_speed: u16,
+    /// Initial bank of each 4 KiB slot of $8000-$FFFF, when the tune
+    /// switches banks
+    pub banks: Option<[u8; 8]>,
+    pub pal: bool,
+    /// The tune plays right on both NTSC and PAL
+    pub dual_region: bool,
+    pub expansions: ExpansionChips,
+    pub data: Vec<u8>,
+    /// One per song, only NSFe files fill them in
+    pub tracks: Vec<Track>,
+    /// Order the tracks are meant to be played in
+    pub playlist: Option<Vec<u8>>,
+}
+
+impl Nsf {
+    pub fn parse(input: &[u8]) -> Result<Self, NsfError> {
+        let nsf = if input.starts_with(NSF_MAGIC) {
+            Self::parse_nsf(input)?
+        } else if input.starts_with(NSFE_MAGIC) {
+            Self::parse_nsfe(&input[NSFE_MAGIC.len()..])?
+        } else {
+            return Err(NsfError::BadMagic);
+        };
+        if nsf.load_addr < PRG_ROM_START {
+            return Err(NsfError::BadLoadAddress(nsf.load_addr));
+        }
+        Ok(nsf)
+    }
+
+    fn parse_nsf(input: &[u8]) -> Result<Self, NsfError> {
+        let (_, nsf) = Self::nsf_header(input).map_err(|_| NsfError::Truncated("header"))?;
+        Ok(nsf)
+    }
+
+    fn nsf_header(input: &[u8]) -> IResult<&[u8], Self> {
+        let (input, _) = tag(NSF_MAGIC)(input)?;
+        let (input, (_version, total_songs, starting_song)) = (u8, u8, u8).parse(input)?;
+        let (input, (load_addr, init_addr, play_addr)) = (le_u16, le_u16, le_u16).parse(input)?;
+        let (input, (title, artist, copyright)) =
+            (take(32usize), take(32usize), take(32usize)).parse(input)?;
+        let (input, ntsc_speed) = le_u16(input)?;
+        let (input, banks) = take(8usize)(input)?;
+        let (input, pal_speed) = le_u16(input)?;
+        let (input, (region, expansions)) = (u8, u8).parse(input)?;
+        // NSF2 flags and program length, NSF2 features are not supported
+        let (data, _) = take(HEADER_SIZE - 0x7C)(input)?;
+
+        let banks: [u8; 8] = banks.try_into().unwrap();
+        let total_songs = total_songs.max(1);
+        Ok((
+            &[],
+            Self {
+                total_songs,
+                starting_song: starting_song.saturating_sub(1),
+                load_addr,
+                init_addr,
+                play_addr,
+                title: text(title),
+                artist: text(artist),
+                copyright: text(copyright),
+                ripper: String::new(),
+                ntsc_speed,
+                pal_speed,
+                banks: banks.iter().any(|&bank| bank != 0).then_some(banks),
+                pal: region & 0b01 != 0,
+                dual_region: region & 0b10 != 0,
+                expansions: ExpansionChips(expansions),
+                data: data.to_vec(),
+                tracks: vec![Track::default(); total_songs as usize],
+                playlist: None,
+            },
+        ))
+    }
+
+    fn parse_nsfe(mut input: &[u8]) -> Result<Self, NsfError> {
+        let mut nsf = None::<Self>;
+        let mut data = None;
+        let mut titles = Vec::new();
+        let mut durations = Vec::new();
+        let mut fades = Vec::new();
+        let mut playlist = None;
+        let mut authors = Vec::new();
+        let mut rate = None;
+        let mut banks = None;
+
+        loop {
+            let (rest, (id, chunk)) =
+                Self::nsfe_chunk(input).map_err(|_| NsfError::Truncated("chunks"))?;
+            input = rest;
+
+            match id {
+                b"INFO" => nsf = Some(Self::nsfe_info(chunk)?),
+                b"DATA" => data = Some(chunk.to_vec()),
+                b"BANK" => {
+                    let mut initial = [0; 8];
+                    let len = chunk.len().min(8);
+                    initial[..len].copy_from_slice(&chunk[..len]);
+                    banks = Some(initial);
+                }
+                b"RATE" => {
+                    let speeds: Vec<u16> = chunk
+                        .chunks_exact(2)
+                        .map(|speed| u16::from_le_bytes([speed[0], speed[1]]))
+                        .collect();
+                    rate = speeds.first().map(|&ntsc| (ntsc, speeds.get(1).copied()));
+                }
+                b"auth" => authors = strings(chunk),
+                b"tlbl" => titles = strings(chunk),
+                b"time" => durations = milliseconds(chunk),
+                b"fade" => fades = milliseconds(chunk),
+                b"plst" => playlist = Some(chunk.to_vec()),
+                b"NEND" => break,
+                // Uppercase chunks matter for playback, the rest is metadata
+                [first, ..] if first.is_ascii_uppercase() => {
+                    return Err(NsfError::UnsupportedChunk(
+                        String::from_utf8_lossy(id).into_owned(),
+                    ));
+                }
+                _ => {}
+            }
+        }
+
+        let mut nsf = nsf.ok_or(NsfError::MissingChunk("INFO"))?;
+        nsf.data = data.ok_or(NsfError::MissingChunk("DATA"))?;
+        nsf.banks = banks;
+        if let Some((ntsc, pal)) = rate {
+            nsf.ntsc_speed = ntsc;
+            nsf.pal_speed = pal.unwrap_or(DEFAULT_PAL_SPEED);
+        }
+        let mut authors = authors.into_iter();
+        nsf.title = authors.next().unwrap_or_default();
+        nsf.artist = authors.next().unwrap_or_default();
+        nsf.copyright = authors.next().unwrap_or_default();
+        nsf.ripper = authors.next().unwrap_or_default();
+        for (i, track) in nsf.tracks.iter_mut().enumerate() {
+            track.title = titles.get(i).cloned();
+            track.duration_ms = durations.get(i).copied().flatten();
+            track.fade_ms = fades.get(i).copied().flatten();
+        }
+        nsf.playlist = playlist;
+        Ok(nsf)
+    }
+
+    /// `[length][id][data]`, the length counting the data only
+    fn nsfe_chunk(input: &[u8]) -> IResult<&[u8], (&[u8], &[u8])> {
+        let (input, (len, id)) = (le_u32, take(4usize)).parse(input)?;
+        let (input, data) = take(len as usize)(input)?;
+        Ok((input, (id, data)))
+    }
+
+    fn nsfe_info(chunk: &[u8]) -> Result<Self, NsfError> {
+        let (rest, (load_addr, init_addr, play_addr, region, expansions)) =
+            Self::nsfe_info_fields(chunk).map_err(|_| NsfError::Truncated("INFO chunk"))?;
+        let total_songs = rest.first().copied().unwrap_or(1).max(1);
+        Ok(Self {
+            total_songs,
+            starting_song: rest.get(1).copied().unwrap_or(0),
+            load_addr,
+            init_addr,
+            play_addr,
+            title: String::new(),
+            artist: String::new(),
+            copyright: String::new(),
+            ripper: String::new(),
+            ntsc_speed: DEFAULT_NTSC_SPEED,
+            pal_speed: DEFAULT_PAL_SPEED,
+            banks: None,
+            pal: region & 0b01 != 0,
+            dual_region: region & 0b10 != 0,
+            expansions: ExpansionChips(expansions),
+            data: Vec::new(),
+            tracks: vec![Track::default(); total_songs as usize],
+            playlist: None,
+        })
+    }
+
+    fn nsfe_info_fields(chunk: &[u8]) -> IResult<&[u8], (u16, u16, u16, u8, u8)> {
+        (le_u16, le_u16, le_u16, u8, u8).parse(chunk)
+    }
+
+    /// The region the tune is meant for, NTSC when it plays on both
+    pub fn region(&self) -> Region {
+        if self.pal && !self.dual_region {
+            Region::PAL
+        } else {
+            Region::NTSC
+        }
+    }
+
+    /// Track to play as the `number`th, counted from 1 and through the
+    /// playlist when there is one. Without a number it is the first of the
+    /// playlist, or else the starting song
+    pub fn track(&self, number: Option<u8>) -> Result<u8, NsfError> {
+        match (&self.playlist, number) {
+            (Some(playlist), number) => {
+                let number = number.unwrap_or(1);
+                number
+                    .checked_sub(1)
+                    .and_then(|index| playlist.get(index as usize))
+                    .copied()
+                    .ok_or(NsfError::NoSuchPlaylistEntry(number))
+            }
+            (None, Some(number)) => Ok(number.saturating_sub(1)),
+            (None, None) => Ok(self.starting_song),
+        }
+    }
+
+    /// Microseconds between two PLAY calls on `region`
+    pub fn play_period(&self, region: Region) -> u32 {
+        let (speed, default) = match region {
+            Region::NTSC => (self.ntsc_speed, DEFAULT_NTSC_SPEED),
+            Region::PAL | Region::Dendy => (self.pal_speed, DEFAULT_PAL_SPEED),
+        };
+        match speed {
+            0 => default as u32,
+            speed => speed as u32,
+        }
+    }
+
+    /// A cartridge holding the tune: the data at its load address in 32 KiB
+    /// of PRG ROM, or in 4 KiB banks when the tune switches banks. There
+    /// always is 8 KiB of RAM at $6000
+    pub fn rom(&self, region: Region) -> NesRom {
+        let mut prg_rom = match self.banks {
+            // Banks are aligned on 4 KiB, the low bits of the load address
+            // are padding in the first one
+            Some(_) => vec![0; self.load_addr as usize % BANK_SIZE],
+            None => vec![0; (self.load_addr - PRG_ROM_START) as usize],
+        };
+        prg_rom.extend_from_slice(&self.data);
+        let size = match self.banks {
+            Some(_) => prg_rom.len().next_multiple_of(PRG_ROM_PAGE_SIZE),
+            None => PRG_ROM_SIZE,
+        };
+        prg_rom.resize(size, 0);
+
+        NesRom {
+            header: Header {
+                len_prg_rom: (size / PRG_ROM_PAGE_SIZE) as u16,
+                len_chr_rom: 0,
+                len_prg_ram: 1,
+                mirroring: Mirroring::Horizontal,
+                battery_backed_ram: false,
+                trainer: false,
+                vs_system: false,
+                rom_mapper: RomMapper::None,
+                region,
+                nes2: None,
+            },
+            trainer: None,
+            prg_rom,
+            chr_rom: Vec::new(),
+        }
+    }
+}
+
+/// A fixed size header field, NUL padded
+fn text(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).into_owned()
+}
+
+/// NUL terminated strings one after the other
+fn strings(chunk: &[u8]) -> Vec<String> {
+    let chunk = chunk.strip_suffix(&[0]).unwrap_or(chunk);
+    chunk
+        .split(|&b| b == 0)
+        .map(|s| String::from_utf8_lossy(s).into_owned())
+        .collect()
+}
+
+/// Signed milliseconds per track, negative ones meaning unknown
+fn milliseconds(chunk: &[u8]) -> Vec<Option<u32>> {
+    chunk
+        .chunks_exact(4)
+        .map(|bytes| u32::try_from(i32::from_le_bytes(bytes.try_into().unwrap())).ok())
+        .collect()
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+
+    /// A two track NSF whose INIT stores A and X at $0200 and PLAY counts
+    /// its calls at $0202
+    pub(crate) fn counter_nsf() -> Vec<u8> {
+        let mut file = NSF_MAGIC.to_vec();
+        file.extend([1, 2, 2]);
+        file.extend(0x8000u16.to_le_bytes());
+        file.extend(0x8000u16.to_le_bytes());
+        file.extend(0x8007u16.to_le_bytes());
+        for field in ["Counter", "Someone", "2025"] {
+            let mut bytes = field.as_bytes().to_vec();
+            bytes.resize(32, 0);
+            file.extend(bytes);
+        }
+        file.extend(16639u16.to_le_bytes());
+        file.extend([0; 8]);
+        file.extend(19997u16.to_le_bytes());
+        file.extend([0b10, ExpansionChips::VRC6, 0, 0, 0, 0]);
+        assert_eq!(file.len(), HEADER_SIZE);
+        file.extend([
+            0x8D, 0x00, 0x02, // STA $0200
+            0x8E, 0x01, 0x02, // STX $0201
+            0x60, // RTS
+            0xEE, 0x02, 0x02, // INC $0202
+            0x60, // RTS
+        ]);
+        file
+    }
+
+    #[test]
+    fn test_parse_nsf() {
+        let nsf = Nsf::parse(&counter_nsf()).unwrap();
+        assert_eq!(nsf.total_songs, 2);
+        assert_eq!(nsf.starting_song, 1);
+        assert_eq!(nsf.track(None), Ok(1));
+        assert_eq!(nsf.track(Some(1)), Ok(0));
+        assert_eq!(
+            (nsf.load_addr, nsf.init_addr, nsf.play_addr),
+            (0x8000, 0x8000, 0x8007)
+        );
+        assert_eq!(nsf.title, "Counter");
+        assert_eq!(nsf.artist, "Someone");
+        assert_eq!(nsf.banks, None);
+        assert!(nsf.dual_region);
+        assert_eq!(nsf.region(), Region::NTSC);
+        assert_eq!(nsf.play_period(Region::PAL), 19997);
+        assert_eq!(nsf.expansions.names(), ["VRC6"]);
+        assert_eq!(nsf.data.len(), 11);
+
+        let rom = nsf.rom(Region::NTSC);
+        assert_eq!(rom.prg_rom.len(), 0x8000);
+        assert_eq!(rom.prg_rom[7], 0xEE);
+
+        assert_eq!(Nsf::parse(b"NES\x1A"), Err(NsfError::BadMagic));
+        assert_eq!(
+            Nsf::parse(&counter_nsf()[..0x40]),
+            Err(NsfError::Truncated("header"))
+        );
+    }
+
+    fn chunk(id: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut chunk = (data.len() as u32).to_le_bytes().to_vec();
+        chunk.extend(id);
+        chunk.extend(data);
+        chunk
+    }
+
+    #[test]
+    fn test_parse_nsfe() {
+        let mut file = NSFE_MAGIC.to_vec();
+        file.extend(chunk(
+            b"INFO",
+            &[0x00, 0x90, 0x00, 0x90, 0x03, 0x90, 0x01, 0x00, 0x03, 0x00],
+        ));
+        file.extend(chunk(b"BANK", &[0, 1, 2]));
+        file.extend(chunk(b"RATE", &0x411Au16.to_le_bytes()));
+        file.extend(chunk(b"DATA", &[0x60; 0x1800]));
+        file.extend(chunk(b"auth", b"Game\0Composer\0Nintendo\0Ripper\0"));
+        file.extend(chunk(b"tlbl", b"Title\0Field\0Boss\0"));
+        let mut times = 90_000i32.to_le_bytes().to_vec();
+        times.extend((-1i32).to_le_bytes());
+        file.extend(chunk(b"time", &times));
+        file.extend(chunk(b"plst", &[2, 0]));
+        file.extend(chunk(b"text", b"ignored"));
+        file.extend(chunk(b"NEND", &[]));
+
+        let nsf = Nsf::parse(&file).unwrap();
+        assert_eq!(nsf.total_songs, 3);
+        assert_eq!(nsf.starting_song, 0);
+        assert_eq!(nsf.load_addr, 0x9000);
+        assert_eq!(nsf.region(), Region::PAL);
+        assert_eq!(nsf.banks, Some([0, 1, 2, 0, 0, 0, 0, 0]));
+        assert_eq!(nsf.ntsc_speed, 0x411A);
+        assert_eq!(nsf.artist, "Composer");
+        assert_eq!(nsf.ripper, "Ripper");
+        assert_eq!(nsf.tracks[2].title.as_deref(), Some("Boss"));
+        assert_eq!(nsf.tracks[0].duration_ms, Some(90_000));
+        assert_eq!(nsf.tracks[1].duration_ms, None);
+        assert_eq!(nsf.playlist, Some(vec![2, 0]));
+        assert_eq!(nsf.track(None), Ok(2));
+        assert_eq!(nsf.track(Some(2)), Ok(0));
+        assert_eq!(nsf.track(Some(3)), Err(NsfError::NoSuchPlaylistEntry(3)));
+        // Banked data is laid out from the start of its 4 KiB bank
+        assert_eq!(nsf.rom(Region::PAL).prg_rom.len(), 0x4000);
+
+        let mut unknown = NSFE_MAGIC.to_vec();
+        unknown.extend(chunk(b"VRC7", &[]));
+        assert_eq!(
+            Nsf::parse(&unknown),
+            Err(NsfError::UnsupportedChunk("VRC7".to_string()))
+        );
+        let mut no_info = NSFE_MAGIC.to_vec();
+        no_info.extend(chunk(b"NEND", &[]));
+        assert_eq!(Nsf::parse(&no_info), Err(NsfError::MissingChunk("INFO")));
+    }
+}
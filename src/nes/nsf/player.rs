@@ -0,0 +1,202 @@
+use tracing::warn;
+
+use super::{Nsf, NsfError};
+use crate::apu::Apu;
+use crate::config::Config;
+use crate::cpu::CPU;
+use crate::cpu::mem::Memory;
+use crate::mapper::Cartridge;
+use crate::mapper::nsf::NsfMapper;
+use crate::nes::Region;
+
+const RAM: std::ops::Range<u16> = 0x0000..0x0800;
+const PRG_RAM: std::ops::Range<u16> = 0x6000..0x8000;
+const APU_REGISTERS: std::ops::Range<u16> = 0x4000..0x4014;
+const APU_STATUS: u16 = 0x4015;
+const APU_FRAME_COUNTER: u16 = 0x4017;
+/// Frame IRQ off, the tune is driven by PLAY calls instead
+const FRAME_IRQ_INHIBIT: u8 = 0x40;
+
+/// Runs a tune the way an NSF player cartridge does: INIT once per track,
+/// then PLAY at the rate of the header
+pub struct NsfPlayer {
+    cpu: CPU,
+    nsf: Nsf,
+    region: Region,
+    /// Microseconds between two PLAY calls
+    play_period: u32,
+    track_start: usize,
+    plays: u64,
+}
+
+impl NsfPlayer {
+    pub fn new(nsf: Nsf, config: &Config) -> Self {
+        let region = config.region.unwrap_or(nsf.region());
+        let mapper = NsfMapper::new(Cartridge::new(nsf.rom(region)), nsf.banks);
+        let mut cpu = CPU::with_mapper(Box::new(mapper), region, config);
+        let missing = cpu.apu_mut().set_expansions(nsf.expansions);
+        if !missing.is_empty() {
+            warn!(
+                target: "nsf",
+                "Expansion audio is not emulated, {} channels will be silent",
+                missing.names().join(", ")
+            );
+        }
+        Self {
+            cpu,
+            play_period: nsf.play_period(region),
+            nsf,
+            region,
+            track_start: 0,
+            plays: 0,
+        }
+    }
+
+    pub fn nsf(&self) -> &Nsf {
+        &self.nsf
+    }
+
+    pub fn cpu(&self) -> &CPU {
+        &self.cpu
+    }
+
+    pub fn apu_mut(&mut self) -> &mut Apu {
+        self.cpu.apu_mut()
+    }
+
+    /// Initializes the sound driver for `track`, counted from 0
+    pub fn start_track(&mut self, track: u8) -> Result<(), NsfError> {
+        if track >= self.nsf.total_songs {
+            return Err(NsfError::NoSuchTrack(track));
+        }
+        self.cpu.reset();
+        self.cpu.apu_mut().set_expansions(self.nsf.expansions);
+        for addr in RAM.chain(PRG_RAM) {
+            self.cpu.mem_write(addr, 0);
+        }
+        for addr in APU_REGISTERS {
+            self.cpu.mem_write(addr, 0);
+        }
+        self.cpu.mem_write(APU_STATUS, 0x00);
+        self.cpu.mem_write(APU_STATUS, 0x0F);
+        self.cpu.mem_write(APU_FRAME_COUNTER, FRAME_IRQ_INHIBIT);
+
+        let pal = u8::from(self.region != Region::NTSC);
+        let timeout = self.region.cpu_clock() as usize;
+        if !self.cpu.call(self.nsf.init_addr, track, pal, timeout) {
+            warn!(target: "nsf", "INIT of track {} did not return", track + 1);
+        }
+        self.track_start = self.cpu.cycles();
+        self.plays = 0;
+        Ok(())
+    }
+
+    /// Calls PLAY once, then lets the APU run until the next call is due.
+    /// Returns false when PLAY did not return in time
+    pub fn run_frame(&mut self) -> bool {
+        self.plays += 1;
+        let next_play = self.track_start
+            + (self.plays * self.play_period as u64 * self.region.cpu_clock() as u64 / 1_000_000)
+                as usize;
+        let period = next_play.saturating_sub(self.cpu.cycles());
+        let returned = self.cpu.call(self.nsf.play_addr, 0, 0, period);
+        self.cpu.idle(next_play.saturating_sub(self.cpu.cycles()));
+        returned
+    }
+
+    /// Time since the track started, in milliseconds
+    pub fn elapsed_ms(&self) -> u64 {
+        (self.cpu.cycles() - self.track_start) as u64 * 1000 / self.region.cpu_clock() as u64
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::ExpansionChips;
+    use super::super::test::counter_nsf;
+    use super::*;
+
+    #[test]
+    fn test_init_and_play() {
+        let nsf = Nsf::parse(&counter_nsf()).unwrap();
+        let mut player = NsfPlayer::new(nsf, &Config::default());
+        assert_eq!(player.start_track(2), Err(NsfError::NoSuchTrack(2)));
+
+        player.start_track(1).unwrap();
+        let ram = |player: &NsfPlayer, addr| player.cpu().mem_peek(addr);
+        assert_eq!((ram(&player, 0x0200), ram(&player, 0x0201)), (1, 0));
+
+        let start = player.cpu().cycles();
+        for _ in 0..60 {
+            assert!(player.run_frame());
+        }
+        assert_eq!(ram(&player, 0x0202), 60);
+        // 60 calls 16639 us apart take about a second
+        let cycles = player.cpu().cycles() - start;
+        assert!((1_786_000..=1_787_000).contains(&cycles), "{cycles}");
+        assert_eq!(player.elapsed_ms(), 998);
+
+        // Starting again clears RAM
+        player.start_track(0).unwrap();
+        assert_eq!(ram(&player, 0x0202), 0);
+    }
+
+    #[test]
+    fn test_bankswitching() {
+        let mut nsf = Nsf::parse(&counter_nsf()).unwrap();
+        // INIT maps bank 1 at $9000 and copies its first byte to $0203
+        let mut data = vec![0; 0x2000];
+        data[..12].copy_from_slice(&[
+            0xA9, 0x01, // LDA #$01
+            0x8D, 0xF9, 0x5F, // STA $5FF9
+            0xAD, 0x00, 0x90, // LDA $9000
+            0x8D, 0x03, 0x02, // STA $0203
+            0x60, // RTS
+        ]);
+        data[0x1000] = 0x42;
+        nsf.data = data;
+        nsf.banks = Some([0; 8]);
+
+        let mut player = NsfPlayer::new(nsf, &Config::default());
+        assert_eq!(player.cpu().mem_peek(0x9000), 0xA9);
+        player.start_track(0).unwrap();
+        assert_eq!(player.cpu().mem_peek(0x0203), 0x42);
+    }
+
+    #[test]
+    fn test_expansion_audio() {
+        // INIT starts the VRC6 sawtooth
+        let sawtooth_nsf = |expansions| {
+            let mut nsf = Nsf::parse(&counter_nsf()).unwrap();
+            nsf.data = vec![
+                0xA9, 0x2A, // LDA #$2A
+                0x8D, 0x00, 0xB0, // STA $B000
+                0xA9, 0x40, // LDA #$40
+                0x8D, 0x01, 0xB0, // STA $B001
+                0xA9, 0x80, // LDA #$80
+                0x8D, 0x02, 0xB0, // STA $B002
+                0x60, // RTS
+            ];
+            nsf.play_addr = 0x800F;
+            nsf.expansions = ExpansionChips(expansions);
+            nsf
+        };
+        let peak = |nsf| {
+            let mut player = NsfPlayer::new(nsf, &Config::default());
+            player.start_track(0).unwrap();
+            // Past the pop of the APU starting up
+            for _ in 0..10 {
+                player.run_frame();
+            }
+            player.apu_mut().samples().clear();
+            player.run_frame();
+            let samples = player.apu_mut().samples();
+            std::iter::from_fn(|| samples.pop()).fold(0.0f32, |peak, s| peak.max(s.abs()))
+        };
+
+        assert!(peak(sawtooth_nsf(ExpansionChips::VRC6)) > 0.05);
+        assert!(peak(sawtooth_nsf(0)) < 1e-3);
+        // Chips that are not emulated stay silent
+        assert!(peak(sawtooth_nsf(ExpansionChips::FDS)) < 1e-3);
+    }
+}
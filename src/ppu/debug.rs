@@ -0,0 +1,313 @@
+//! Pictures of the PPU memories, to track down graphics glitches
+
+use std::path::{Path, PathBuf};
+
+use super::render::{FLIP_HORIZONTAL, FLIP_VERTICAL};
+use super::{NAMETABLE_SIZE, NAMETABLES, OAM_SIZE, PALETTE_RAM, PALETTE_SIZE, PPU};
+use crate::image::Image;
+use crate::ppu::frame::{HEIGHT, WIDTH};
+use crate::ppu::palette::Palette;
+
+const TILE_SIZE: usize = 8;
+/// Pattern tables are 16 by 16 tiles
+const PATTERN_TABLE_TILES: usize = 16;
+const NAMETABLE_COLUMNS: usize = 32;
+const NAMETABLE_ROWS: usize = 30;
+/// Palette RAM entries are drawn as squares this wide
+const SWATCH_SIZE: usize = 16;
+/// Sprites are drawn in a grid of cells fitting a 8x16 sprite with a margin
+const SPRITE_CELL: usize = 20;
+const SPRITES_PER_ROW: usize = 8;
+const SPRITES: usize = OAM_SIZE / 4;
+
+const VIEWPORT_COLOR: [u8; 3] = [0xFF, 0x00, 0xFF];
+const EMPTY_COLOR: [u8; 3] = [0x20, 0x20, 0x20];
+
+impl PPU {
+    /// The two bitplanes of a row of a tile, `table` being $0000 or $1000
+    fn tile_row(&self, table: u16, tile: u16, row: u16) -> (u8, u8) {
+        let addr = table + tile * 16 + row;
+        (self.mem_read(addr), self.mem_read(addr + 8))
+    }
+
+    /// Color index of a pixel in one of the 8 palettes, pixel 0 is the backdrop
+    fn palette_color(&self, palette: u8, pixel: u8) -> u16 {
+        let addr = match pixel {
+            0 => PALETTE_RAM,
+            _ => PALETTE_RAM + palette as u16 * 4 + pixel as u16,
+        };
+        self.mem_read(addr) as u16
+    }
+
+    /// Draws one tile with its top-left corner at `x`/`y`, `None` pixels are
+    /// left untouched
+    fn draw_tile(
+        &self,
+        image: &mut Image,
+        (x, y): (usize, usize),
+        (table, tile): (u16, u16),
+        mut color: impl FnMut(u8) -> Option<[u8; 3]>,
+        flip: u8,
+    ) {
+        for row in 0..TILE_SIZE {
+            let source_row = if flip & FLIP_VERTICAL != 0 {
+                TILE_SIZE - 1 - row
+            } else {
+                row
+            };
+            let (lo, hi) = self.tile_row(table, tile, source_row as u16);
+            for column in 0..TILE_SIZE {
+                let bit = if flip & FLIP_HORIZONTAL != 0 {
+                    column
+                } else {
+                    7 - column
+                };
+                let pixel = (lo >> bit) & 1 | ((hi >> bit) & 1) << 1;
+                if let Some(rgb) = color(pixel) {
+                    image.set_pixel(x + column, y + row, rgb);
+                }
+            }
+        }
+    }
+}
+
+/// Both pattern tables side by side, colored with one of the 8 palettes,
+/// 0-3 being the background ones and 4-7 the sprite ones
+pub fn pattern_tables(ppu: &PPU, palette_index: u8, palette: &Palette) -> Image {
+    let side = PATTERN_TABLE_TILES * TILE_SIZE;
+    let mut image = Image::new(side * 2, side);
+    let palette_index = palette_index % 8;
+    for table in 0..2 {
+        for tile in 0..PATTERN_TABLE_TILES * PATTERN_TABLE_TILES {
+            let x = table * side + tile % PATTERN_TABLE_TILES * TILE_SIZE;
+            let y = tile / PATTERN_TABLE_TILES * TILE_SIZE;
+            ppu.draw_tile(
+                &mut image,
+                (x, y),
+                (table as u16 * 0x1000, tile as u16),
+                |pixel| Some(palette.rgb(ppu.palette_color(palette_index, pixel))),
+                0,
+            );
+        }
+    }
+    image
+}
+
+/// The four logical nametables in a 2x2 grid, through the current mirroring,
+/// with the outline of the area the scroll registers point at
+pub fn nametables(ppu: &PPU, palette: &Palette) -> Image {
+    let mut image = Image::new(WIDTH * 2, HEIGHT * 2);
+    let table = ppu.ctrl.background_pattern_addr();
+    for nametable in 0..4 {
+        let base = NAMETABLES + nametable * NAMETABLE_SIZE;
+        let (left, top) = (
+            (nametable as usize % 2) * WIDTH,
+            (nametable as usize / 2) * HEIGHT,
+        );
+        for row in 0..NAMETABLE_ROWS {
+            for column in 0..NAMETABLE_COLUMNS {
+                let tile = ppu.mem_read(base + (row * NAMETABLE_COLUMNS + column) as u16);
+                let attribute_addr = base + 0x03C0 + (row / 4 * 8 + column / 4) as u16;
+                let shift = (row % 4 / 2 * 2 + column % 4 / 2) * 2;
+                let attribute = (ppu.mem_read(attribute_addr) >> shift) & 0b11;
+                ppu.draw_tile(
+                    &mut image,
+                    (left + column * TILE_SIZE, top + row * TILE_SIZE),
+                    (table, tile as u16),
+                    |pixel| Some(palette.rgb(ppu.palette_color(attribute, pixel))),
+                    0,
+                );
+            }
+        }
+    }
+
+    // The viewport wraps around the edges like the scroll does
+    let scroll_x = (ppu.t.value >> 10 & 1) as usize * WIDTH
+        + ppu.t.coarse_x() as usize * TILE_SIZE
+        + ppu.fine_x as usize;
+    let scroll_y = (ppu.t.value >> 11 & 1) as usize * HEIGHT
+        + ppu.t.coarse_y() as usize * TILE_SIZE
+        + ppu.t.fine_y() as usize;
+    for offset in 0..WIDTH {
+        let x = (scroll_x + offset) % image.width;
+        image.set_pixel(x, scroll_y % image.height, VIEWPORT_COLOR);
+        image.set_pixel(x, (scroll_y + HEIGHT - 1) % image.height, VIEWPORT_COLOR);
+    }
+    for offset in 0..HEIGHT {
+        let y = (scroll_y + offset) % image.height;
+        image.set_pixel(scroll_x % image.width, y, VIEWPORT_COLOR);
+        image.set_pixel((scroll_x + WIDTH - 1) % image.width, y, VIEWPORT_COLOR);
+    }
+    image
+}
+
+/// The 32 palette RAM entries, background palettes on the top row and sprite
+/// palettes on the bottom one
+pub fn palette_ram(ppu: &PPU, palette: &Palette) -> Image {
+    let columns = PALETTE_SIZE / 2;
+    let mut image = Image::new(columns * SWATCH_SIZE, 2 * SWATCH_SIZE);
+    for entry in 0..PALETTE_SIZE {
+        let rgb = palette.rgb(ppu.mem_read(PALETTE_RAM + entry as u16) as u16);
+        let (left, top) = (entry % columns * SWATCH_SIZE, entry / columns * SWATCH_SIZE);
+        for y in top..top + SWATCH_SIZE {
+            for x in left..left + SWATCH_SIZE {
+                image.set_pixel(x, y, rgb);
+            }
+        }
+    }
+    image
+}
+
+/// The 64 sprites of OAM in index order, eight per row, drawn with their
+/// palette and flips but regardless of their position
+pub fn oam(ppu: &PPU, palette: &Palette) -> Image {
+    let rows = SPRITES / SPRITES_PER_ROW;
+    let mut image = Image::new(SPRITES_PER_ROW * SPRITE_CELL, rows * SPRITE_CELL);
+    image.data.chunks_exact_mut(4).for_each(|rgba| {
+        rgba.copy_from_slice(&[EMPTY_COLOR[0], EMPTY_COLOR[1], EMPTY_COLOR[2], 0xFF])
+    });
+
+    let height = ppu.ctrl.sprite_height() as usize;
+    for (index, sprite) in ppu.oam_data.chunks_exact(4).enumerate() {
+        let (tile, attributes) = (sprite[1] as u16, sprite[2]);
+        let sprite_palette = 4 + (attributes & 0b11);
+        let left = index % SPRITES_PER_ROW * SPRITE_CELL + (SPRITE_CELL - TILE_SIZE) / 2;
+        let top = index / SPRITES_PER_ROW * SPRITE_CELL + (SPRITE_CELL - height) / 2;
+
+        let tiles = if height == 16 {
+            let table = (tile & 1) * 0x1000;
+            let (first, second) = ((tile & 0xFE), (tile & 0xFE) + 1);
+            // Vertical flips swap the two halves too
+            if attributes & FLIP_VERTICAL != 0 {
+                vec![(table, second), (table, first)]
+            } else {
+                vec![(table, first), (table, second)]
+            }
+        } else {
+            vec![(ppu.ctrl.sprite_pattern_addr(), tile)]
+        };
+        for (half, tile) in tiles.into_iter().enumerate() {
+            ppu.draw_tile(
+                &mut image,
+                (left, top + half * TILE_SIZE),
+                tile,
+                |pixel| (pixel != 0).then(|| palette.rgb(ppu.palette_color(sprite_palette, pixel))),
+                attributes,
+            );
+        }
+    }
+    image
+}
+
+/// Saves every view as a PNG in `dir`, prefixed with `name`, and returns the
+/// paths written
+pub fn save_all(
+    ppu: &PPU,
+    palette: &Palette,
+    palette_index: u8,
+    dir: impl AsRef<Path>,
+    name: &str,
+) -> Result<Vec<PathBuf>, png::EncodingError> {
+    let views = [
+        ("patterns", pattern_tables(ppu, palette_index, palette)),
+        ("nametables", nametables(ppu, palette)),
+        ("palette", palette_ram(ppu, palette)),
+        ("oam", oam(ppu, palette)),
+    ];
+    let mut paths = Vec::with_capacity(views.len());
+    for (view, image) in views {
+        let path = dir.as_ref().join(format!("{name}-{view}.png"));
+        image.save_png(&path)?;
+        paths.push(path);
+    }
+    Ok(paths)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::nes::Mirroring;
+
+    /// Tile 1 is solid color 1, tile 2 has only its top-left pixel set to color 3
+    fn ppu() -> PPU {
+        let mut ppu = PPU::with_chr_ram(0x2000, Mirroring::Vertical);
+        for row in 0..8 {
+            ppu.mem_write(16 + row, 0xFF);
+        }
+        ppu.mem_write(32, 0x80);
+        ppu.mem_write(32 + 8, 0x80);
+        for (i, color) in [0x0F, 0x16, 0x1A, 0x12].iter().enumerate() {
+            ppu.palette_table[i] = *color;
+            ppu.palette_table[0x10 + i] = *color + 0x10;
+        }
+        ppu
+    }
+
+    #[test]
+    fn test_pattern_tables() {
+        let ppu = ppu();
+        let palette = Palette::default();
+        let image = pattern_tables(&ppu, 0, &palette);
+        assert_eq!((image.width, image.height), (256, 128));
+        assert_eq!(image.pixel(0, 0), palette.rgb(0x0F));
+        assert_eq!(image.pixel(8, 0), palette.rgb(0x16));
+        assert_eq!(image.pixel(16, 0), palette.rgb(0x12));
+        assert_eq!(image.pixel(17, 0), palette.rgb(0x0F));
+
+        let image = pattern_tables(&ppu, 4, &palette);
+        assert_eq!(image.pixel(8, 0), palette.rgb(0x26));
+    }
+
+    #[test]
+    fn test_nametables_follow_mirroring_and_show_viewport() {
+        let mut ppu = ppu();
+        let palette = Palette::default();
+        ppu.mem_write(0x2000, 1);
+        // Attribute palette 1 for the top-left tile of the second nametable
+        ppu.mem_write(0x2400, 1);
+        ppu.mem_write(0x27C0, 0b01);
+        ppu.palette_table[5] = 0x2A;
+        ppu.write_to_scroll(8);
+        ppu.write_to_scroll(16);
+
+        let image = nametables(&ppu, &palette);
+        assert_eq!((image.width, image.height), (512, 480));
+        assert_eq!(image.pixel(1, 1), palette.rgb(0x16));
+        assert_eq!(image.pixel(WIDTH + 1, 1), palette.rgb(0x2A));
+        // Vertical mirroring repeats the top row below
+        assert_eq!(image.pixel(1, HEIGHT + 1), palette.rgb(0x16));
+        assert_eq!(image.pixel(100, 16), VIEWPORT_COLOR);
+        assert_eq!(image.pixel(8, 100), VIEWPORT_COLOR);
+        assert_eq!(image.pixel(8 + WIDTH - 1, 100), VIEWPORT_COLOR);
+        assert_ne!(image.pixel(100, 15), VIEWPORT_COLOR);
+    }
+
+    #[test]
+    fn test_palette_ram() {
+        let ppu = ppu();
+        let palette = Palette::default();
+        let image = palette_ram(&ppu, &palette);
+        assert_eq!(image.pixel(SWATCH_SIZE + 3, 3), palette.rgb(0x16));
+        // $3F10 mirrors $3F00
+        assert_eq!(image.pixel(3, SWATCH_SIZE + 3), palette.rgb(0x0F));
+        assert_eq!(
+            image.pixel(SWATCH_SIZE + 3, SWATCH_SIZE + 3),
+            palette.rgb(0x26)
+        );
+    }
+
+    #[test]
+    fn test_oam_sprites_with_flips() {
+        let mut ppu = ppu();
+        let palette = Palette::default();
+        ppu.oam_data[0..4].copy_from_slice(&[0, 2, 0, 0]);
+        ppu.oam_data[4..8].copy_from_slice(&[0, 2, FLIP_HORIZONTAL | FLIP_VERTICAL, 0]);
+
+        let image = oam(&ppu, &palette);
+        let margin = (SPRITE_CELL - TILE_SIZE) / 2;
+        assert_eq!(image.pixel(margin, margin), palette.rgb(0x22));
+        assert_eq!(image.pixel(margin + 1, margin), EMPTY_COLOR);
+        let (right, bottom) = (SPRITE_CELL + margin + 7, margin + 7);
+        assert_eq!(image.pixel(right, bottom), palette.rgb(0x22));
+    }
+}
@@ -0,0 +1,31 @@
+pub const WIDTH: usize = 256;
+pub const HEIGHT: usize = 240;
+
+/// A picture as produced by the PPU, one 9-bit pixel per dot: the PPUMASK
+/// emphasis bits above the 6-bit palette index, see [`super::palette::Palette`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub data: Vec<u16>,
+}
+
+impl Frame {
+    pub fn new() -> Self {
+        Self {
+            data: vec![0; WIDTH * HEIGHT],
+        }
+    }
+
+    pub fn set_pixel(&mut self, x: usize, y: usize, color: u16) {
+        self.data[y * WIDTH + x] = color;
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> u16 {
+        self.data[y * WIDTH + x]
+    }
+}
+
+impl Default for Frame {
+    fn default() -> Self {
+        Self::new()
+    }
+}
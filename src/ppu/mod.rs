@@ -0,0 +1,943 @@
+pub mod debug;
+pub mod frame;
+pub mod ntsc;
+pub mod palette;
+mod registers;
+mod render;
+
+use std::cell::{Ref, RefCell};
+use std::rc::Rc;
+
+use num_traits::FromPrimitive;
+use rand::Rng;
+
+use frame::Frame;
+use registers::{ControlRegister, MaskRegister, StatusRegister, VramAddress};
+use render::{BackgroundPipeline, Sprite, SpriteUnit};
+
+use crate::config::{Enhancements, PowerOnState};
+use crate::mapper::{Cartridge, Nrom, SharedMapper};
+use crate::nes::{Mirroring, Region};
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+pub const OAM_SIZE: usize = 256;
+pub const PALETTE_SIZE: usize = 32;
+pub const VRAM_SIZE: usize = 2048;
+/// Four-screen carts add their own 2 KiB so every nametable is distinct
+pub const FOUR_SCREEN_VRAM_SIZE: usize = 2 * VRAM_SIZE;
+
+const PATTERN_TABLES: u16 = 0x0000;
+const PATTERN_TABLES_END: u16 = 0x1FFF;
+const NAMETABLES: u16 = 0x2000;
+const NAMETABLES_MIRRORS_END: u16 = 0x3EFF;
+const PALETTE_RAM: u16 = 0x3F00;
+const PALETTE_RAM_MIRRORS_END: u16 = 0x3FFF;
+
+const NAMETABLE_SIZE: u16 = 0x0400;
+
+pub const DOTS_PER_SCANLINE: u16 = 341;
+pub const VISIBLE_SCANLINES: u16 = 240;
+/// NTSC frame timing, see [`Region`] for the others
+pub const SCANLINES_PER_FRAME: u16 = 262;
+pub const PRE_RENDER_SCANLINE: u16 = SCANLINES_PER_FRAME - 1;
+pub const VBLANK_SCANLINE: u16 = 241;
+
+/// Dots between the second $2006 write and the copy of `t` into `v`
+const VRAM_ADDR_COPY_DELAY: u8 = 3;
+
+/// The 2C02 picture processing unit
+pub struct PPU {
+    /// The cartridge, which holds the pattern tables
+    mapper: SharedMapper,
+    vram: Vec<u8>,
+    palette_table: [u8; PALETTE_SIZE],
+    oam_data: [u8; OAM_SIZE],
+    mirroring: Mirroring,
+    region: Region,
+    enhancements: Enhancements,
+
+    ctrl: ControlRegister,
+    mask: MaskRegister,
+    status: StatusRegister,
+    oam_addr: u8,
+    /// Current VRAM address, also used as the scroll position while rendering
+    v: VramAddress,
+    /// Temporary VRAM address, the scroll position for the top-left onscreen tile
+    t: VramAddress,
+    fine_x: u8,
+    /// The first/second write toggle `w` shared by $2005 and $2006
+    write_toggle: bool,
+    /// Pending copy of `t` into `v` after a second $2006 write, with the dots left
+    delayed_vram_addr: Option<u8>,
+    /// $2007 reads outside of palette RAM return the previous fetch
+    read_buffer: u8,
+    /// The PPU side of the data bus, returned by write-only registers
+    io_latch: u8,
+    /// Raised on a rising edge of the NMI line, until the CPU polls it
+    nmi_pending: bool,
+    /// Set by a $2002 read right before vblank starts, which keeps the flag
+    /// from being raised this frame
+    suppress_vblank: bool,
+
+    cycle: u16,
+    scanline: u16,
+    /// Overclock scanlines still to run this frame
+    overclock_left: u16,
+    frame: Frame,
+    frame_count: u64,
+    odd_frame: bool,
+    background: BackgroundPipeline,
+    /// Sprites found by the evaluation of the current scanline, drawn on the next one
+    secondary_oam: Vec<Sprite>,
+    /// Sprites fetched during the previous scanline, drawn on the current one
+    sprite_units: Vec<SpriteUnit>,
+}
+
+impl PPU {
+    /// A PPU wired to an NROM board with `chr_rom` as its pattern tables
+    pub fn new(chr_rom: Vec<u8>, mirroring: Mirroring) -> Self {
+        Self::with_cartridge(Cartridge {
+            prg_rom: Vec::new(),
+            prg_ram: Vec::new(),
+            chr: chr_rom,
+            chr_is_ram: false,
+            mirroring,
+        })
+    }
+
+    /// A PPU whose pattern tables are `size` bytes of writable CHR RAM
+    pub fn with_chr_ram(size: usize, mirroring: Mirroring) -> Self {
+        Self::with_cartridge(Cartridge {
+            prg_rom: Vec::new(),
+            prg_ram: Vec::new(),
+            chr: vec![0; size],
+            chr_is_ram: true,
+            mirroring,
+        })
+    }
+
+    fn with_cartridge(cartridge: Cartridge) -> Self {
+        Self::with_mapper(Rc::new(RefCell::new(Box::new(Nrom::new(cartridge)))))
+    }
+
+    pub fn with_mapper(mapper: SharedMapper) -> Self {
+        let mirroring = mapper.borrow().mirroring();
+        Self {
+            mapper,
+            vram: vec![0; Self::vram_size(mirroring)],
+            palette_table: [0; PALETTE_SIZE],
+            oam_data: [0; OAM_SIZE],
+            mirroring,
+            region: Region::NTSC,
+            enhancements: Enhancements::default(),
+            ctrl: ControlRegister::default(),
+            mask: MaskRegister::default(),
+            status: StatusRegister::default(),
+            oam_addr: 0,
+            v: VramAddress::default(),
+            t: VramAddress::default(),
+            fine_x: 0,
+            write_toggle: false,
+            delayed_vram_addr: None,
+            read_buffer: 0,
+            io_latch: 0,
+            nmi_pending: false,
+            suppress_vblank: false,
+            cycle: 0,
+            scanline: 0,
+            overclock_left: 0,
+            frame: Frame::new(),
+            frame_count: 0,
+            odd_frame: false,
+            background: BackgroundPipeline::default(),
+            secondary_oam: Vec::with_capacity(8),
+            sprite_units: Vec::with_capacity(8),
+        }
+    }
+
+    fn vram_size(mirroring: Mirroring) -> usize {
+        match mirroring {
+            Mirroring::FourScreen => FOUR_SCREEN_VRAM_SIZE,
+            _ => VRAM_SIZE,
+        }
+    }
+
+    /// Fills the console memories, the cartridge ones are left to the mapper
+    pub fn power_on(&mut self, state: &PowerOnState, rng: &mut impl Rng) {
+        state.fill(&mut self.vram, rng);
+        state.fill(&mut self.palette_table, rng);
+        state.fill(&mut self.oam_data, rng);
+        // Palette entries only store six bits
+        for entry in self.palette_table.iter_mut() {
+            *entry &= 0b0011_1111;
+        }
+    }
+
+    /// Advances the PPU by a number of dots, returns whether a frame was completed
+    pub fn tick(&mut self, dots: u16) -> bool {
+        let mut frame_complete = false;
+        for _ in 0..dots {
+            frame_complete |= self.step();
+        }
+        frame_complete
+    }
+
+    fn step(&mut self) -> bool {
+        if let Some(dots) = self.delayed_vram_addr {
+            if dots <= 1 {
+                self.flush_vram_addr();
+            } else {
+                self.delayed_vram_addr = Some(dots - 1);
+            }
+        }
+
+        let rendering_line =
+            self.scanline < VISIBLE_SCANLINES || self.scanline == self.pre_render_scanline();
+        if self.scanline == self.region.vblank_scanline() && self.cycle == 1 {
+            if !self.suppress_vblank {
+                self.status.vblank_started = true;
+                self.nmi_pending |= self.ctrl.generate_nmi;
+            }
+            self.suppress_vblank = false;
+        }
+        if self.scanline == self.pre_render_scanline() && self.cycle == 1 {
+            self.status.vblank_started = false;
+            self.status.sprite_overflow = false;
+            self.status.sprite_zero_hit = false;
+        }
+        if rendering_line && self.mask.rendering_enabled() {
+            self.fetch();
+            if self.cycle == DOTS_PER_SCANLINE - 1 {
+                self.mapper.borrow_mut().notify_scanline();
+            }
+        }
+        if self.scanline < VISIBLE_SCANLINES && (1..=256).contains(&self.cycle) {
+            self.output_pixel();
+        }
+
+        self.advance()
+    }
+
+    /// Moves to the next dot, returns whether a frame was completed
+    fn advance(&mut self) -> bool {
+        // The idle dot at the end of the pre-render line is skipped on odd frames
+        let skip_dot = self.scanline == self.pre_render_scanline()
+            && self.cycle == DOTS_PER_SCANLINE - 2
+            && self.odd_frame
+            && self.mask.rendering_enabled()
+            && self.region.skips_odd_frame_dot();
+
+        self.cycle += 1;
+        if self.cycle < DOTS_PER_SCANLINE && !skip_dot {
+            return false;
+        }
+        self.cycle = 0;
+        self.scanline += 1;
+        if self.scanline == self.pre_render_scanline() && self.overclock_left > 0 {
+            // Repeats the last vblank line, on which the PPU does nothing
+            self.overclock_left -= 1;
+            self.scanline -= 1;
+        }
+        if self.scanline < self.region.scanlines_per_frame() {
+            return false;
+        }
+        self.scanline = 0;
+        self.overclock_left = self.enhancements.overclock_scanlines;
+        self.frame_count += 1;
+        self.odd_frame = !self.odd_frame;
+        true
+    }
+
+    fn flush_vram_addr(&mut self) {
+        if self.delayed_vram_addr.take().is_some() {
+            self.v = self.t;
+        }
+    }
+
+    /// Whether the PPU is walking `v` through the nametables right now
+    fn is_rendering(&self) -> bool {
+        (self.scanline < VISIBLE_SCANLINES || self.scanline == self.pre_render_scanline())
+            && self.mask.rendering_enabled()
+    }
+
+    fn pre_render_scanline(&self) -> u16 {
+        self.region.scanlines_per_frame() - 1
+    }
+
+    /// Accesses through $2007 bump `v`, during rendering they trigger both
+    /// the coarse X and the Y increment instead
+    fn increment_vram_addr(&mut self) {
+        if self.is_rendering() {
+            self.v.increment_x();
+            self.v.increment_y();
+        } else {
+            self.v.value = (self.v.value + self.ctrl.vram_addr_increment()) & 0x7FFF;
+        }
+    }
+
+    pub fn write_to_ctrl(&mut self, data: u8) {
+        self.io_latch = data;
+        let nmi_was_enabled = self.ctrl.generate_nmi;
+        self.ctrl = data.into();
+        self.t.set_nametable(self.ctrl.base_nametable);
+        // The NMI line is the AND of this bit and the vblank flag, so enabling
+        // it during vblank is another rising edge, and disabling it drops one
+        // the CPU has not seen yet
+        if !self.ctrl.generate_nmi {
+            self.nmi_pending = false;
+        } else if !nmi_was_enabled && self.status.vblank_started {
+            self.nmi_pending = true;
+        }
+    }
+
+    pub fn write_to_mask(&mut self, data: u8) {
+        self.io_latch = data;
+        self.mask = data.into();
+    }
+
+    pub fn read_status(&mut self) -> u8 {
+        if self.scanline == self.region.vblank_scanline() {
+            match self.cycle {
+                // One dot before the flag is set: it reads clear and stays clear
+                1 => self.suppress_vblank = true,
+                // Right as it is set: it reads set, but the NMI never happens
+                2 | 3 => self.nmi_pending = false,
+                _ => {}
+            }
+        }
+        let data = u8::from(self.status) | (self.io_latch & 0b0001_1111);
+        self.status.vblank_started = false;
+        self.write_toggle = false;
+        self.io_latch = data;
+        data
+    }
+
+    pub fn write_to_oam_addr(&mut self, data: u8) {
+        self.io_latch = data;
+        self.oam_addr = data;
+    }
+
+    pub fn write_to_oam_data(&mut self, data: u8) {
+        self.io_latch = data;
+        self.oam_data[self.oam_addr as usize] = data;
+        self.oam_addr = self.oam_addr.wrapping_add(1);
+    }
+
+    pub fn read_oam_data(&mut self) -> u8 {
+        let mut data = self.oam_data[self.oam_addr as usize];
+        // Bits 2-4 of the sprite attribute byte do not exist
+        if self.oam_addr & 0b11 == 2 {
+            data &= 0b1110_0011;
+        }
+        self.io_latch = data;
+        data
+    }
+
+    pub fn write_to_scroll(&mut self, data: u8) {
+        self.io_latch = data;
+        if self.write_toggle {
+            self.t.set_fine_y(data & 0b111);
+            self.t.set_coarse_y(data >> 3);
+        } else {
+            self.fine_x = data & 0b111;
+            self.t.set_coarse_x(data >> 3);
+        }
+        self.write_toggle = !self.write_toggle;
+    }
+
+    pub fn write_to_ppu_addr(&mut self, data: u8) {
+        self.io_latch = data;
+        if self.write_toggle {
+            self.t.value = (self.t.value & 0xFF00) | data as u16;
+            self.delayed_vram_addr = Some(VRAM_ADDR_COPY_DELAY);
+        } else {
+            self.t.value = (self.t.value & 0x00FF) | (data as u16 & 0b0011_1111) << 8;
+        }
+        self.write_toggle = !self.write_toggle;
+    }
+
+    pub fn write_to_data(&mut self, data: u8) {
+        self.io_latch = data;
+        self.flush_vram_addr();
+        let addr = self.v.addr();
+        self.increment_vram_addr();
+        self.mapper.borrow_mut().notify_ppu_addr(addr);
+        self.mem_write(addr, data);
+    }
+
+    pub fn read_data(&mut self) -> u8 {
+        self.flush_vram_addr();
+        let addr = self.v.addr();
+        self.increment_vram_addr();
+        self.mapper.borrow_mut().notify_ppu_addr(addr);
+
+        let data = match addr {
+            PALETTE_RAM..=PALETTE_RAM_MIRRORS_END => {
+                // Palette reads skip the buffer, which gets the nametable byte underneath instead
+                self.read_buffer = self.mem_read(addr - 0x1000);
+                self.mem_read(addr) | (self.io_latch & 0b1100_0000)
+            }
+            _ => {
+                let data = self.read_buffer;
+                self.read_buffer = self.mem_read(addr);
+                data
+            }
+        };
+        self.io_latch = data;
+        data
+    }
+
+    /// Whether an NMI was raised since the last poll, clearing it
+    pub fn poll_nmi(&mut self) -> bool {
+        std::mem::take(&mut self.nmi_pending)
+    }
+
+    /// Reads from one of the write-only registers
+    pub fn read_open_bus(&self) -> u8 {
+        self.io_latch
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+    }
+
+    /// Whether this is one of the extra vblank lines added by
+    /// [`Enhancements::overclock_scanlines`]
+    pub fn overclocking(&self) -> bool {
+        self.scanline == self.pre_render_scanline() - 1
+            && self.overclock_left < self.enhancements.overclock_scanlines
+    }
+
+    pub fn set_enhancements(&mut self, enhancements: Enhancements) {
+        self.enhancements = enhancements;
+        self.overclock_left = enhancements.overclock_scanlines;
+    }
+
+    pub fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    /// Changes the nametable layout, for mappers that control it at runtime
+    pub fn set_mirroring(&mut self, mirroring: Mirroring) {
+        let size = Self::vram_size(mirroring);
+        if self.vram.len() < size {
+            self.vram.resize(size, 0);
+        }
+        self.mirroring = mirroring;
+    }
+
+    /// The cartridge CHR RAM, if it has any
+    pub fn chr_ram(&self) -> Option<Ref<'_, [u8]>> {
+        Ref::filter_map(self.mapper.borrow(), |mapper| mapper.chr_ram()).ok()
+    }
+
+    pub fn oam(&self) -> &[u8; OAM_SIZE] {
+        &self.oam_data
+    }
+
+    /// The picture rendered so far, complete right after [`PPU::tick`] reports a finished frame
+    pub fn frame(&self) -> &Frame {
+        &self.frame
+    }
+
+    pub fn frame_count(&self) -> u64 {
+        self.frame_count
+    }
+
+    pub fn scanline(&self) -> u16 {
+        self.scanline
+    }
+
+    pub fn cycle(&self) -> u16 {
+        self.cycle
+    }
+}
+
+impl PPU {
+    /// A read the rendering pipeline makes, which the cartridge sees on the bus
+    fn mem_fetch(&mut self, addr: u16) -> u8 {
+        self.mapper.borrow_mut().notify_ppu_addr(addr);
+        self.mem_read(addr)
+    }
+
+    fn mem_read(&self, addr: u16) -> u8 {
+        if let Some(data) = self.cartridge_read(addr) {
+            return data;
+        }
+        match addr {
+            PATTERN_TABLES..=PATTERN_TABLES_END => 0,
+            NAMETABLES..=NAMETABLES_MIRRORS_END => self.vram[self.mirror_vram_addr(addr)],
+            PALETTE_RAM..=PALETTE_RAM_MIRRORS_END => {
+                self.palette_table[Self::mirror_palette_addr(addr)]
+            }
+            _ => unreachable!("PPU addresses are 14 bits wide, got {addr:04X}"),
+        }
+    }
+
+    fn mem_write(&mut self, addr: u16, data: u8) {
+        if addr < PALETTE_RAM && self.mapper.borrow_mut().ppu_write(addr, data) {
+            return;
+        }
+        match addr {
+            PATTERN_TABLES..=PATTERN_TABLES_END => {}
+            NAMETABLES..=NAMETABLES_MIRRORS_END => {
+                let addr = self.mirror_vram_addr(addr);
+                self.vram[addr] = data;
+            }
+            PALETTE_RAM..=PALETTE_RAM_MIRRORS_END => {
+                self.palette_table[Self::mirror_palette_addr(addr)] = data & 0b0011_1111;
+            }
+            _ => unreachable!("PPU addresses are 14 bits wide, got {addr:04X}"),
+        }
+    }
+
+    /// Palette RAM is inside the PPU, the cartridge sees everything below it
+    fn cartridge_read(&self, addr: u16) -> Option<u8> {
+        match addr {
+            PALETTE_RAM..=PALETTE_RAM_MIRRORS_END => None,
+            _ => self.mapper.borrow().ppu_read(addr),
+        }
+    }
+
+    /// Maps the four logical nametables onto the 2 KiB of console VRAM
+    fn mirror_vram_addr(&self, addr: u16) -> usize {
+        let vram_index = (addr - NAMETABLES) % (4 * NAMETABLE_SIZE);
+        let nametable = vram_index / NAMETABLE_SIZE;
+        let offset = vram_index % NAMETABLE_SIZE;
+        let bank = match self.mirroring {
+            Mirroring::Horizontal => nametable / 2,
+            Mirroring::Vertical => nametable % 2,
+            Mirroring::SingleScreenA => 0,
+            Mirroring::SingleScreenB => 1,
+            Mirroring::FourScreen => nametable,
+        };
+        (bank * NAMETABLE_SIZE + offset) as usize
+    }
+
+    /// $3F10/$3F14/$3F18/$3F1C are mirrors of $3F00/$3F04/$3F08/$3F0C
+    fn mirror_palette_addr(addr: u16) -> usize {
+        let index = (addr - PALETTE_RAM) as usize % PALETTE_SIZE;
+        match index {
+            0x10 | 0x14 | 0x18 | 0x1C => index - 0x10,
+            _ => index,
+        }
+    }
+}
+
+/// The fetch pipeline is not part of the state, a state loaded mid-frame may
+/// glitch the scanline it resumes on
+impl Snapshot for PPU {
+    fn save(&self, state: &mut StateWriter) {
+        state.u8(self.mirroring as u8);
+        state.bytes(&self.vram);
+        state.bytes(&self.palette_table);
+        state.bytes(&self.oam_data);
+
+        state.u8(self.ctrl.into());
+        state.u8(self.mask.into());
+        state.u8(self.status.into());
+        state.u8(self.oam_addr);
+        state.u16(self.v.value);
+        state.u16(self.t.value);
+        state.u8(self.fine_x);
+        state.bool(self.write_toggle);
+        state.u8(self.delayed_vram_addr.unwrap_or(0));
+        state.u8(self.read_buffer);
+        state.u8(self.io_latch);
+        state.bool(self.nmi_pending);
+        state.bool(self.suppress_vblank);
+
+        state.u16(self.cycle);
+        state.u16(self.scanline);
+        state.u64(self.frame_count);
+        state.bool(self.odd_frame);
+        state.u16(self.overclock_left);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        let mirroring =
+            Mirroring::from_u8(state.u8()?).ok_or(StateError::InvalidValue("mirroring"))?;
+        self.set_mirroring(mirroring);
+        state.bytes("VRAM", &mut self.vram)?;
+        state.bytes("palette RAM", &mut self.palette_table)?;
+        state.bytes("OAM", &mut self.oam_data)?;
+
+        self.ctrl = state.u8()?.into();
+        self.mask = state.u8()?.into();
+        self.status = state.u8()?.into();
+        self.oam_addr = state.u8()?;
+        self.v.value = state.u16()?;
+        self.t.value = state.u16()?;
+        self.fine_x = state.u8()?;
+        self.write_toggle = state.bool()?;
+        self.delayed_vram_addr = Some(state.u8()?).filter(|&dots| dots > 0);
+        self.read_buffer = state.u8()?;
+        self.io_latch = state.u8()?;
+        self.nmi_pending = state.bool()?;
+        self.suppress_vblank = state.bool()?;
+
+        self.cycle = state.u16()?;
+        self.scanline = state.u16()?;
+        self.frame_count = state.u64()?;
+        self.odd_frame = state.bool()?;
+        self.overclock_left = state.u16()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ppu(mirroring: Mirroring) -> PPU {
+        PPU::new(vec![0; 0x2000], mirroring)
+    }
+
+    fn set_addr(ppu: &mut PPU, addr: u16) {
+        ppu.write_to_ppu_addr((addr >> 8) as u8);
+        ppu.write_to_ppu_addr(addr as u8);
+    }
+
+    #[test]
+    fn test_vram_writes() {
+        let mut ppu = ppu(Mirroring::Horizontal);
+        set_addr(&mut ppu, 0x2305);
+        ppu.write_to_data(0x66);
+
+        assert_eq!(ppu.vram[0x0305], 0x66);
+    }
+
+    #[test]
+    fn test_chr_ram_is_writable() {
+        let mut ppu = PPU::with_chr_ram(0x2000, Mirroring::Horizontal);
+        set_addr(&mut ppu, 0x1FF0);
+        ppu.write_to_data(0x42);
+        set_addr(&mut ppu, 0x1FF0);
+        ppu.read_data();
+        assert_eq!(ppu.read_data(), 0x42);
+
+        let mut ppu = PPU::new(vec![0; 0x2000], Mirroring::Horizontal);
+        set_addr(&mut ppu, 0x1FF0);
+        ppu.write_to_data(0x42);
+        assert_eq!(ppu.mem_read(0x1FF0), 0x00);
+        assert!(ppu.chr_ram().is_none());
+    }
+
+    #[test]
+    fn test_vram_reads_are_buffered() {
+        let mut ppu = ppu(Mirroring::Horizontal);
+        ppu.vram[0x0305] = 0x66;
+        ppu.vram[0x0306] = 0x77;
+
+        set_addr(&mut ppu, 0x2305);
+        ppu.read_data(); // Load into the buffer
+        assert_eq!(ppu.read_data(), 0x66);
+        assert_eq!(ppu.read_data(), 0x77);
+        assert_eq!(ppu.v.addr(), 0x2308);
+    }
+
+    #[test]
+    fn test_vram_reads_step_32() {
+        let mut ppu = ppu(Mirroring::Horizontal);
+        ppu.write_to_ctrl(0b100);
+        ppu.vram[0x01FF] = 0x66;
+        ppu.vram[0x01FF + 32] = 0x77;
+
+        set_addr(&mut ppu, 0x21FF);
+        ppu.read_data();
+        assert_eq!(ppu.read_data(), 0x66);
+        assert_eq!(ppu.read_data(), 0x77);
+    }
+
+    #[test]
+    fn test_horizontal_mirroring() {
+        let mut ppu = ppu(Mirroring::Horizontal);
+        set_addr(&mut ppu, 0x2405);
+        ppu.write_to_data(0x66);
+        set_addr(&mut ppu, 0x2805);
+        ppu.write_to_data(0x77);
+
+        set_addr(&mut ppu, 0x2005);
+        ppu.read_data();
+        assert_eq!(ppu.read_data(), 0x66);
+
+        set_addr(&mut ppu, 0x2C05);
+        ppu.read_data();
+        assert_eq!(ppu.read_data(), 0x77);
+    }
+
+    #[test]
+    fn test_vertical_mirroring() {
+        let mut ppu = ppu(Mirroring::Vertical);
+        set_addr(&mut ppu, 0x2005);
+        ppu.write_to_data(0x66);
+        set_addr(&mut ppu, 0x2C05);
+        ppu.write_to_data(0x77);
+
+        set_addr(&mut ppu, 0x2805);
+        ppu.read_data();
+        assert_eq!(ppu.read_data(), 0x66);
+
+        set_addr(&mut ppu, 0x2405);
+        ppu.read_data();
+        assert_eq!(ppu.read_data(), 0x77);
+    }
+
+    #[test]
+    fn test_single_screen_mirroring() {
+        let mut ppu = ppu(Mirroring::SingleScreenB);
+        set_addr(&mut ppu, 0x2005);
+        ppu.write_to_data(0x66);
+        assert_eq!(ppu.vram[0x0405], 0x66);
+
+        set_addr(&mut ppu, 0x2C05);
+        ppu.read_data();
+        assert_eq!(ppu.read_data(), 0x66);
+
+        // Switching at runtime remaps every nametable to the other bank
+        ppu.set_mirroring(Mirroring::SingleScreenA);
+        set_addr(&mut ppu, 0x2805);
+        ppu.read_data();
+        assert_eq!(ppu.read_data(), 0x00);
+    }
+
+    #[test]
+    fn test_four_screen_mirroring() {
+        let mut ppu = ppu(Mirroring::FourScreen);
+        for (i, addr) in [0x2005, 0x2405, 0x2805, 0x2C05].into_iter().enumerate() {
+            set_addr(&mut ppu, addr);
+            ppu.write_to_data(i as u8 + 1);
+        }
+        assert_eq!(ppu.vram.len(), FOUR_SCREEN_VRAM_SIZE);
+        assert_eq!(ppu.vram[0x0C05], 4);
+
+        set_addr(&mut ppu, 0x3405);
+        ppu.read_data();
+        assert_eq!(ppu.read_data(), 2);
+    }
+
+    #[test]
+    fn test_status_read_resets_write_toggle() {
+        let mut ppu = ppu(Mirroring::Horizontal);
+        ppu.vram[0x0305] = 0x66;
+
+        ppu.write_to_ppu_addr(0x21);
+        ppu.write_to_ppu_addr(0x23);
+        ppu.write_to_ppu_addr(0x05);
+        ppu.read_data();
+        assert_ne!(ppu.read_data(), 0x66);
+
+        ppu.read_status();
+        set_addr(&mut ppu, 0x2305);
+        ppu.read_data();
+        assert_eq!(ppu.read_data(), 0x66);
+    }
+
+    #[test]
+    fn test_status_read_clears_vblank() {
+        let mut ppu = ppu(Mirroring::Horizontal);
+        ppu.status.vblank_started = true;
+        assert_eq!(ppu.read_status() >> 7, 1);
+        assert_eq!(ppu.read_status() >> 7, 0);
+    }
+
+    #[test]
+    fn test_palette_mirroring_and_unbuffered_reads() {
+        let mut ppu = ppu(Mirroring::Horizontal);
+        set_addr(&mut ppu, 0x3F10);
+        ppu.write_to_data(0x21);
+        set_addr(&mut ppu, 0x3F2C);
+        ppu.write_to_data(0x12);
+
+        assert_eq!(ppu.palette_table[0x00], 0x21);
+        assert_eq!(ppu.palette_table[0x0C], 0x12);
+        set_addr(&mut ppu, 0x3F00);
+        assert_eq!(ppu.read_data(), 0x21);
+    }
+
+    #[test]
+    fn test_oam_read_write() {
+        let mut ppu = ppu(Mirroring::Horizontal);
+        ppu.write_to_oam_addr(0x10);
+        ppu.write_to_oam_data(0x66);
+        ppu.write_to_oam_data(0x77);
+
+        ppu.write_to_oam_addr(0x10);
+        assert_eq!(ppu.read_oam_data(), 0x66);
+        ppu.write_to_oam_addr(0x11);
+        assert_eq!(ppu.read_oam_data(), 0x77);
+    }
+
+    #[test]
+    fn test_tick_completes_frames() {
+        let mut ppu = ppu(Mirroring::Horizontal);
+        for _ in 0..SCANLINES_PER_FRAME - 1 {
+            assert!(!ppu.tick(DOTS_PER_SCANLINE));
+        }
+        assert!(!ppu.tick(DOTS_PER_SCANLINE - 1));
+        assert_eq!(ppu.scanline(), SCANLINES_PER_FRAME - 1);
+        assert!(ppu.tick(1));
+        assert_eq!((ppu.scanline(), ppu.cycle(), ppu.frame_count()), (0, 0, 1));
+    }
+
+    #[test]
+    fn test_region_frame_timing() {
+        // Rendering is enabled, NTSC would skip a dot on the odd frame
+        let mut pal = ppu(Mirroring::Horizontal);
+        pal.set_region(Region::PAL);
+        pal.write_to_mask(0b0001_1000);
+        for _ in 0..2 {
+            for _ in 0..311 {
+                assert!(!pal.tick(DOTS_PER_SCANLINE));
+            }
+            assert!(!pal.tick(DOTS_PER_SCANLINE - 1));
+            assert!(pal.tick(1));
+        }
+
+        let mut dendy = ppu(Mirroring::Horizontal);
+        dendy.set_region(Region::Dendy);
+        dendy.write_to_ctrl(0b1000_0000);
+        run_to(&mut dendy, VBLANK_SCANLINE, 2);
+        assert!(!dendy.poll_nmi());
+        run_to(&mut dendy, 291, 2);
+        assert!(dendy.poll_nmi());
+    }
+
+    #[test]
+    fn test_overclock_scanlines_only_stretch_vblank() {
+        let mut ppu = ppu(Mirroring::Horizontal);
+        ppu.set_enhancements(Enhancements {
+            overclock_scanlines: 10,
+            ..Default::default()
+        });
+        ppu.write_to_ctrl(0b1000_0000);
+        run_to(&mut ppu, VBLANK_SCANLINE, 2);
+        assert!(ppu.poll_nmi());
+        for _ in VBLANK_SCANLINE..PRE_RENDER_SCANLINE + 9 {
+            assert!(!ppu.tick(DOTS_PER_SCANLINE));
+        }
+        assert!(ppu.status.vblank_started);
+        assert_eq!(ppu.scanline(), PRE_RENDER_SCANLINE - 1);
+        assert!(ppu.overclocking());
+
+        // A state saved here only has the last overclock line left to run
+        let mut state = StateWriter::new();
+        ppu.save(&mut state);
+        let state = state.finish();
+        let mut restored = self::ppu(Mirroring::Horizontal);
+        restored.set_enhancements(ppu.enhancements);
+        restored
+            .load(&mut StateReader::new(&state).unwrap())
+            .unwrap();
+        assert_eq!(restored.overclock_left, 0);
+
+        ppu.tick(DOTS_PER_SCANLINE);
+        assert!(!ppu.overclocking());
+        assert!(!ppu.status.vblank_started);
+        assert_eq!(ppu.scanline(), PRE_RENDER_SCANLINE);
+        run_to(&mut ppu, 0, 0);
+        assert_eq!(ppu.frame_count(), 1);
+        assert_eq!(ppu.overclock_left, 10);
+    }
+
+    /// Ticks until the given dot is the next one to run
+    fn run_to(ppu: &mut PPU, scanline: u16, cycle: u16) {
+        while (ppu.scanline, ppu.cycle) != (scanline, cycle) {
+            ppu.tick(1);
+        }
+    }
+
+    #[test]
+    fn test_vblank_flag_and_nmi() {
+        let mut ppu = ppu(Mirroring::Horizontal);
+        ppu.write_to_ctrl(0b1000_0000);
+        run_to(&mut ppu, VBLANK_SCANLINE, 1);
+        assert!(!ppu.status.vblank_started);
+        ppu.tick(1);
+        assert!(ppu.status.vblank_started);
+        assert!(ppu.poll_nmi());
+        assert!(!ppu.poll_nmi());
+
+        run_to(&mut ppu, PRE_RENDER_SCANLINE, 2);
+        assert!(!ppu.status.vblank_started);
+    }
+
+    #[test]
+    fn test_enabling_nmi_during_vblank_triggers_it() {
+        let mut ppu = ppu(Mirroring::Horizontal);
+        run_to(&mut ppu, VBLANK_SCANLINE + 5, 0);
+        assert!(!ppu.poll_nmi());
+
+        ppu.write_to_ctrl(0b1000_0000);
+        assert!(ppu.poll_nmi());
+        // Toggling the bit off and on again is another edge
+        ppu.write_to_ctrl(0b0000_0000);
+        ppu.write_to_ctrl(0b1000_0000);
+        assert!(ppu.poll_nmi());
+
+        // Nothing happens once the flag was read
+        ppu.read_status();
+        ppu.write_to_ctrl(0b0000_0000);
+        ppu.write_to_ctrl(0b1000_0000);
+        assert!(!ppu.poll_nmi());
+    }
+
+    #[test]
+    fn test_status_read_races_vblank() {
+        let mut ppu = ppu(Mirroring::Horizontal);
+        ppu.write_to_ctrl(0b1000_0000);
+
+        // Just before the flag is set
+        run_to(&mut ppu, VBLANK_SCANLINE, 1);
+        assert_eq!(ppu.read_status() >> 7, 0);
+        ppu.tick(1);
+        assert!(!ppu.status.vblank_started);
+        assert!(!ppu.poll_nmi());
+
+        // Just after, on the next frame
+        run_to(&mut ppu, 0, 0);
+        run_to(&mut ppu, VBLANK_SCANLINE, 2);
+        assert_eq!(ppu.read_status() >> 7, 1);
+        assert!(!ppu.poll_nmi());
+
+        // Later reads leave the NMI alone
+        run_to(&mut ppu, 0, 0);
+        run_to(&mut ppu, VBLANK_SCANLINE, 4);
+        assert_eq!(ppu.read_status() >> 7, 1);
+        assert!(ppu.poll_nmi());
+    }
+
+    #[test]
+    fn test_sprite_zero_hit_is_flagged_on_its_dot() {
+        let mut ppu = PPU::new(vec![0xFF; 0x2000], Mirroring::Horizontal);
+        ppu.write_to_mask(0b0001_1110);
+        // Sprite 0 covers x 40-47 on scanline 31
+        ppu.oam_data[0..4].copy_from_slice(&[30, 0, 0, 40]);
+
+        for _ in 0..31 {
+            ppu.tick(DOTS_PER_SCANLINE);
+        }
+        ppu.tick(41);
+        assert!(!ppu.status.sprite_zero_hit);
+        ppu.tick(1);
+        assert!(ppu.status.sprite_zero_hit);
+
+        for _ in 31..PRE_RENDER_SCANLINE {
+            ppu.tick(DOTS_PER_SCANLINE);
+        }
+        ppu.tick(2);
+        assert!(!ppu.status.sprite_zero_hit);
+    }
+
+    #[test]
+    fn test_write_only_registers_return_io_latch() {
+        let mut ppu = ppu(Mirroring::Horizontal);
+        ppu.write_to_mask(0b0001_1010);
+        assert_eq!(ppu.read_open_bus(), 0b0001_1010);
+        assert_eq!(ppu.read_status() & 0b0001_1111, 0b0001_1010);
+    }
+}
@@ -0,0 +1,231 @@
+use clap::ValueEnum;
+
+use super::{
+    frame::{Frame, HEIGHT, WIDTH},
+    palette::{Palette, PaletteParams, SUBCARRIER_PHASES, composite_level, subcarrier},
+};
+use crate::image::Image;
+
+/// Width of a filtered picture, the 8:7 pixel aspect ratio of a TV brings
+/// the 256 dots to roughly this many square pixels
+pub const NTSC_WIDTH: usize = 602;
+
+/// The PPU outputs eight signal samples per dot, two thirds of a color cycle
+const SAMPLES_PER_DOT: usize = 8;
+/// Phase advance from one scanline to the next, 341 dots of 8 samples
+const LINE_PHASE_STEP: usize = 341 * SAMPLES_PER_DOT % SUBCARRIER_PHASES;
+
+/// How the console is connected to the TV
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum NtscPreset {
+    /// Luma and chroma share one wire: color fringes, dot crawl and the
+    /// artifact colors some games rely on
+    #[default]
+    Composite,
+    /// Luma on its own wire, sharp picture with soft colors
+    SVideo,
+    /// A clean RGB mod, no signal artifacts at all
+    Rgb,
+}
+
+impl NtscPreset {
+    pub fn params(self) -> NtscParams {
+        match self {
+            Self::Composite => NtscParams {
+                luma_width: 6,
+                chroma_width: 24,
+                ..Default::default()
+            },
+            Self::SVideo => NtscParams {
+                luma_width: 3,
+                chroma_width: 18,
+                separate_luma: true,
+                ..Default::default()
+            },
+            Self::Rgb => NtscParams {
+                bypass: true,
+                ..Default::default()
+            },
+        }
+    }
+}
+
+/// Knobs of the [`NtscFilter`], widths are in signal samples
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NtscParams {
+    pub picture: PaletteParams,
+    /// Samples averaged for luma, narrow windows are sharp but let the color
+    /// subcarrier through as dots, 12 removes it completely
+    pub luma_width: usize,
+    /// Samples averaged for chroma, wider windows bleed colors further
+    pub chroma_width: usize,
+    /// Take luma from a signal without the subcarrier, as S-Video does
+    pub separate_luma: bool,
+    /// Skip the signal and scale the decoded palette colors, as RGB does
+    pub bypass: bool,
+}
+
+impl Default for NtscParams {
+    fn default() -> Self {
+        Self {
+            picture: PaletteParams::default(),
+            luma_width: SUBCARRIER_PHASES,
+            chroma_width: SUBCARRIER_PHASES * 2,
+            separate_luma: false,
+            bypass: false,
+        }
+    }
+}
+
+/// Rebuilds the video signal of a frame and decodes it the way a TV would,
+/// on the CPU so that it also works headless
+#[derive(Debug, Clone)]
+pub struct NtscFilter {
+    params: NtscParams,
+    palette: Palette,
+}
+
+impl NtscFilter {
+    pub fn new(params: NtscParams) -> Self {
+        Self {
+            palette: Palette::generate(&params.picture),
+            params,
+        }
+    }
+
+    pub fn params(&self) -> &NtscParams {
+        &self.params
+    }
+
+    /// Filters a frame into a [`NTSC_WIDTH`] wide picture. The frame count
+    /// picks the starting phase, odd frames are a dot short which makes
+    /// artifacts alternate between frames
+    pub fn apply(&self, frame: &Frame, frame_count: u64) -> Image {
+        let mut image = Image::new(NTSC_WIDTH, HEIGHT);
+        let mut signal = vec![0.0; WIDTH * SAMPLES_PER_DOT];
+        let mut luma = vec![0.0; WIDTH * SAMPLES_PER_DOT];
+        let frame_phase = (frame_count % 2) as usize * SAMPLES_PER_DOT;
+
+        for y in 0..HEIGHT {
+            if self.params.bypass {
+                for x in 0..NTSC_WIDTH {
+                    let pixel = frame.pixel(x * WIDTH / NTSC_WIDTH, y);
+                    image.set_pixel(x, y, self.palette.rgb(pixel));
+                }
+                continue;
+            }
+
+            let line_phase = (y * LINE_PHASE_STEP + frame_phase) % SUBCARRIER_PHASES;
+            for x in 0..WIDTH {
+                let pixel = frame.pixel(x, y);
+                let flat: f32 = (0..SUBCARRIER_PHASES)
+                    .map(|phase| composite_level(pixel, phase))
+                    .sum::<f32>()
+                    / SUBCARRIER_PHASES as f32;
+                for sample in 0..SAMPLES_PER_DOT {
+                    let i = x * SAMPLES_PER_DOT + sample;
+                    signal[i] = composite_level(pixel, (line_phase + i) % SUBCARRIER_PHASES);
+                    luma[i] = flat;
+                }
+            }
+
+            for x in 0..NTSC_WIDTH {
+                let center = (x * 2 + 1) * signal.len() / (NTSC_WIDTH * 2);
+                let luma_source = if self.params.separate_luma {
+                    &luma
+                } else {
+                    &signal
+                };
+                let (start, end) = window(center, self.params.luma_width, signal.len());
+                let y_level = luma_source[start..end].iter().sum::<f32>() / (end - start) as f32;
+
+                let (start, end) = window(center, self.params.chroma_width, signal.len());
+                let (mut i_level, mut q_level) = (0.0, 0.0);
+                for k in start..end {
+                    // S-Video chroma carries no luma, composite relies on the
+                    // window averaging it out
+                    let chroma = if self.params.separate_luma {
+                        signal[k] - luma[k]
+                    } else {
+                        signal[k]
+                    };
+                    let (cos, sin) = subcarrier(
+                        (line_phase + k) % SUBCARRIER_PHASES,
+                        self.params.picture.hue,
+                    );
+                    i_level += chroma * cos;
+                    q_level += chroma * sin;
+                }
+                let count = (end - start) as f32;
+                let rgb = self
+                    .params
+                    .picture
+                    .yiq_to_rgb(y_level, i_level / count, q_level / count);
+                image.set_pixel(x, y, rgb);
+            }
+        }
+        image
+    }
+}
+
+impl Default for NtscFilter {
+    fn default() -> Self {
+        Self::new(NtscPreset::default().params())
+    }
+}
+
+/// Samples around `center`, clamped to the scanline
+fn window(center: usize, width: usize, len: usize) -> (usize, usize) {
+    let start = center.saturating_sub(width / 2);
+    let end = (start + width.max(1)).min(len);
+    (start.min(end - 1), end)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn filled(color: u16) -> Frame {
+        let mut frame = Frame::new();
+        frame.data.fill(color);
+        frame
+    }
+
+    fn is_gray([r, g, b]: [u8; 3]) -> bool {
+        r.abs_diff(g) <= 2 && g.abs_diff(b) <= 2
+    }
+
+    #[test]
+    fn test_rgb_matches_generated_palette() {
+        let filter = NtscFilter::new(NtscPreset::Rgb.params());
+        let image = filter.apply(&filled(0x16), 0);
+        assert_eq!((image.width, image.height), (NTSC_WIDTH, HEIGHT));
+        let palette = Palette::generate(&PaletteParams::default());
+        assert_eq!(image.pixel(300, 100), palette.rgb(0x16));
+    }
+
+    #[test]
+    fn test_solid_colors_decode() {
+        let filter = NtscFilter::default();
+        assert!(is_gray(filter.apply(&filled(0x10), 0).pixel(300, 100)));
+        let [r, g, b] = filter.apply(&filled(0x16), 0).pixel(300, 100);
+        assert!(r > g && r > b, "0x16 should be red, got {r} {g} {b}");
+        let [r, g, b] = filter.apply(&filled(0x12), 1).pixel(300, 100);
+        assert!(b > r && b > g, "0x12 should be blue, got {r} {g} {b}");
+    }
+
+    #[test]
+    fn test_artifact_colors() {
+        // Alternating black and white columns
+        let mut frame = Frame::new();
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                frame.set_pixel(x, y, if x % 2 == 0 { 0x30 } else { 0x0F });
+            }
+        }
+        let composite = NtscFilter::new(NtscPreset::Composite.params()).apply(&frame, 0);
+        assert!((0..NTSC_WIDTH).any(|x| !is_gray(composite.pixel(x, 50))));
+        let svideo = NtscFilter::new(NtscPreset::SVideo.params()).apply(&frame, 0);
+        assert!((0..NTSC_WIDTH).all(|x| is_gray(svideo.pixel(x, 50))));
+    }
+}
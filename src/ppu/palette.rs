@@ -0,0 +1,275 @@
+use std::f32::consts::PI;
+use std::{fs, io, path::Path};
+
+use thiserror::Error;
+
+use super::frame::Frame;
+
+/// Colors the PPU can output, six bits of palette index
+pub const COLORS: usize = 64;
+/// One set of colors per combination of the three PPUMASK emphasis bits
+pub const EMPHASIS_VARIANTS: usize = 8;
+
+/// How much an emphasis bit darkens the two other color channels
+const EMPHASIS_ATTENUATION: f32 = 0.746;
+
+/// A widely used approximation of the 2C02 output
+#[rustfmt::skip]
+const DEFAULT_COLORS: [[u8; 3]; COLORS] = [
+    [0x80, 0x80, 0x80], [0x00, 0x3D, 0xA6], [0x00, 0x12, 0xB0], [0x44, 0x00, 0x96],
+    [0xA1, 0x00, 0x5E], [0xC7, 0x00, 0x28], [0xBA, 0x06, 0x00], [0x8C, 0x17, 0x00],
+    [0x5C, 0x2F, 0x00], [0x10, 0x45, 0x00], [0x05, 0x4A, 0x00], [0x00, 0x47, 0x2E],
+    [0x00, 0x41, 0x66], [0x00, 0x00, 0x00], [0x05, 0x05, 0x05], [0x05, 0x05, 0x05],
+    [0xC7, 0xC7, 0xC7], [0x00, 0x77, 0xFF], [0x21, 0x55, 0xFF], [0x82, 0x37, 0xFA],
+    [0xEB, 0x2F, 0xB5], [0xFF, 0x29, 0x50], [0xFF, 0x22, 0x00], [0xD6, 0x32, 0x00],
+    [0xC4, 0x62, 0x00], [0x35, 0x80, 0x00], [0x05, 0x8F, 0x00], [0x00, 0x8A, 0x55],
+    [0x00, 0x99, 0xCC], [0x21, 0x21, 0x21], [0x09, 0x09, 0x09], [0x09, 0x09, 0x09],
+    [0xFF, 0xFF, 0xFF], [0x0F, 0xD7, 0xFF], [0x69, 0xA2, 0xFF], [0xD4, 0x80, 0xFF],
+    [0xFF, 0x45, 0xF3], [0xFF, 0x61, 0x8B], [0xFF, 0x88, 0x33], [0xFF, 0x9C, 0x12],
+    [0xFA, 0xBC, 0x20], [0x9F, 0xE3, 0x0E], [0x2B, 0xF0, 0x35], [0x0C, 0xF0, 0xA4],
+    [0x05, 0xFB, 0xFF], [0x5E, 0x5E, 0x5E], [0x0D, 0x0D, 0x0D], [0x0D, 0x0D, 0x0D],
+    [0xFF, 0xFF, 0xFF], [0xA6, 0xFC, 0xFF], [0xB3, 0xEC, 0xFF], [0xDA, 0xAB, 0xEB],
+    [0xFF, 0xA8, 0xF9], [0xFF, 0xAB, 0xB3], [0xFF, 0xD2, 0xB0], [0xFF, 0xEF, 0xA6],
+    [0xFF, 0xF7, 0x9C], [0xD7, 0xE8, 0x95], [0xA6, 0xED, 0xAF], [0xA2, 0xF2, 0xDA],
+    [0x99, 0xFF, 0xFC], [0xDD, 0xDD, 0xDD], [0x11, 0x11, 0x11], [0x11, 0x11, 0x11],
+];
+
+#[derive(Debug, Error)]
+pub enum PaletteError {
+    #[error("palette files are 192 or 1536 bytes long, got {0}")]
+    InvalidSize(usize),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+/// Samples per color subcarrier cycle, the PPU outputs 8 of them per pixel
+pub(super) const SUBCARRIER_PHASES: usize = 12;
+
+const LOW_LEVELS: [f32; 4] = [0.350, 0.518, 0.962, 1.550];
+const HIGH_LEVELS: [f32; 4] = [1.094, 1.506, 1.962, 1.962];
+const BLACK: f32 = LOW_LEVELS[1];
+const WHITE: f32 = HIGH_LEVELS[3];
+
+fn in_color_phase(color: usize, phase: usize) -> bool {
+    (color + phase) % SUBCARRIER_PHASES < 6
+}
+
+/// Voltage the PPU outputs for a 9-bit pixel at one of the subcarrier phases,
+/// scaled so that black is 0 and white is 1
+pub(super) fn composite_level(pixel: u16, phase: usize) -> f32 {
+    let color = (pixel & 0x0F) as usize;
+    let emphasis = pixel >> 6;
+    // $xE and $xF output black
+    let level = if color > 0x0D {
+        1
+    } else {
+        (pixel as usize >> 4) & 0b11
+    };
+    // Column 0 is a flat high level and $xD a flat low one, no subcarrier
+    let low = if color == 0x00 {
+        HIGH_LEVELS[level]
+    } else {
+        LOW_LEVELS[level]
+    };
+    let high = if color > 0x0C {
+        low
+    } else {
+        HIGH_LEVELS[level]
+    };
+
+    let mut signal = if in_color_phase(color, phase) {
+        high
+    } else {
+        low
+    };
+    let attenuated = (0..3).any(|bit| emphasis & (1 << bit) != 0 && in_color_phase(bit * 4, phase));
+    if attenuated {
+        signal *= EMPHASIS_ATTENUATION;
+    }
+    (signal - BLACK) / (WHITE - BLACK)
+}
+
+/// Reference carrier used to demodulate I and Q at a phase
+pub(super) fn subcarrier(phase: usize, hue: f32) -> (f32, f32) {
+    // Shifted so the decoded hues line up with the colorburst reference
+    let angle = PI / 6.0 * (phase as f32 + 4.0) + hue.to_radians();
+    (angle.cos(), angle.sin())
+}
+
+/// Knobs for [`Palette::generate`], the defaults decode a plain NTSC signal
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PaletteParams {
+    /// Rotation of every hue, in degrees
+    pub hue: f32,
+    pub saturation: f32,
+    pub contrast: f32,
+    pub brightness: f32,
+    /// Gamma of the display, 2.2 leaves the decoded levels untouched
+    pub gamma: f32,
+}
+
+impl PaletteParams {
+    /// Converts demodulated YIQ, I and Q being averages of the signal times
+    /// the subcarrier, to gamma corrected RGB
+    pub(super) fn yiq_to_rgb(&self, y: f32, i: f32, q: f32) -> [u8; 3] {
+        let y = y * self.contrast + self.brightness;
+        // Averaging against the carrier halves the chroma amplitude
+        let (i, q) = (i * self.saturation * 2.0, q * self.saturation * 2.0);
+        let rgb = [
+            y + 0.946882 * i + 0.623557 * q,
+            y - 0.274788 * i - 0.635691 * q,
+            y - 1.108545 * i + 1.709007 * q,
+        ];
+        rgb.map(|value| (value.clamp(0.0, 1.0).powf(2.2 / self.gamma) * 255.0).round() as u8)
+    }
+}
+
+impl Default for PaletteParams {
+    fn default() -> Self {
+        Self {
+            hue: 0.0,
+            saturation: 1.0,
+            contrast: 1.0,
+            brightness: 0.0,
+            gamma: 2.2,
+        }
+    }
+}
+
+/// Converts the 9-bit pixels of a [`Frame`], emphasis bits above the palette
+/// index, to RGB
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Palette {
+    colors: Vec<[u8; 3]>,
+}
+
+impl Palette {
+    /// Parses a `.pal` file, either 64 colors or 64 colors for each emphasis
+    /// combination. Files without the emphasis variants get them computed
+    pub fn from_pal(bytes: &[u8]) -> Result<Self, PaletteError> {
+        let colors: Vec<[u8; 3]> = bytes
+            .chunks_exact(3)
+            .map(|rgb| [rgb[0], rgb[1], rgb[2]])
+            .collect();
+        match bytes.len() {
+            len if len == COLORS * 3 => Ok(Self::with_emphasis(&colors)),
+            len if len == COLORS * EMPHASIS_VARIANTS * 3 => Ok(Self { colors }),
+            len => Err(PaletteError::InvalidSize(len)),
+        }
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, PaletteError> {
+        Self::from_pal(&fs::read(path)?)
+    }
+
+    /// Darkens the base colors for every emphasis combination
+    fn with_emphasis(base: &[[u8; 3]]) -> Self {
+        let mut colors = Vec::with_capacity(COLORS * EMPHASIS_VARIANTS);
+        for emphasis in 0..EMPHASIS_VARIANTS {
+            colors.extend(base.iter().map(|rgb| {
+                let mut rgb = *rgb;
+                for (channel, value) in rgb.iter_mut().enumerate() {
+                    // Each bit darkens the channels it does not emphasize
+                    let darkened = (0..3)
+                        .filter(|&bit| bit != channel && emphasis & (1 << bit) != 0)
+                        .count();
+                    *value = (*value as f32 * EMPHASIS_ATTENUATION.powi(darkened as i32)) as u8;
+                }
+                rgb
+            }));
+        }
+        Self { colors }
+    }
+
+    /// Builds a palette by decoding the composite signal the PPU generates
+    /// for every color, after Bisqwit's NTSC palette generator
+    pub fn generate(params: &PaletteParams) -> Self {
+        let colors = (0..(COLORS * EMPHASIS_VARIANTS) as u16)
+            .map(|pixel| {
+                let (mut y, mut i, mut q) = (0.0, 0.0, 0.0);
+                for phase in 0..SUBCARRIER_PHASES {
+                    let signal = composite_level(pixel, phase) / SUBCARRIER_PHASES as f32;
+                    let (cos, sin) = subcarrier(phase, params.hue);
+                    y += signal;
+                    i += signal * cos;
+                    q += signal * sin;
+                }
+                params.yiq_to_rgb(y, i, q)
+            })
+            .collect();
+        Self { colors }
+    }
+
+    /// RGB of a frame pixel, see [`Frame`] for its layout
+    pub fn rgb(&self, pixel: u16) -> [u8; 3] {
+        self.colors[pixel as usize % self.colors.len()]
+    }
+
+    /// Writes a frame as RGBA8 into `buffer`, four bytes per pixel
+    pub fn to_rgba(&self, frame: &Frame, buffer: &mut [u8]) {
+        for (&pixel, rgba) in frame.data.iter().zip(buffer.chunks_exact_mut(4)) {
+            let [r, g, b] = self.rgb(pixel);
+            rgba.copy_from_slice(&[r, g, b, 0xFF]);
+        }
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Self::with_emphasis(&DEFAULT_COLORS)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_default_palette() {
+        let palette = Palette::default();
+        assert_eq!(palette.rgb(0x30), [0xFF, 0xFF, 0xFF]);
+        assert_eq!(palette.rgb(0x0F), [0x05, 0x05, 0x05]);
+        // Red emphasis keeps red and darkens green and blue
+        let [r, g, b] = palette.rgb(0b001 << 6 | 0x30);
+        assert_eq!(r, 0xFF);
+        assert!(g < 0xC0 && b < 0xC0);
+        // All three bits darken everything
+        assert!(palette.rgb(0b111 << 6 | 0x30).iter().all(|&c| c < 0x90));
+    }
+
+    #[test]
+    fn test_pal_files() {
+        let base: Vec<u8> = (0..COLORS * 3).map(|i| i as u8).collect();
+        let palette = Palette::from_pal(&base).unwrap();
+        assert_eq!(palette.rgb(0x01), [3, 4, 5]);
+        assert_eq!(palette.colors.len(), COLORS * EMPHASIS_VARIANTS);
+
+        let mut full = vec![0; COLORS * EMPHASIS_VARIANTS * 3];
+        full[(0b010 << 6 | 0x01) * 3] = 0xAB;
+        let palette = Palette::from_pal(&full).unwrap();
+        assert_eq!(palette.rgb(0b010 << 6 | 0x01), [0xAB, 0, 0]);
+
+        assert!(matches!(
+            Palette::from_pal(&[0; 100]),
+            Err(PaletteError::InvalidSize(100))
+        ));
+    }
+
+    #[test]
+    fn test_generated_palette() {
+        let palette = Palette::generate(&PaletteParams::default());
+        assert_eq!(palette.rgb(0x0F), [0, 0, 0]);
+        assert!(palette.rgb(0x30).iter().all(|&c| c > 0xF0));
+        let [r, g, b] = palette.rgb(0x16);
+        assert!(r > g && r > b, "0x16 should be red, got {r} {g} {b}");
+        let [r, g, b] = palette.rgb(0x12);
+        assert!(b > r && b > g, "0x12 should be blue, got {r} {g} {b}");
+
+        let desaturated = Palette::generate(&PaletteParams {
+            saturation: 0.0,
+            ..Default::default()
+        });
+        let [r, g, b] = desaturated.rgb(0x16);
+        assert!(r.abs_diff(g) <= 1 && g.abs_diff(b) <= 1);
+    }
+}
@@ -0,0 +1,320 @@
+/// PPUCTRL ($2000)
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ControlRegister {
+    pub base_nametable: u8,
+    pub vram_increment_32: bool,
+    pub sprite_pattern_table: bool,
+    pub background_pattern_table: bool,
+    pub sprite_size_16: bool,
+    pub master_slave_select: bool,
+    pub generate_nmi: bool,
+}
+
+impl ControlRegister {
+    pub fn base_nametable_addr(&self) -> u16 {
+        0x2000 + self.base_nametable as u16 * 0x0400
+    }
+
+    pub fn vram_addr_increment(&self) -> u16 {
+        if self.vram_increment_32 { 32 } else { 1 }
+    }
+
+    pub fn sprite_pattern_addr(&self) -> u16 {
+        if self.sprite_pattern_table {
+            0x1000
+        } else {
+            0x0000
+        }
+    }
+
+    pub fn background_pattern_addr(&self) -> u16 {
+        if self.background_pattern_table {
+            0x1000
+        } else {
+            0x0000
+        }
+    }
+
+    pub fn sprite_height(&self) -> u8 {
+        if self.sprite_size_16 { 16 } else { 8 }
+    }
+}
+
+const VRAM_INCREMENT_OFFSET: u8 = 2;
+const SPRITE_PATTERN_TABLE_OFFSET: u8 = 3;
+const BACKGROUND_PATTERN_TABLE_OFFSET: u8 = 4;
+const SPRITE_SIZE_OFFSET: u8 = 5;
+const MASTER_SLAVE_SELECT_OFFSET: u8 = 6;
+const GENERATE_NMI_OFFSET: u8 = 7;
+
+impl From<ControlRegister> for u8 {
+    fn from(value: ControlRegister) -> Self {
+        (value.generate_nmi as u8) << GENERATE_NMI_OFFSET
+            | (value.master_slave_select as u8) << MASTER_SLAVE_SELECT_OFFSET
+            | (value.sprite_size_16 as u8) << SPRITE_SIZE_OFFSET
+            | (value.background_pattern_table as u8) << BACKGROUND_PATTERN_TABLE_OFFSET
+            | (value.sprite_pattern_table as u8) << SPRITE_PATTERN_TABLE_OFFSET
+            | (value.vram_increment_32 as u8) << VRAM_INCREMENT_OFFSET
+            | (value.base_nametable & 0b11)
+    }
+}
+
+impl From<u8> for ControlRegister {
+    fn from(value: u8) -> Self {
+        Self {
+            base_nametable: value & 0b11,
+            vram_increment_32: value & (1 << VRAM_INCREMENT_OFFSET) != 0,
+            sprite_pattern_table: value & (1 << SPRITE_PATTERN_TABLE_OFFSET) != 0,
+            background_pattern_table: value & (1 << BACKGROUND_PATTERN_TABLE_OFFSET) != 0,
+            sprite_size_16: value & (1 << SPRITE_SIZE_OFFSET) != 0,
+            master_slave_select: value & (1 << MASTER_SLAVE_SELECT_OFFSET) != 0,
+            generate_nmi: value & (1 << GENERATE_NMI_OFFSET) != 0,
+        }
+    }
+}
+
+/// PPUMASK ($2001)
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MaskRegister {
+    pub greyscale: bool,
+    pub show_background_left: bool,
+    pub show_sprites_left: bool,
+    pub show_background: bool,
+    pub show_sprites: bool,
+    pub emphasize_red: bool,
+    pub emphasize_green: bool,
+    pub emphasize_blue: bool,
+}
+
+impl MaskRegister {
+    pub fn rendering_enabled(&self) -> bool {
+        self.show_background || self.show_sprites
+    }
+
+    /// The emphasis bits, red in bit 0
+    pub fn emphasis(&self) -> u8 {
+        u8::from(*self) >> EMPHASIZE_RED_OFFSET
+    }
+}
+
+const GREYSCALE_OFFSET: u8 = 0;
+const SHOW_BACKGROUND_LEFT_OFFSET: u8 = 1;
+const SHOW_SPRITES_LEFT_OFFSET: u8 = 2;
+const SHOW_BACKGROUND_OFFSET: u8 = 3;
+const SHOW_SPRITES_OFFSET: u8 = 4;
+const EMPHASIZE_RED_OFFSET: u8 = 5;
+const EMPHASIZE_GREEN_OFFSET: u8 = 6;
+const EMPHASIZE_BLUE_OFFSET: u8 = 7;
+
+impl From<MaskRegister> for u8 {
+    fn from(value: MaskRegister) -> Self {
+        (value.emphasize_blue as u8) << EMPHASIZE_BLUE_OFFSET
+            | (value.emphasize_green as u8) << EMPHASIZE_GREEN_OFFSET
+            | (value.emphasize_red as u8) << EMPHASIZE_RED_OFFSET
+            | (value.show_sprites as u8) << SHOW_SPRITES_OFFSET
+            | (value.show_background as u8) << SHOW_BACKGROUND_OFFSET
+            | (value.show_sprites_left as u8) << SHOW_SPRITES_LEFT_OFFSET
+            | (value.show_background_left as u8) << SHOW_BACKGROUND_LEFT_OFFSET
+            | (value.greyscale as u8) << GREYSCALE_OFFSET
+    }
+}
+
+impl From<u8> for MaskRegister {
+    fn from(value: u8) -> Self {
+        Self {
+            greyscale: value & (1 << GREYSCALE_OFFSET) != 0,
+            show_background_left: value & (1 << SHOW_BACKGROUND_LEFT_OFFSET) != 0,
+            show_sprites_left: value & (1 << SHOW_SPRITES_LEFT_OFFSET) != 0,
+            show_background: value & (1 << SHOW_BACKGROUND_OFFSET) != 0,
+            show_sprites: value & (1 << SHOW_SPRITES_OFFSET) != 0,
+            emphasize_red: value & (1 << EMPHASIZE_RED_OFFSET) != 0,
+            emphasize_green: value & (1 << EMPHASIZE_GREEN_OFFSET) != 0,
+            emphasize_blue: value & (1 << EMPHASIZE_BLUE_OFFSET) != 0,
+        }
+    }
+}
+
+/// PPUSTATUS ($2002), the lower five bits are open bus
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct StatusRegister {
+    pub sprite_overflow: bool,
+    pub sprite_zero_hit: bool,
+    pub vblank_started: bool,
+}
+
+const SPRITE_OVERFLOW_OFFSET: u8 = 5;
+const SPRITE_ZERO_HIT_OFFSET: u8 = 6;
+const VBLANK_STARTED_OFFSET: u8 = 7;
+
+impl From<StatusRegister> for u8 {
+    fn from(value: StatusRegister) -> Self {
+        (value.vblank_started as u8) << VBLANK_STARTED_OFFSET
+            | (value.sprite_zero_hit as u8) << SPRITE_ZERO_HIT_OFFSET
+            | (value.sprite_overflow as u8) << SPRITE_OVERFLOW_OFFSET
+    }
+}
+
+impl From<u8> for StatusRegister {
+    fn from(value: u8) -> Self {
+        Self {
+            sprite_overflow: value & (1 << SPRITE_OVERFLOW_OFFSET) != 0,
+            sprite_zero_hit: value & (1 << SPRITE_ZERO_HIT_OFFSET) != 0,
+            vblank_started: value & (1 << VBLANK_STARTED_OFFSET) != 0,
+        }
+    }
+}
+
+/// The internal `v` and `t` registers, laid out as `yyy NN YYYYY XXXXX`
+/// (fine Y, nametable, coarse Y, coarse X)
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct VramAddress {
+    pub value: u16,
+}
+
+const COARSE_X_MASK: u16 = 0x001F;
+const COARSE_Y_MASK: u16 = 0x03E0;
+const NAMETABLE_X: u16 = 0x0400;
+const NAMETABLE_Y: u16 = 0x0800;
+const NAMETABLE_MASK: u16 = NAMETABLE_X | NAMETABLE_Y;
+const FINE_Y_MASK: u16 = 0x7000;
+const HORIZONTAL_MASK: u16 = COARSE_X_MASK | NAMETABLE_X;
+const VERTICAL_MASK: u16 = COARSE_Y_MASK | FINE_Y_MASK | NAMETABLE_Y;
+
+impl VramAddress {
+    pub fn coarse_x(&self) -> u16 {
+        self.value & COARSE_X_MASK
+    }
+
+    pub fn coarse_y(&self) -> u16 {
+        (self.value & COARSE_Y_MASK) >> 5
+    }
+
+    pub fn fine_y(&self) -> u16 {
+        (self.value & FINE_Y_MASK) >> 12
+    }
+
+    pub fn set_coarse_x(&mut self, coarse_x: u8) {
+        self.value = (self.value & !COARSE_X_MASK) | (coarse_x as u16 & 0b11111);
+    }
+
+    pub fn set_coarse_y(&mut self, coarse_y: u8) {
+        self.value = (self.value & !COARSE_Y_MASK) | (coarse_y as u16 & 0b11111) << 5;
+    }
+
+    pub fn set_nametable(&mut self, nametable: u8) {
+        self.value = (self.value & !NAMETABLE_MASK) | (nametable as u16 & 0b11) << 10;
+    }
+
+    pub fn set_fine_y(&mut self, fine_y: u8) {
+        self.value = (self.value & !FINE_Y_MASK) | (fine_y as u16 & 0b111) << 12;
+    }
+
+    /// Moves to the next tile, wrapping into the horizontally adjacent nametable
+    pub fn increment_x(&mut self) {
+        if self.coarse_x() == 31 {
+            self.value &= !COARSE_X_MASK;
+            self.value ^= NAMETABLE_X;
+        } else {
+            self.value += 1;
+        }
+    }
+
+    /// Moves to the next pixel row, wrapping into the vertically adjacent nametable
+    /// after row 29, rows 30 and 31 wrap without switching nametables
+    pub fn increment_y(&mut self) {
+        if self.fine_y() < 7 {
+            self.value += 1 << 12;
+            return;
+        }
+
+        self.value &= !FINE_Y_MASK;
+        let coarse_y = match self.coarse_y() {
+            29 => {
+                self.value ^= NAMETABLE_Y;
+                0
+            }
+            31 => 0,
+            y => y + 1,
+        };
+        self.set_coarse_y(coarse_y as u8);
+    }
+
+    pub fn copy_horizontal(&mut self, from: VramAddress) {
+        self.value = (self.value & !HORIZONTAL_MASK) | (from.value & HORIZONTAL_MASK);
+    }
+
+    pub fn copy_vertical(&mut self, from: VramAddress) {
+        self.value = (self.value & !VERTICAL_MASK) | (from.value & VERTICAL_MASK);
+    }
+
+    /// Address on the PPU bus, which is only 14 bits wide
+    pub fn addr(&self) -> u16 {
+        self.value & 0x3FFF
+    }
+
+    pub fn tile_addr(&self) -> u16 {
+        0x2000 | (self.value & 0x0FFF)
+    }
+
+    pub fn attribute_addr(&self) -> u16 {
+        0x23C0
+            | (self.value & NAMETABLE_MASK)
+            | ((self.coarse_y() >> 2) << 3)
+            | (self.coarse_x() >> 2)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_control_from_eq() {
+        let ctrl = ControlRegister::from(0b1011_1110);
+        assert_eq!(ctrl.base_nametable, 0b10);
+        assert!(ctrl.vram_increment_32);
+        assert!(!ctrl.master_slave_select);
+        assert_eq!(u8::from(ctrl), 0b1011_1110);
+    }
+
+    #[test]
+    fn test_vram_address_increment_x_wraps_nametable() {
+        let mut v = VramAddress::default();
+        v.set_coarse_x(31);
+        v.increment_x();
+        assert_eq!(v.coarse_x(), 0);
+        assert_eq!(v.tile_addr(), 0x2400);
+    }
+
+    #[test]
+    fn test_vram_address_increment_y() {
+        let mut v = VramAddress::default();
+        v.set_coarse_y(29);
+        v.set_fine_y(7);
+        v.increment_y();
+        assert_eq!((v.coarse_y(), v.fine_y()), (0, 0));
+        assert_eq!(v.tile_addr(), 0x2800);
+
+        // Rows 30 and 31 hold attributes, scrolling past them skips the nametable switch
+        v.set_coarse_y(31);
+        v.set_fine_y(7);
+        v.increment_y();
+        assert_eq!(v.tile_addr(), 0x2800);
+    }
+
+    #[test]
+    fn test_vram_address_attribute_addr() {
+        let mut v = VramAddress::default();
+        v.set_nametable(0b11);
+        v.set_coarse_x(31);
+        v.set_coarse_y(29);
+        assert_eq!(v.attribute_addr(), 0x2FFF);
+    }
+
+    #[test]
+    fn test_mask_from_eq() {
+        let mask = MaskRegister::from(0b1010_0101);
+        assert!(mask.greyscale && mask.show_sprites_left && mask.emphasize_red);
+        assert_eq!(u8::from(mask), 0b1010_0101);
+    }
+}
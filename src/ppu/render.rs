@@ -0,0 +1,585 @@
+use super::frame::WIDTH;
+use super::{PALETTE_RAM, PPU, VISIBLE_SCANLINES};
+
+const SPRITE_PALETTES: usize = 0x10;
+const MAX_SPRITES_PER_SCANLINE: usize = 8;
+
+pub(super) const FLIP_VERTICAL: u8 = 0b1000_0000;
+pub(super) const FLIP_HORIZONTAL: u8 = 0b0100_0000;
+const BEHIND_BACKGROUND: u8 = 0b0010_0000;
+
+/// An OAM entry copied into secondary OAM during sprite evaluation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct Sprite {
+    pub index: u8,
+    pub y: u8,
+    pub tile: u8,
+    pub attributes: u8,
+    pub x: u8,
+}
+
+/// A sprite whose pattern was fetched and is being drawn on the current scanline
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct SpriteUnit {
+    pub index: u8,
+    pub attributes: u8,
+    pub x: u8,
+    pub pattern_lo: u8,
+    pub pattern_hi: u8,
+}
+
+impl SpriteUnit {
+    fn pixel(&self, x: usize) -> u8 {
+        match x.checked_sub(self.x as usize) {
+            Some(column) if column < 8 => {
+                ((self.pattern_hi >> (7 - column)) & 1) << 1
+                    | ((self.pattern_lo >> (7 - column)) & 1)
+            }
+            _ => 0,
+        }
+    }
+}
+
+/// The background tile fetch latches and the shift registers they feed
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub(super) struct BackgroundPipeline {
+    next_tile: u8,
+    next_attribute: u8,
+    next_pattern_lo: u8,
+    next_pattern_hi: u8,
+    pattern_lo: u16,
+    pattern_hi: u16,
+    attribute_lo: u16,
+    attribute_hi: u16,
+}
+
+impl BackgroundPipeline {
+    fn shift(&mut self) {
+        self.pattern_lo <<= 1;
+        self.pattern_hi <<= 1;
+        self.attribute_lo <<= 1;
+        self.attribute_hi <<= 1;
+    }
+
+    /// Moves the latched tile into the low byte of the shift registers
+    fn reload(&mut self) {
+        let expand = |bit: u8| if bit != 0 { 0x00FF } else { 0x0000 };
+        self.pattern_lo = (self.pattern_lo & 0xFF00) | self.next_pattern_lo as u16;
+        self.pattern_hi = (self.pattern_hi & 0xFF00) | self.next_pattern_hi as u16;
+        self.attribute_lo = (self.attribute_lo & 0xFF00) | expand(self.next_attribute & 0b01);
+        self.attribute_hi = (self.attribute_hi & 0xFF00) | expand(self.next_attribute & 0b10);
+    }
+
+    /// Palette and two-bit pattern value of the pixel selected by fine X
+    fn pixel(&self, fine_x: u8) -> (u8, u8) {
+        let bit = 15 - fine_x;
+        let pixel = ((self.pattern_hi >> bit) & 1) << 1 | ((self.pattern_lo >> bit) & 1);
+        let palette = ((self.attribute_hi >> bit) & 1) << 1 | ((self.attribute_lo >> bit) & 1);
+        (palette as u8, pixel as u8)
+    }
+}
+
+impl PPU {
+    /// Runs the memory fetches of the current dot, only called while rendering
+    /// is enabled on the visible and pre-render scanlines
+    pub(super) fn fetch(&mut self) {
+        let cycle = self.cycle;
+        if let 2..=257 | 321..=337 = cycle {
+            self.background.shift();
+            match (cycle - 1) % 8 {
+                0 => {
+                    self.background.reload();
+                    self.background.next_tile = self.mem_fetch(self.v.tile_addr());
+                }
+                2 => {
+                    let attribute = self.mem_fetch(self.v.attribute_addr());
+                    let shift = ((self.v.coarse_y() & 0b10) << 1) | (self.v.coarse_x() & 0b10);
+                    self.background.next_attribute = (attribute >> shift) & 0b11;
+                }
+                4 => {
+                    let addr = self.background_pattern_addr();
+                    self.background.next_pattern_lo = self.mem_fetch(addr);
+                }
+                6 => {
+                    let addr = self.background_pattern_addr() + 8;
+                    self.background.next_pattern_hi = self.mem_fetch(addr);
+                }
+                7 => self.v.increment_x(),
+                _ => {}
+            }
+        }
+
+        match cycle {
+            256 => {
+                self.v.increment_y();
+                if self.scanline == self.pre_render_scanline() {
+                    self.secondary_oam.clear();
+                } else {
+                    self.evaluate_sprites(self.scanline);
+                }
+            }
+            257 => {
+                self.v.copy_horizontal(self.t);
+                self.sprite_units.clear();
+            }
+            280..=304 if self.scanline == self.pre_render_scanline() => {
+                self.v.copy_vertical(self.t)
+            }
+            _ => {}
+        }
+
+        // Each sprite takes eight dots of 257-320, the pattern is ready on the last one
+        if (257..=320).contains(&cycle) && (cycle - 257) % 8 == 7 {
+            let slot = ((cycle - 257) / 8) as usize;
+            if let Some(sprite) = self.secondary_oam.get(slot).copied() {
+                self.load_sprite_unit(&sprite);
+            }
+        }
+        // Sprites past the hardware limit all load at once
+        if cycle == 320 {
+            for slot in MAX_SPRITES_PER_SCANLINE..self.secondary_oam.len() {
+                let sprite = self.secondary_oam[slot];
+                self.load_sprite_unit(&sprite);
+            }
+        }
+    }
+
+    fn load_sprite_unit(&mut self, sprite: &Sprite) {
+        let (pattern_lo, pattern_hi) = self.sprite_pattern(sprite);
+        self.sprite_units.push(SpriteUnit {
+            index: sprite.index,
+            attributes: sprite.attributes,
+            x: sprite.x,
+            pattern_lo,
+            pattern_hi,
+        });
+    }
+
+    fn background_pattern_addr(&self) -> u16 {
+        self.ctrl.background_pattern_addr()
+            + self.background.next_tile as u16 * 16
+            + self.v.fine_y()
+    }
+
+    /// Selects the sprites of the next scanline the way the dots 65-256 of
+    /// `scanline` do, including the diagonal OAM walk that makes the overflow
+    /// flag unreliable
+    pub(super) fn evaluate_sprites(&mut self, scanline: u16) {
+        let height = self.ctrl.sprite_height() as i16;
+        let in_range = |y: u8| (0..height).contains(&(scanline as i16 - y as i16));
+
+        self.secondary_oam.clear();
+        let mut n = 0;
+        while n < 64 && self.secondary_oam.len() < MAX_SPRITES_PER_SCANLINE {
+            let entry = &self.oam_data[n * 4..n * 4 + 4];
+            if in_range(entry[0]) {
+                self.secondary_oam.push(Sprite {
+                    index: n as u8,
+                    y: entry[0],
+                    tile: entry[1],
+                    attributes: entry[2],
+                    x: entry[3],
+                });
+            }
+            n += 1;
+        }
+        let first_dropped = n;
+
+        // Once eight sprites are found the hardware keeps comparing, but
+        // increments the byte offset alongside the sprite index
+        let mut m = 0;
+        while n < 64 {
+            if in_range(self.oam_data[n * 4 + m]) {
+                self.status.sprite_overflow = true;
+                break;
+            }
+            n += 1;
+            m = (m + 1) % 4;
+        }
+
+        // The flag was decided above, the extra sprites stay invisible to the game
+        if self.enhancements.unlimited_sprites {
+            for n in first_dropped..64 {
+                let entry = &self.oam_data[n * 4..n * 4 + 4];
+                if in_range(entry[0]) {
+                    self.secondary_oam.push(Sprite {
+                        index: n as u8,
+                        y: entry[0],
+                        tile: entry[1],
+                        attributes: entry[2],
+                        x: entry[3],
+                    });
+                }
+            }
+        }
+    }
+
+    /// Fetches the row of `sprite` drawn on the scanline after the current one
+    fn sprite_pattern(&mut self, sprite: &Sprite) -> (u8, u8) {
+        let height = self.ctrl.sprite_height() as u16;
+        let mut row = self.scanline.wrapping_sub(sprite.y as u16) & (height - 1);
+        if sprite.attributes & FLIP_VERTICAL != 0 {
+            row = height - 1 - row;
+        }
+
+        let addr = if height == 16 {
+            let table = (sprite.tile as u16 & 1) * 0x1000;
+            let tile = (sprite.tile & 0b1111_1110) as u16 + row / 8;
+            table + tile * 16 + row % 8
+        } else {
+            self.ctrl.sprite_pattern_addr() + sprite.tile as u16 * 16 + row
+        };
+        let (mut lo, mut hi) = (self.mem_fetch(addr), self.mem_fetch(addr + 8));
+        if sprite.attributes & FLIP_HORIZONTAL != 0 {
+            lo = lo.reverse_bits();
+            hi = hi.reverse_bits();
+        }
+        (lo, hi)
+    }
+
+    /// Lower OAM indexes win, even when they end up behind the background
+    fn sprite_pixel(&self, x: usize) -> Option<(SpriteUnit, u8)> {
+        self.sprite_units.iter().find_map(|unit| {
+            let pixel = unit.pixel(x);
+            (pixel != 0).then_some((*unit, pixel))
+        })
+    }
+
+    /// Multiplexes the background and sprite pixels of dots 1-256
+    pub(super) fn output_pixel(&mut self) {
+        let x = (self.cycle - 1) as usize;
+        let y = self.scanline as usize;
+        debug_assert!(x < WIDTH && self.scanline < VISIBLE_SCANLINES);
+
+        if !self.mask.rendering_enabled() {
+            // The backdrop is replaced by the palette entry `v` points to, if any
+            let addr = match self.v.addr() {
+                addr if addr >= PALETTE_RAM => addr,
+                _ => PALETTE_RAM,
+            };
+            let color = self.palette_table[Self::mirror_palette_addr(addr)];
+            self.frame.set_pixel(x, y, self.pixel_value(color));
+            return;
+        }
+
+        let (background_palette, background_pixel) =
+            if self.mask.show_background && (x >= 8 || self.mask.show_background_left) {
+                self.background.pixel(self.fine_x)
+            } else {
+                (0, 0)
+            };
+        let sprite = if self.mask.show_sprites && (x >= 8 || self.mask.show_sprites_left) {
+            self.sprite_pixel(x)
+        } else {
+            None
+        };
+
+        let background_color = (background_palette * 4 + background_pixel) as usize;
+        let color_addr = match sprite {
+            None if background_pixel == 0 => 0,
+            None => background_color,
+            Some((unit, pixel)) => {
+                let sprite_color =
+                    SPRITE_PALETTES + (unit.attributes & 0b11) as usize * 4 + pixel as usize;
+                if background_pixel == 0 {
+                    sprite_color
+                } else {
+                    if unit.index == 0 && x != WIDTH - 1 {
+                        self.status.sprite_zero_hit = true;
+                    }
+                    if unit.attributes & BEHIND_BACKGROUND != 0 {
+                        background_color
+                    } else {
+                        sprite_color
+                    }
+                }
+            }
+        };
+        self.frame
+            .set_pixel(x, y, self.pixel_value(self.palette_table[color_addr]));
+    }
+
+    /// Grayscale masks the palette index down to its brightness column, the
+    /// emphasis bits ride along for the palette to apply, in NTSC order
+    fn pixel_value(&self, color: u8) -> u16 {
+        let color = if self.mask.greyscale {
+            color & 0x30
+        } else {
+            color
+        };
+        let mut emphasis = self.mask.emphasis();
+        if self.region.swaps_emphasis() {
+            emphasis = emphasis & 0b100 | (emphasis & 0b001) << 1 | (emphasis & 0b010) >> 1;
+        }
+        (emphasis as u16) << 6 | color as u16
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::Enhancements;
+    use crate::nes::{Mirroring, Region};
+    use crate::ppu::{PPU, PRE_RENDER_SCANLINE, VISIBLE_SCANLINES};
+
+    /// Tile 1 is solid color 1, tile 2 is solid color 3, tile 3 only has its
+    /// leftmost column set
+    fn ppu() -> PPU {
+        let mut chr = vec![0; 0x2000];
+        chr[0x10..0x18].fill(0xFF);
+        chr[0x20..0x30].fill(0xFF);
+        chr[0x30..0x38].fill(0x80);
+        let mut ppu = PPU::new(chr, Mirroring::Vertical);
+        ppu.palette_table[0] = 0x0F;
+        ppu.palette_table[1] = 0x01;
+        ppu.palette_table[3] = 0x03;
+        ppu.palette_table[5] = 0x11;
+        ppu.palette_table[7] = 0x13;
+        ppu.palette_table[0x11] = 0x21;
+        ppu.palette_table[0x13] = 0x23;
+        ppu.write_to_mask(0b0001_1110);
+        ppu
+    }
+
+    fn set_sprite(ppu: &mut PPU, index: usize, y: u8, tile: u8, attributes: u8, x: u8) {
+        ppu.oam_data[index * 4..index * 4 + 4].copy_from_slice(&[y, tile, attributes, x]);
+    }
+
+    fn hide_sprites(ppu: &mut PPU) {
+        ppu.oam_data.fill(0xFF);
+    }
+
+    /// Runs from the start of the pre-render scanline until the given dot
+    fn run_to(ppu: &mut PPU, scanline: u16, cycle: u16) {
+        while (ppu.scanline, ppu.cycle) != (scanline, cycle) {
+            ppu.tick(1);
+        }
+    }
+
+    fn render_frame(ppu: &mut PPU) {
+        ppu.scanline = PRE_RENDER_SCANLINE;
+        ppu.cycle = 0;
+        run_to(ppu, VISIBLE_SCANLINES, 0);
+    }
+
+    #[test]
+    fn test_tiles_and_attributes() {
+        let mut ppu = ppu();
+        ppu.vram[0] = 1;
+        ppu.vram[1] = 2;
+        ppu.vram[2] = 1;
+        // Top-left quadrant keeps palette 0, top-right one gets palette 1
+        ppu.vram[0x03C0] = 0b0000_0100;
+
+        render_frame(&mut ppu);
+        assert_eq!(ppu.frame.pixel(0, 0), 0x01);
+        assert_eq!(ppu.frame.pixel(8, 0), 0x03);
+        assert_eq!(ppu.frame.pixel(16, 0), 0x11);
+        assert_eq!(ppu.frame.pixel(24, 0), 0x0F);
+    }
+
+    #[test]
+    fn test_scroll_wraps_into_next_nametable() {
+        let mut ppu = ppu();
+        // First tile of the nametable to the right
+        ppu.vram[0x0400] = 2;
+        ppu.write_to_scroll(252);
+        ppu.write_to_scroll(0);
+
+        render_frame(&mut ppu);
+        assert_eq!(ppu.frame.pixel(3, 0), 0x0F);
+        assert_eq!(ppu.frame.pixel(4, 0), 0x03);
+        assert_eq!(ppu.frame.pixel(11, 0), 0x03);
+        assert_eq!(ppu.frame.pixel(12, 0), 0x0F);
+    }
+
+    #[test]
+    fn test_left_column_clipping() {
+        let mut ppu = ppu();
+        ppu.vram[0] = 1;
+        ppu.vram[1] = 1;
+        ppu.write_to_mask(0b0001_1100);
+
+        render_frame(&mut ppu);
+        assert_eq!(ppu.frame.pixel(7, 0), 0x0F);
+        assert_eq!(ppu.frame.pixel(8, 0), 0x01);
+    }
+
+    #[test]
+    fn test_rendering_disabled_shows_palette_at_v() {
+        let mut ppu = ppu();
+        ppu.write_to_mask(0);
+        render_frame(&mut ppu);
+        assert_eq!(ppu.frame.pixel(100, 100), 0x0F);
+
+        ppu.v.value = 0x3F03;
+        render_frame(&mut ppu);
+        assert_eq!(ppu.frame.pixel(100, 100), 0x03);
+    }
+
+    #[test]
+    fn test_grayscale_and_emphasis() {
+        let mut ppu = ppu();
+        ppu.vram[0] = 2;
+        ppu.palette_table[3] = 0x16;
+        ppu.write_to_mask(0b1010_1111);
+
+        render_frame(&mut ppu);
+        assert_eq!(ppu.frame.pixel(0, 0), 0b101 << 6 | 0x10);
+
+        // PAL wires red and green the other way round
+        ppu.set_region(Region::PAL);
+        ppu.write_to_mask(0b0010_1110);
+        ppu.scanline = 311;
+        ppu.cycle = 0;
+        run_to(&mut ppu, VISIBLE_SCANLINES, 0);
+        assert_eq!(ppu.frame.pixel(0, 0), 0b010 << 6 | 0x16);
+    }
+
+    #[test]
+    fn test_sprite_flip_and_priority() {
+        let mut ppu = ppu();
+        hide_sprites(&mut ppu);
+        ppu.vram[0x20 + 2] = 1;
+        set_sprite(&mut ppu, 0, 9, 3, 0b0100_0000, 0);
+        set_sprite(&mut ppu, 1, 9, 3, 0b0010_0000, 16);
+        set_sprite(&mut ppu, 2, 9, 2, 0b0000_0000, 16);
+
+        render_frame(&mut ppu);
+        // Flipped horizontally, so only the rightmost column is opaque
+        assert_eq!(ppu.frame.pixel(0, 10), 0x0F);
+        assert_eq!(ppu.frame.pixel(7, 10), 0x21);
+        // Sprite 1 wins over sprite 2 but sits behind the background
+        assert_eq!(ppu.frame.pixel(16, 10), 0x01);
+        assert_eq!(ppu.frame.pixel(17, 10), 0x23);
+        // Sprites are drawn one line below their Y coordinate
+        assert_eq!(ppu.frame.pixel(7, 9), 0x0F);
+    }
+
+    #[test]
+    fn test_tall_sprites() {
+        let mut ppu = ppu();
+        hide_sprites(&mut ppu);
+        ppu.write_to_ctrl(0b0010_0000);
+        // Tiles 2 (top) and 3 (bottom), flipped vertically
+        set_sprite(&mut ppu, 0, 0, 2, 0b1000_0000, 0);
+
+        render_frame(&mut ppu);
+        assert_eq!(ppu.frame.pixel(0, 1), 0x21);
+        assert_eq!(ppu.frame.pixel(1, 1), 0x0F);
+        assert_eq!(ppu.frame.pixel(1, 16), 0x23);
+        assert_eq!(ppu.frame.pixel(1, 17), 0x0F);
+    }
+
+    #[test]
+    fn test_eight_sprites_per_scanline() {
+        let mut ppu = ppu();
+        hide_sprites(&mut ppu);
+        for i in 0..9 {
+            set_sprite(&mut ppu, i, 0, 2, 0, i as u8 * 8);
+        }
+
+        render_frame(&mut ppu);
+        assert!(ppu.status.sprite_overflow);
+        assert_eq!(ppu.frame.pixel(63, 1), 0x23);
+        assert_eq!(ppu.frame.pixel(64, 1), 0x0F);
+    }
+
+    #[test]
+    fn test_unlimited_sprites_keep_overflow_flag() {
+        let mut ppu = ppu();
+        ppu.set_enhancements(Enhancements {
+            unlimited_sprites: true,
+            ..Default::default()
+        });
+        hide_sprites(&mut ppu);
+        for i in 0..12 {
+            set_sprite(&mut ppu, i, 0, 2, 0, i as u8 * 8);
+        }
+
+        render_frame(&mut ppu);
+        assert!(ppu.status.sprite_overflow);
+        assert_eq!(ppu.frame.pixel(64, 1), 0x23);
+        assert_eq!(ppu.frame.pixel(95, 1), 0x23);
+        assert_eq!(ppu.frame.pixel(96, 1), 0x0F);
+
+        // Eight sprites never set the flag, enhanced or not
+        hide_sprites(&mut ppu);
+        for i in 0..8 {
+            set_sprite(&mut ppu, i, 0, 2, 0, i as u8 * 8);
+        }
+        ppu.status.sprite_overflow = false;
+        ppu.evaluate_sprites(0);
+        assert!(!ppu.status.sprite_overflow);
+    }
+
+    #[test]
+    fn test_sprite_overflow_diagonal_scan() {
+        let mut ppu = ppu();
+        hide_sprites(&mut ppu);
+        for i in 0..8 {
+            set_sprite(&mut ppu, i, 0, 0, 0, 0);
+        }
+        // The ninth sprite is hidden, so the scan moves on to the tile byte of
+        // the tenth, which is on the line but never has its Y compared
+        set_sprite(&mut ppu, 9, 0, 0xF0, 0, 0);
+        ppu.evaluate_sprites(0);
+        assert!(!ppu.status.sprite_overflow);
+
+        // A false positive from the attribute byte of the eleventh sprite
+        set_sprite(&mut ppu, 9, 0xF0, 0xF0, 0xF0, 0xF0);
+        set_sprite(&mut ppu, 10, 0xF0, 0xF0, 0, 0xF0);
+        ppu.evaluate_sprites(0);
+        assert!(ppu.status.sprite_overflow);
+    }
+
+    #[test]
+    fn test_sprite_zero_hit() {
+        let mut ppu = ppu();
+        hide_sprites(&mut ppu);
+        ppu.vram[0x20 + 1] = 1;
+        set_sprite(&mut ppu, 0, 9, 3, 0, 10);
+
+        render_frame(&mut ppu);
+        assert!(ppu.status.sprite_zero_hit);
+
+        ppu.write_to_mask(0b0001_0110);
+        render_frame(&mut ppu);
+        assert!(!ppu.status.sprite_zero_hit);
+    }
+
+    #[test]
+    fn test_ppu_addr_write_mid_frame_changes_next_line() {
+        let mut ppu = ppu();
+        ppu.vram[20 * 32] = 2;
+
+        ppu.scanline = PRE_RENDER_SCANLINE;
+        ppu.cycle = 0;
+        run_to(&mut ppu, 49, 300);
+        // v = $2280, coarse Y 20 of the first nametable, bit 13 of the address
+        // lands in fine Y so the row starts two pixels in
+        ppu.write_to_ppu_addr(0x22);
+        ppu.write_to_ppu_addr(0x80);
+        run_to(&mut ppu, VISIBLE_SCANLINES, 0);
+
+        assert_eq!(ppu.frame.pixel(0, 49), 0x0F);
+        assert_eq!(ppu.frame.pixel(0, 50), 0x03);
+        assert_eq!(ppu.frame.pixel(0, 55), 0x03);
+        assert_eq!(ppu.frame.pixel(0, 56), 0x0F);
+    }
+
+    #[test]
+    fn test_scroll_write_mid_line_applies_from_next_line() {
+        let mut ppu = ppu();
+        ppu.vram[6 * 32 + 1] = 2;
+
+        ppu.scanline = PRE_RENDER_SCANLINE;
+        ppu.cycle = 0;
+        run_to(&mut ppu, 49, 100);
+        ppu.write_to_scroll(8);
+        run_to(&mut ppu, VISIBLE_SCANLINES, 0);
+
+        assert_eq!(ppu.frame.pixel(0, 49), 0x0F);
+        assert_eq!(ppu.frame.pixel(8, 49), 0x03);
+        assert_eq!(ppu.frame.pixel(0, 50), 0x03);
+    }
+}
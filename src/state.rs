@@ -0,0 +1,192 @@
+use thiserror::Error;
+
+const MAGIC: &[u8; 4] = b"NESS";
+const VERSION: u8 = 8;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum StateError {
+    #[error("not a save state")]
+    BadMagic,
+    #[error("unsupported save state version {0}")]
+    UnsupportedVersion(u8),
+    #[error("save state ended early")]
+    UnexpectedEnd,
+    #[error("save state has an invalid {0}")]
+    InvalidValue(&'static str),
+    #[error("save state has {found} bytes of {what}, this machine has {expected}")]
+    SizeMismatch {
+        what: &'static str,
+        expected: usize,
+        found: usize,
+    },
+}
+
+/// Parts of the machine that can be written to and restored from a save state
+pub trait Snapshot {
+    fn save(&self, state: &mut StateWriter);
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError>;
+}
+
+/// Builds a save state, every value is little-endian and blocks of memory
+/// are prefixed with their length
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        let mut data = MAGIC.to_vec();
+        data.push(VERSION);
+        Self { data }
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.data
+            .extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+        self.data.extend_from_slice(bytes);
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+impl Default for StateWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Result<Self, StateError> {
+        let data = data.strip_prefix(MAGIC).ok_or(StateError::BadMagic)?;
+        let mut reader = Self { data };
+        match reader.u8()? {
+            VERSION => Ok(reader),
+            version => Err(StateError::UnsupportedVersion(version)),
+        }
+    }
+
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], StateError> {
+        let (bytes, rest) = self
+            .data
+            .split_first_chunk::<N>()
+            .ok_or(StateError::UnexpectedEnd)?;
+        self.data = rest;
+        Ok(*bytes)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.take::<1>()?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, StateError> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn u16(&mut self) -> Result<u16, StateError> {
+        Ok(u16::from_le_bytes(self.take()?))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, StateError> {
+        Ok(u32::from_le_bytes(self.take()?))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, StateError> {
+        Ok(u64::from_le_bytes(self.take()?))
+    }
+
+    /// Fills `dest` with a block of memory, which must have the same size
+    pub fn bytes(&mut self, what: &'static str, dest: &mut [u8]) -> Result<(), StateError> {
+        let len = u32::from_le_bytes(self.take()?) as usize;
+        if len != dest.len() {
+            return Err(StateError::SizeMismatch {
+                what,
+                expected: dest.len(),
+                found: len,
+            });
+        }
+        let (bytes, rest) = self
+            .data
+            .split_at_checked(len)
+            .ok_or(StateError::UnexpectedEnd)?;
+        dest.copy_from_slice(bytes);
+        self.data = rest;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let mut writer = StateWriter::new();
+        writer.u8(0x12);
+        writer.bool(true);
+        writer.u16(0x3456);
+        writer.u64(0x789A);
+        writer.bytes(&[1, 2, 3]);
+        let data = writer.finish();
+
+        let mut reader = StateReader::new(&data).unwrap();
+        assert_eq!(reader.u8(), Ok(0x12));
+        assert_eq!(reader.bool(), Ok(true));
+        assert_eq!(reader.u16(), Ok(0x3456));
+        assert_eq!(reader.u64(), Ok(0x789A));
+        let mut bytes = [0; 3];
+        reader.bytes("test", &mut bytes).unwrap();
+        assert_eq!(bytes, [1, 2, 3]);
+        assert_eq!(reader.u8(), Err(StateError::UnexpectedEnd));
+    }
+
+    #[test]
+    fn test_rejects_bad_input() {
+        assert!(matches!(
+            StateReader::new(b"NOPE\x02"),
+            Err(StateError::BadMagic)
+        ));
+        assert!(matches!(
+            StateReader::new(b"NESS\x01"),
+            Err(StateError::UnsupportedVersion(1))
+        ));
+
+        let mut writer = StateWriter::new();
+        writer.bytes(&[1, 2, 3]);
+        let data = writer.finish();
+        let mut reader = StateReader::new(&data).unwrap();
+        assert_eq!(
+            reader.bytes("test", &mut [0; 2]),
+            Err(StateError::SizeMismatch {
+                what: "test",
+                expected: 2,
+                found: 3
+            })
+        );
+    }
+}